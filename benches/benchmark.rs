@@ -1,5 +1,22 @@
-use criterion::{black_box, criterion_group, criterion_main, BenchmarkId, Criterion};
-use fcaptcha::{build_puzzle, get, verify_puzzle_result::verify_puzzle_result_with};
+use criterion::{black_box, criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+use fcaptcha::{
+    build_puzzle, build_puzzle::PuzzleOptions, build_puzzle_with, get,
+    verify_puzzle_result::verify_puzzle_result_with, CaptchaStore, Keyring, MemoryStore,
+    ShardedStore,
+};
+use std::thread;
+use std::time::{Duration, Instant};
+
+const THREAD_COUNT: u64 = 8;
+const SOLUTION: &str = "3761fae80ef01b32dcf892d099ca07f31db7a97311cce59529a4bae93a801db4.\
+    ZO+cGAAAAAEAAAABAQwzegAAAAAAAAAAWlXMkohinFU=.\
+    AAAAAIgRAAABAAAAzHwAAAIAAAAuDQAAAwAAAPsUAAAEAAAACaMAAAUAAADEGgAABgAAAEcSAAAHAAAAvz0AAAgAAABhpQ\
+    AACQAAAAstAAAKAAAA2CYAAAsAAADtNgEADAAAAC0CAAANAAAAFp8AAA4AAABdcgAADwAAAL6JAAAQAAAALYkAABEAAAD0\
+    vAEAEgAAAPxaAAATAAAAvFAAABQAAAAA7wEAFQAAAPoWAAAWAAAAGoEAABcAAACovwAAGAAAAGXcAAAZAAAAP2sBABoAAA\
+    D4BQAAGwAAAE9nAAAcAAAAFcQBAB0AAABQCgEAHgAAAB0FAAAfAAAAe9EAACAAAAClywAAIQAAAFYPAAAiAAAAtjcAACMA\
+    AABIgQAAJAAAAJoPAQAlAAAAYlgAACYAAABIbAAAJwAAAGCwAAAoAAAAokkAACkAAADl6gAAKgAAAAo5AQArAAAA5igAAC\
+    wAAADVfAAALQAAAHYfAAAuAAAALdYAAC8AAAC11gEAMAAAAN1dAAAxAAAAbyEAADIAAADjwAAA.\
+    AgAA";
 
 fn build_puzzle_benchmark(c: &mut Criterion) {
     let ip_addresses = ["127.0.0.1", "192.168.0.0.1"];
@@ -11,7 +28,7 @@ fn build_puzzle_benchmark(c: &mut Criterion) {
             BenchmarkId::from_parameter(ip_address),
             ip_address,
             |b, ip_address| {
-                b.iter(|| build_puzzle(&ip_address));
+                b.iter(|| build_puzzle(ip_address));
             },
         );
     }
@@ -19,27 +36,22 @@ fn build_puzzle_benchmark(c: &mut Criterion) {
 }
 
 fn verify_puzzle_result_with_benchmark(c: &mut Criterion) {
-    let secret_key = get::<Vec<u8>>("SECRET_KEY");
-    let solution = "3761fae80ef01b32dcf892d099ca07f31db7a97311cce59529a4bae93a801db4.\
-    ZO+cGAAAAAEAAAABAQwzegAAAAAAAAAAWlXMkohinFU=.\
-    AAAAAIgRAAABAAAAzHwAAAIAAAAuDQAAAwAAAPsUAAAEAAAACaMAAAUAAADEGgAABgAAAEcSAAAHAAAAvz0AAAgAAABhpQ\
-    AACQAAAAstAAAKAAAA2CYAAAsAAADtNgEADAAAAC0CAAANAAAAFp8AAA4AAABdcgAADwAAAL6JAAAQAAAALYkAABEAAAD0\
-    vAEAEgAAAPxaAAATAAAAvFAAABQAAAAA7wEAFQAAAPoWAAAWAAAAGoEAABcAAACovwAAGAAAAGXcAAAZAAAAP2sBABoAAA\
-    D4BQAAGwAAAE9nAAAcAAAAFcQBAB0AAABQCgEAHgAAAB0FAAAfAAAAe9EAACAAAAClywAAIQAAAFYPAAAiAAAAtjcAACMA\
-    AABIgQAAJAAAAJoPAQAlAAAAYlgAACYAAABIbAAAJwAAAGCwAAAoAAAAokkAACkAAADl6gAAKgAAAAo5AQArAAAA5igAAC\
-    wAAADVfAAALQAAAHYfAAAuAAAALdYAAC8AAAC11gEAMAAAAN1dAAAxAAAAbyEAADIAAADjwAAA.\
-    AgAA";
+    let keyring = Keyring::new(0, &get::<Vec<u8>>("SECRET_KEY").unwrap());
     let timestamp: u64 = 1693424664;
+    let store = MemoryStore::new();
 
     c.bench_function(
         "verify_puzzle_result_with",
         |b: &mut criterion::Bencher<'_>| {
             b.iter(|| {
                 let result = verify_puzzle_result_with(
-                    black_box(solution),
+                    &store,
+                    black_box(SOLUTION),
+                    None,
                     black_box(timestamp),
                     black_box(0),
-                    black_box(&secret_key),
+                    black_box(&keyring),
+                    black_box(&PuzzleOptions::default()),
                 );
                 assert!(result.is_ok())
             })
@@ -47,9 +59,113 @@ fn verify_puzzle_result_with_benchmark(c: &mut Criterion) {
     );
 }
 
+fn stores() -> [(&'static str, Box<dyn CaptchaStore>); 2] {
+    [
+        ("memory", Box::new(MemoryStore::new())),
+        ("sharded", Box::new(ShardedStore::new(64))),
+    ]
+}
+
+/// Runs `iters` calls of `op` spread over [THREAD_COUNT] threads and returns the elapsed time.
+fn run_concurrently<F: Fn(u64, u64) + Sync>(iters: u64, op: F) -> Duration {
+    let start = Instant::now();
+    thread::scope(|scope| {
+        for thread_idx in 0..THREAD_COUNT {
+            let op = &op;
+            scope.spawn(move || {
+                for iter in 0..iters.div_ceil(THREAD_COUNT) {
+                    op(thread_idx, iter);
+                }
+            });
+        }
+    });
+    start.elapsed()
+}
+
+fn concurrent_build_puzzle_benchmark(c: &mut Criterion) {
+    let keyring = Keyring::new(0, &get::<Vec<u8>>("SECRET_KEY").unwrap());
+    let timestamp: u64 = 1693424664;
+
+    let mut group = c.benchmark_group("concurrent_build_puzzle_with");
+    group.throughput(Throughput::Elements(1));
+    for (name, store) in stores() {
+        group.bench_function(BenchmarkId::from_parameter(name), |b| {
+            b.iter_custom(|iters| {
+                run_concurrently(iters, |thread_idx, iter| {
+                    let ip_address =
+                        format!("10.{}.{}.{}", thread_idx, (iter >> 8) & 0xff, iter & 0xff);
+                    let result = build_puzzle_with(
+                        store.as_ref(),
+                        &ip_address,
+                        None,
+                        timestamp,
+                        iter,
+                        &keyring,
+                        1800,
+                        &PuzzleOptions::default(),
+                    );
+                    assert!(result.is_ok())
+                })
+            });
+        });
+    }
+    group.finish();
+}
+
+fn concurrent_verify_puzzle_result_benchmark(c: &mut Criterion) {
+    let keyring = Keyring::new(0, &get::<Vec<u8>>("SECRET_KEY").unwrap());
+    let timestamp: u64 = 1693424664;
+
+    let mut group = c.benchmark_group("concurrent_verify_puzzle_result_with");
+    group.throughput(Throughput::Elements(1));
+    for (name, store) in stores() {
+        group.bench_function(BenchmarkId::from_parameter(name), |b| {
+            b.iter_custom(|iters| {
+                run_concurrently(iters, |_, _| {
+                    let result = verify_puzzle_result_with(
+                        store.as_ref(),
+                        black_box(SOLUTION),
+                        None,
+                        black_box(timestamp),
+                        black_box(0),
+                        black_box(&keyring),
+                        black_box(&PuzzleOptions::default()),
+                    );
+                    assert!(result.is_ok())
+                })
+            });
+        });
+    }
+    group.finish();
+}
+
+fn concurrent_mark_puzzle_used_benchmark(c: &mut Criterion) {
+    let timestamp: u64 = 1693424664;
+
+    let mut group = c.benchmark_group("concurrent_mark_puzzle_used");
+    group.throughput(Throughput::Elements(1));
+    for (name, store) in stores() {
+        group.bench_function(BenchmarkId::from_parameter(name), |b| {
+            b.iter_custom(|iters| {
+                run_concurrently(iters, |thread_idx, iter| {
+                    let mut puzzle = [0_u8; 32];
+                    puzzle[..8].copy_from_slice(&thread_idx.to_be_bytes());
+                    puzzle[8..16].copy_from_slice(&iter.to_be_bytes());
+                    let result = store.mark_puzzle_used(&puzzle, timestamp, 3600);
+                    assert!(result.is_ok())
+                })
+            });
+        });
+    }
+    group.finish();
+}
+
 criterion_group!(
     benches,
     build_puzzle_benchmark,
     verify_puzzle_result_with_benchmark,
+    concurrent_build_puzzle_benchmark,
+    concurrent_verify_puzzle_result_benchmark,
+    concurrent_mark_puzzle_used_benchmark,
 );
 criterion_main!(benches);
@@ -3,8 +3,12 @@ use actix_web::{
     get, http::StatusCode, post, web, App, Error, HttpResponse, HttpServer, Responder,
 };
 use fcaptcha::{
+    config::Settings,
     verify_puzzle_result,
-    web::{build_puzzle_service, verify_puzzle_result_service},
+    web::{
+        build_puzzle_service, legacy_verify_puzzle_result_service, verify_puzzle_result_service,
+    },
+    Fcaptcha, ReloadableFcaptcha,
 };
 use log::info;
 use serde::Deserialize;
@@ -20,13 +24,22 @@ struct FormInput {
 async fn main() -> std::io::Result<()> {
     env_logger::init();
 
-    HttpServer::new(|| {
+    let fcaptcha = web::Data::new(ReloadableFcaptcha::new(
+        Fcaptcha::from_settings(&Settings::from_config().expect("Invalid configuration"))
+            .expect("Invalid configuration"),
+    ));
+    HttpServer::new(move || {
         let cors = Cors::permissive();
         App::new()
             .wrap(cors)
+            .app_data(fcaptcha.clone())
             .route("/build-puzzle", web::get().to(build_puzzle_service))
             .route(
                 "/verify-puzzle-result",
+                web::post().to(legacy_verify_puzzle_result_service),
+            )
+            .route(
+                "/api/v1/siteverify",
                 web::post().to(verify_puzzle_result_service),
             )
             .service(demo_form)
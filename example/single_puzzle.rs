@@ -1,19 +1,13 @@
-use fcaptcha::{build_puzzle, verify_puzzle_result};
+use fcaptcha::{build_puzzle, solve_puzzle, verify_puzzle_result};
 
 fn main() {
     env_logger::init();
-    let puzzle = build_puzzle("127.0.0.1");
+    let puzzle = build_puzzle("127.0.0.1").unwrap();
     println!("Generated puzzle: {:?}", puzzle);
 
-    let solution = "3761fae80ef01b32dcf892d099ca07f31db7a97311cce59529a4bae93a801db4.\
-    ZO+cGAAAAAEAAAABAQwzegAAAAAAAAAAWlXMkohinFU=.\
-    AAAAAIgRAAABAAAAzHwAAAIAAAAuDQAAAwAAAPsUAAAEAAAACaMAAAUAAADEGgAABgAAAEcSAAAHAAAAvz0AAAgAAABhpQ\
-    AACQAAAAstAAAKAAAA2CYAAAsAAADtNgEADAAAAC0CAAANAAAAFp8AAA4AAABdcgAADwAAAL6JAAAQAAAALYkAABEAAAD0\
-    vAEAEgAAAPxaAAATAAAAvFAAABQAAAAA7wEAFQAAAPoWAAAWAAAAGoEAABcAAACovwAAGAAAAGXcAAAZAAAAP2sBABoAAA\
-    D4BQAAGwAAAE9nAAAcAAAAFcQBAB0AAABQCgEAHgAAAB0FAAAfAAAAe9EAACAAAAClywAAIQAAAFYPAAAiAAAAtjcAACMA\
-    AABIgQAAJAAAAJoPAQAlAAAAYlgAACYAAABIbAAAJwAAAGCwAAAoAAAAokkAACkAAADl6gAAKgAAAAo5AQArAAAA5igAAC\
-    wAAADVfAAALQAAAHYfAAAuAAAALdYAAC8AAAC11gEAMAAAAN1dAAAxAAAAbyEAADIAAADjwAAA.\
-    AgAA";
-    let result = verify_puzzle_result(solution);
+    let solution = solve_puzzle(&puzzle).unwrap();
+    println!("Solved puzzle: {:?}", solution);
+
+    let result = verify_puzzle_result(&solution);
     println!("Verification result: {:?}", result);
 }
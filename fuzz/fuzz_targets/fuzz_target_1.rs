@@ -1,9 +1,17 @@
 #![no_main]
 
-use fcaptcha::verify_puzzle_result_with;
+use fcaptcha::{build_puzzle::PuzzleOptions, verify_puzzle_result_with, Keyring, MemoryStore};
 use libfuzzer_sys::fuzz_target;
 
 fuzz_target!(|data: &str| {
-    // TODO: Can not proceed further then verify_signature()
-    let _ = verify_puzzle_result_with(data, 0, 0, "".as_bytes());
+    // TODO: Can not proceed further then the signature verification
+    let _ = verify_puzzle_result_with(
+        &MemoryStore::new(),
+        data,
+        None,
+        0,
+        0,
+        &Keyring::new(0, "".as_bytes()),
+        &PuzzleOptions::default(),
+    );
 });
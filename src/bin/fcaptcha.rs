@@ -0,0 +1,541 @@
+use clap::{Args, Parser, Subcommand, ValueEnum};
+use fcaptcha::build_puzzle::PuzzleOptions;
+use fcaptcha::config::Settings;
+use fcaptcha::difficulty::{Scaling, Tier, TierTable};
+use fcaptcha::puzzle::{Diagnostics, SOLUTION_LEN_BYTE};
+use fcaptcha::solve_puzzle::SolveOptions;
+use fcaptcha::store::MemoryStore;
+use fcaptcha::{Keyring, Puzzle, PuzzleSolution, SignedPuzzle};
+use serde_json::{json, Value};
+use std::process::ExitCode;
+
+/// Builds, inspects, solves and verifies puzzles. Defaults are read from the same environment
+/// variables as the server.
+#[derive(Parser)]
+#[command(name = "fcaptcha", version)]
+struct Cli {
+    /// Output format.
+    #[arg(long, short, value_enum, global = true, default_value_t = Format::Human)]
+    format: Format,
+    #[command(subcommand)]
+    command: Command,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, ValueEnum)]
+enum Format {
+    Human,
+    Json,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Builds a puzzle.
+    Build(BuildArgs),
+    /// Decodes a puzzle or solution into its fields and checks its signature.
+    Inspect(InspectArgs),
+    /// Solves a puzzle.
+    Solve(SolveArgs),
+    /// Verifies a solution.
+    Verify(VerifyArgs),
+}
+
+#[derive(Args)]
+struct KeyArgs {
+    /// Secret key, defaults to the keys of `FCAPTCHA_SECRET_KEY` and `FCAPTCHA_SECRET_KEYS`.
+    #[arg(long)]
+    key: Option<String>,
+    /// Id of the secret key given by `--key`.
+    #[arg(long, default_value_t = 0)]
+    key_id: u32,
+}
+
+impl KeyArgs {
+    fn keyring(&self, settings: &Settings) -> Result<Keyring, String> {
+        match &self.key {
+            Some(key) => Ok(Keyring::new(self.key_id, key.as_bytes())),
+            None => Keyring::from_settings(settings).map_err(|err| err.to_string()),
+        }
+    }
+}
+
+#[derive(Args)]
+struct OriginArgs {
+    /// Account id, defaults to `FCAPTCHA_ACCOUNT_ID`.
+    #[arg(long)]
+    account_id: Option<u32>,
+    /// App id, defaults to `FCAPTCHA_APP_ID`.
+    #[arg(long)]
+    app_id: Option<u32>,
+    /// Context the puzzle is bound to, e.g. the client IP address.
+    #[arg(long)]
+    context: Option<String>,
+}
+
+impl OriginArgs {
+    fn options(&self, settings: &Settings) -> PuzzleOptions {
+        PuzzleOptions::default()
+            .with_account_id(self.account_id.unwrap_or(settings.account_id))
+            .with_app_id(self.app_id.unwrap_or(settings.app_id))
+            .with_version(settings.puzzle_version)
+            .with_expiry(settings.puzzle_expiry)
+    }
+
+    fn context(&self) -> Option<&[u8]> {
+        self.context.as_deref().map(str::as_bytes)
+    }
+}
+
+#[derive(Args)]
+struct BuildArgs {
+    /// IP address of the client.
+    #[arg(long, default_value = "127.0.0.1")]
+    ip: String,
+    /// Time of building in seconds since the Unix epoch, defaults to now.
+    #[arg(long)]
+    timestamp: Option<u64>,
+    /// Nonce of the puzzle, random by default.
+    #[arg(long)]
+    nonce: Option<u64>,
+    /// Expiry in units of 5 minutes, defaults to `FCAPTCHA_PUZZLE_EXPIRY`.
+    #[arg(long)]
+    expiry: Option<u8>,
+    /// Number of solutions, defaults to the lowest tier of `FCAPTCHA_DIFFICULTY_TIERS`.
+    #[arg(long)]
+    solution_count: Option<u8>,
+    /// Difficulty, defaults to the lowest tier of `FCAPTCHA_DIFFICULTY_TIERS`.
+    #[arg(long)]
+    difficulty: Option<u8>,
+    #[command(flatten)]
+    origin: OriginArgs,
+    #[command(flatten)]
+    key: KeyArgs,
+}
+
+#[derive(Args)]
+struct InspectArgs {
+    /// Puzzle or solution, with or without signature.
+    input: String,
+    #[command(flatten)]
+    key: KeyArgs,
+}
+
+#[derive(Args)]
+struct SolveArgs {
+    /// Signed puzzle as served by the server.
+    puzzle: String,
+    /// Number of threads, defaults to all available.
+    #[arg(long)]
+    threads: Option<usize>,
+}
+
+#[derive(Args)]
+struct VerifyArgs {
+    /// Solution as submitted by the client.
+    solution: String,
+    /// Time of verification in seconds since the Unix epoch, defaults to now.
+    #[arg(long)]
+    timestamp: Option<u64>,
+    /// Time used puzzles are remembered for in seconds, defaults to `FCAPTCHA_PUZZLE_TTL`.
+    #[arg(long)]
+    ttl: Option<u64>,
+    #[command(flatten)]
+    origin: OriginArgs,
+    #[command(flatten)]
+    key: KeyArgs,
+}
+
+/// The result of a command, printed in the chosen format.
+struct Report {
+    /// Fields shown in the human format, in order.
+    lines: Vec<(&'static str, String)>,
+    json: Value,
+    success: bool,
+}
+
+fn timestamp_or_now(timestamp: Option<u64>) -> Result<u64, String> {
+    match timestamp {
+        Some(timestamp) => Ok(timestamp),
+        None => fcaptcha::get_timestamp().map_err(|err| err.to_string()),
+    }
+}
+
+fn build(args: &BuildArgs, settings: &Settings) -> Result<Report, String> {
+    let lowest_tier = TierTable::from_settings(settings)
+        .map_err(|err| err.to_string())?
+        .get(0);
+    let tiers = TierTable::new(vec![Tier {
+        min_access_count: 0,
+        scaling: Scaling::new(
+            args.solution_count.unwrap_or(lowest_tier.solution_count),
+            args.difficulty.unwrap_or(lowest_tier.difficulty),
+        ),
+    }])
+    .map_err(|err| err.to_string())?;
+    let mut options = args.origin.options(settings).with_difficulty_policy(tiers);
+    if let Some(expiry) = args.expiry {
+        options = options.with_expiry(expiry);
+    }
+
+    let puzzle = fcaptcha::build_puzzle_with(
+        &MemoryStore::new(),
+        &args.ip,
+        args.origin.context(),
+        timestamp_or_now(args.timestamp)?,
+        args.nonce.unwrap_or_else(rand::random),
+        &args.key.keyring(settings)?,
+        settings.access_ttl,
+        &options,
+    )
+    .map_err(|err| err.to_string())?;
+    Ok(Report {
+        lines: vec![("puzzle", puzzle.clone())],
+        json: json!({ "puzzle": puzzle }),
+        success: true,
+    })
+}
+
+fn puzzle_lines(puzzle: &Puzzle) -> Vec<(&'static str, String)> {
+    vec![
+        ("timestamp", puzzle.timestamp.to_string()),
+        ("account id", puzzle.account_id.to_string()),
+        ("app id", puzzle.app_id.to_string()),
+        ("version", puzzle.version.to_string()),
+        (
+            "expiry",
+            puzzle
+                .expiry_secs()
+                .map_or("none".to_string(), |secs| format!("{} s", secs)),
+        ),
+        ("solution count", puzzle.solution_count.to_string()),
+        ("difficulty", puzzle.difficulty.to_string()),
+        ("key id", puzzle.key_id.to_string()),
+        ("context tag", hex::encode(puzzle.context_tag)),
+        ("nonce", format!("{:#018x}", puzzle.nonce)),
+    ]
+}
+
+fn inspect(args: &InspectArgs, settings: &Settings) -> Result<Report, String> {
+    // Solutions have four parts, signed puzzles two and bare puzzles one.
+    let (signature, puzzle, solution) = match args.input.split('.').count() {
+        4 => {
+            let solution = args
+                .input
+                .parse::<PuzzleSolution>()
+                .map_err(|err| err.to_string())?;
+            (
+                Some(solution.signature.clone()),
+                solution.puzzle.clone(),
+                Some(solution),
+            )
+        }
+        2 => {
+            let signed = args
+                .input
+                .parse::<SignedPuzzle>()
+                .map_err(|err| err.to_string())?;
+            (Some(signed.signature), signed.puzzle, None)
+        }
+        _ => (
+            None,
+            args.input
+                .parse::<Puzzle>()
+                .map_err(|err| err.to_string())?,
+            None,
+        ),
+    };
+
+    let mut lines = puzzle_lines(&puzzle);
+    let mut json = json!({ "puzzle": puzzle });
+    let mut success = true;
+    if let Some(signature) = &signature {
+        let check = match args.key.keyring(settings)?.verify(&puzzle, signature) {
+            Ok(()) => "valid".to_string(),
+            Err(err) => {
+                success = false;
+                err.to_string()
+            }
+        };
+        lines.push((
+            "signature",
+            format!("{} ({})", hex::encode(signature), check),
+        ));
+        json["signature"] = json!({ "hex": hex::encode(signature), "check": check });
+    }
+    if let Some(solution) = &solution {
+        let solutions = solution.solutions.len() / SOLUTION_LEN_BYTE;
+        let diagnostics = Diagnostics::from_bytes(&solution.diagnostics);
+        lines.push(("solutions", solutions.to_string()));
+        lines.push(("diagnostics", format!("{:?}", diagnostics)));
+        json["solutions"] = json!(solutions);
+        json["diagnostics"] = json!(diagnostics);
+    }
+    Ok(Report {
+        lines,
+        json,
+        success,
+    })
+}
+
+fn solve(args: &SolveArgs, format: Format) -> Result<Report, String> {
+    let mut options = SolveOptions::default();
+    if let Some(threads) = args.threads {
+        options = options.with_threads(threads);
+    }
+    if format == Format::Human {
+        options = options.with_progress(|progress| {
+            eprint!(
+                "\rSolved {}/{} with {} hashes",
+                progress.solutions_found, progress.solution_count, progress.hashes
+            );
+            if progress.solutions_found == progress.solution_count {
+                eprintln!();
+            }
+        });
+    }
+
+    let solution =
+        fcaptcha::solve_puzzle_with(&args.puzzle, &options).map_err(|err| err.to_string())?;
+    Ok(Report {
+        lines: vec![("solution", solution.clone())],
+        json: json!({ "solution": solution }),
+        success: true,
+    })
+}
+
+fn verify(args: &VerifyArgs, settings: &Settings) -> Result<Report, String> {
+    let verdict = fcaptcha::verify_puzzle_result_with(
+        &MemoryStore::new(),
+        &args.solution,
+        args.origin.context(),
+        timestamp_or_now(args.timestamp)?,
+        args.ttl.unwrap_or(settings.puzzle_ttl),
+        &args.key.keyring(settings)?,
+        &args.origin.options(settings),
+    );
+
+    let mut lines = vec![("valid", verdict.is_ok().to_string())];
+    if let Some(failure) = &verdict.failure {
+        lines.push(("failed check", format!("{:?}", failure.check)));
+        lines.push(("error", failure.error.to_string()));
+        lines.push(("error code", failure.error.error_code().to_string()));
+    }
+    if let Some(age) = verdict.age {
+        lines.push(("age", format!("{} s", age)));
+    }
+    if let Some(diagnostics) = &verdict.diagnostics {
+        lines.push(("diagnostics", format!("{:?}", diagnostics)));
+    }
+    if let Some(puzzle) = &verdict.puzzle {
+        lines.extend(puzzle_lines(puzzle));
+    }
+
+    let json = json!({
+        "valid": verdict.is_ok(),
+        "failed_check": verdict.failed_check().map(|check| format!("{:?}", check)),
+        "error": verdict.error().map(ToString::to_string),
+        "error_code": verdict.error().map(|err| err.error_code()),
+        "age": verdict.age,
+        "diagnostics": verdict.diagnostics,
+        "puzzle": verdict.puzzle,
+    });
+    Ok(Report {
+        lines,
+        json,
+        success: verdict.is_ok(),
+    })
+}
+
+impl Report {
+    /// Returns the report as printed in `format`.
+    fn render(&self, format: Format) -> String {
+        match format {
+            Format::Human => self
+                .lines
+                .iter()
+                .map(|(name, value)| format!("{}: {}\n", name, value))
+                .collect(),
+            Format::Json => format!("{}\n", self.json),
+        }
+    }
+}
+
+/// Runs the command of `cli` with defaults taken from `settings`.
+fn run(cli: &Cli, settings: &Settings) -> Result<Report, String> {
+    match &cli.command {
+        Command::Build(args) => build(args, settings),
+        Command::Inspect(args) => inspect(args, settings),
+        Command::Solve(args) => solve(args, cli.format),
+        Command::Verify(args) => verify(args, settings),
+    }
+}
+
+fn main() -> ExitCode {
+    env_logger::init();
+    let cli = Cli::parse();
+    let report = Settings::from_config()
+        .map_err(|err| err.to_string())
+        .and_then(|settings| run(&cli, &settings));
+
+    match report {
+        Ok(report) => {
+            print!("{}", report.render(cli.format));
+            if report.success {
+                ExitCode::SUCCESS
+            } else {
+                ExitCode::FAILURE
+            }
+        }
+        Err(err) => {
+            match cli.format {
+                Format::Human => eprintln!("Error: {}", err),
+                Format::Json => println!("{}", json!({ "error": err })),
+            }
+            ExitCode::from(2)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TIMESTAMP: &str = "1693469848";
+
+    /// Runs the command line `args` and returns the output and whether it succeeded.
+    fn output(args: &[&str], settings: &Settings) -> Result<(String, bool), String> {
+        let cli = Cli::try_parse_from(["fcaptcha"].iter().chain(args)).unwrap();
+        let report = run(&cli, settings)?;
+        Ok((report.render(cli.format), report.success))
+    }
+
+    fn json_output(args: &[&str], settings: &Settings) -> (Value, bool) {
+        let mut args = args.to_vec();
+        args.extend(["--format", "json"]);
+        let (output, success) = output(&args, settings).unwrap();
+        (serde_json::from_str(&output).unwrap(), success)
+    }
+
+    #[test]
+    fn test_round_trip_json() {
+        let settings = Settings::default();
+        let (built, _) = json_output(
+            &[
+                "build",
+                "--timestamp",
+                TIMESTAMP,
+                "--solution-count",
+                "2",
+                "--difficulty",
+                "100",
+                "--context",
+                "192.0.2.1",
+            ],
+            &settings,
+        );
+        let puzzle = built["puzzle"].as_str().unwrap();
+
+        let (inspected, success) = json_output(&["inspect", puzzle], &settings);
+        assert!(success);
+        assert_eq!(inspected["signature"]["check"], "valid");
+        assert_eq!(inspected["puzzle"]["timestamp"], 1693469848);
+        assert_eq!(inspected["puzzle"]["solution_count"], 2);
+        assert_eq!(inspected["puzzle"]["difficulty"], 100);
+
+        let (solved, _) = json_output(&["solve", puzzle], &settings);
+        let solution = solved["solution"].as_str().unwrap();
+        let (inspected, _) = json_output(&["inspect", solution], &settings);
+        assert_eq!(inspected["solutions"], 2);
+
+        let verify = |context| {
+            json_output(
+                &[
+                    "verify",
+                    solution,
+                    "--timestamp",
+                    "1693469858",
+                    "--context",
+                    context,
+                ],
+                &settings,
+            )
+        };
+        let (verified, success) = verify("192.0.2.1");
+        assert!(success);
+        assert_eq!(verified["valid"], true);
+        assert_eq!(verified["age"], 10);
+        assert_eq!(verified["puzzle"]["difficulty"], 100);
+        let (verified, success) = verify("198.51.100.1");
+        assert!(!success);
+        assert_eq!(verified["error_code"], "solution_invalid");
+    }
+
+    #[test]
+    fn test_round_trip_human() {
+        let settings = Settings::default();
+        let key = ["--key", "OTHER-KEY", "--key-id", "3"];
+        let mut args = vec!["build", "--timestamp", TIMESTAMP, "--difficulty", "100"];
+        args.extend(["--solution-count", "2", "--app-id", "7"]);
+        args.extend(key);
+        let (built, _) = output(&args, &settings).unwrap();
+        let puzzle = built.strip_prefix("puzzle: ").unwrap().trim_end();
+
+        let (inspected, success) =
+            output(&[&["inspect", puzzle], &key[..]].concat(), &settings).unwrap();
+        assert!(success);
+        assert!(inspected.contains("\napp id: 7\n"), "{}", inspected);
+        assert!(inspected.contains("\nkey id: 3\n"), "{}", inspected);
+        assert!(inspected.contains(" (valid)\n"), "{}", inspected);
+        // The signature does not match the key of the settings.
+        let (inspected, success) = output(&["inspect", puzzle], &settings).unwrap();
+        assert!(!success);
+        assert!(!inspected.contains(" (valid)\n"), "{}", inspected);
+
+        let (solved, _) = output(&["solve", puzzle, "--threads", "1"], &settings).unwrap();
+        let solution = solved.strip_prefix("solution: ").unwrap().trim_end();
+        let mut args = vec![
+            "verify",
+            solution,
+            "--timestamp",
+            TIMESTAMP,
+            "--app-id",
+            "7",
+        ];
+        args.extend(key);
+        let (verified, success) = output(&args, &settings).unwrap();
+        assert!(success);
+        assert!(
+            verified.starts_with("valid: true\nage: 0 s\n"),
+            "{}",
+            verified
+        );
+        // Puzzles of other apps are not accepted.
+        args.retain(|arg| *arg != "--app-id" && *arg != "7");
+        let (verified, success) = output(&args, &settings).unwrap();
+        assert!(!success);
+        assert!(verified.starts_with("valid: false\n"), "{}", verified);
+    }
+
+    #[test]
+    fn test_defaults_from_settings() {
+        let settings = Settings {
+            app_id: 7,
+            puzzle_expiry: 0,
+            difficulty_tiers: "5:4:120,0:3:90".to_string(),
+            ..Settings::default()
+        };
+        let (built, _) = json_output(&["build", "--timestamp", TIMESTAMP], &settings);
+        let (inspected, _) =
+            json_output(&["inspect", built["puzzle"].as_str().unwrap()], &settings);
+        assert_eq!(inspected["signature"]["check"], "valid");
+        assert_eq!(inspected["puzzle"]["app_id"], 7);
+        assert_eq!(inspected["puzzle"]["expiry"], 0);
+        assert_eq!(inspected["puzzle"]["solution_count"], 3);
+        assert_eq!(inspected["puzzle"]["difficulty"], 90);
+
+        let settings = Settings {
+            secret_keys: "malformed".to_string(),
+            ..Settings::default()
+        };
+        assert!(output(&["build"], &settings).is_err());
+    }
+}
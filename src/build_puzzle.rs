@@ -1,23 +1,181 @@
-use crate::config::get;
-use crate::util;
+use crate::config::{ConfigError, Settings};
+use crate::difficulty::{
+    AdaptivePolicy, DifficultyPolicy, LoadMonitor, PuzzleRequest, Scaling, TierTable,
+};
+use crate::engine::default_fcaptcha;
+use crate::ip_rules::{IpRules, IpRulesError, RuleAction};
+use crate::keyring::{Keyring, KeyringError};
+use crate::prefix::AccessPrefixes;
+use crate::puzzle::{Puzzle, SignedPuzzle};
+use crate::store::{Access, CaptchaStore, StoreError};
 use base64::EncodeSliceError;
-use base64::{engine::general_purpose, Engine as _};
 use blake2::digest::InvalidLength;
 use displaydoc::Display;
-use hmac::{Hmac, Mac};
-use sha2::Sha256;
-use std::collections::HashMap;
 use std::str;
-use std::sync::{Mutex, PoisonError};
+use std::sync::{Arc, PoisonError};
 use std::time::SystemTimeError;
 use thiserror::Error;
 
-lazy_static! {
-    // TODO: Empty maps periodically!
-    static ref IP_ADDRESS_TO_ACCESS_MAP: Mutex<HashMap<String, Access>> =
-        Mutex::new(HashMap::new());
-    static ref ACCESS_TTL: u64 = get::<u64>("ACCESS_TTL");
-    static ref SECRET_KEY: Vec<u8> = get::<Vec<u8>>("SECRET_KEY");
+/// Describes the fields of a puzzle that do not depend on the individual request and the policy
+/// deciding about the others.
+///
+/// # Examples
+///
+/// ```
+/// use fcaptcha::build_puzzle::PuzzleOptions;
+///
+/// let options = PuzzleOptions::default().with_app_id(2).with_expiry(24);
+/// assert_eq!(options.account_id, 1);
+/// assert_eq!(options.app_id, 2);
+/// ```
+#[derive(Clone, Debug)]
+pub struct PuzzleOptions {
+    /// Identifies the account the puzzle is issued for.
+    pub account_id: u32,
+    /// Identifies the app, e.g. the site or form, the puzzle is issued for.
+    pub app_id: u32,
+    /// Version of the puzzle format.
+    pub version: u8,
+    /// Time after which the puzzle expires, in units of 5 minutes. Zero disables expiry.
+    pub expiry: u8,
+    /// Decides how costly the puzzle is.
+    pub difficulty_policy: Arc<dyn DifficultyPolicy>,
+    /// Network prefixes accesses are counted for.
+    pub access_prefixes: AccessPrefixes,
+    /// Rules for networks that are allowed, bypassed, throttled or denied.
+    pub ip_rules: Arc<IpRules>,
+    /// Monitor of the load the difficulty of tiers set by [PuzzleOptions::with_tiers] is raised
+    /// with, `None` if the load is not considered.
+    pub load_monitor: Option<Arc<LoadMonitor>>,
+}
+
+impl Default for PuzzleOptions {
+    fn default() -> Self {
+        PuzzleOptions {
+            account_id: 1,
+            app_id: 1,
+            version: 1,
+            expiry: 12,
+            difficulty_policy: Arc::new(TierTable::default()),
+            access_prefixes: AccessPrefixes::default(),
+            ip_rules: Arc::new(IpRules::default()),
+            load_monitor: None,
+        }
+    }
+}
+
+impl PuzzleOptions {
+    /// Reads the options from `account_id`, `app_id`, `puzzle_version` and `puzzle_expiry` of
+    /// `settings`, the difficulty tiers read by [TierTable::from_settings], raised with the load
+    /// tracked by a [LoadMonitor::from_settings], the network prefixes read by
+    /// [AccessPrefixes::from_settings] and the IP rules read by [IpRules::from_settings].
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use fcaptcha::build_puzzle::PuzzleOptions;
+    /// use fcaptcha::config::Settings;
+    ///
+    /// let settings = Settings {
+    ///     app_id: 2,
+    ///     load_thresholds: "100:8".to_string(),
+    ///     ..Settings::default()
+    /// };
+    /// let options = PuzzleOptions::from_settings(&settings).unwrap();
+    /// assert_eq!(options.app_id, 2);
+    /// assert!(options.load_monitor.is_some());
+    /// ```
+    pub fn from_settings(settings: &Settings) -> Result<PuzzleOptions, ConfigError> {
+        let load_monitor = LoadMonitor::from_settings(settings)
+            .map_err(|err| ConfigError::Invalid("load_thresholds", err.to_string()))?;
+        PuzzleOptions::from_settings_with_load(
+            settings,
+            Some(Arc::new(load_monitor)).filter(|monitor| monitor.is_enabled()),
+        )
+    }
+
+    /// Reads the options like [PuzzleOptions::from_settings], but raises the difficulty with the
+    /// load tracked by `load_monitor` instead of a new monitor.
+    pub(crate) fn from_settings_with_load(
+        settings: &Settings,
+        load_monitor: Option<Arc<LoadMonitor>>,
+    ) -> Result<PuzzleOptions, ConfigError> {
+        let tiers = TierTable::from_settings(settings)
+            .map_err(|err| ConfigError::Invalid("difficulty_tiers", err.to_string()))?;
+        let access_prefixes = AccessPrefixes::from_settings(settings)
+            .map_err(|err| ConfigError::Invalid("access_prefixes", err.to_string()))?;
+        let ip_rules = IpRules::from_settings(settings).map_err(|err| match err {
+            IpRulesError::FileUnreadable(..) => {
+                ConfigError::Invalid("ip_rules_file", err.to_string())
+            }
+            err => ConfigError::Invalid("ip_rules", err.to_string()),
+        })?;
+        Ok(PuzzleOptions {
+            account_id: settings.account_id,
+            app_id: settings.app_id,
+            version: settings.puzzle_version,
+            expiry: settings.puzzle_expiry,
+            access_prefixes,
+            ip_rules: Arc::new(ip_rules),
+            load_monitor,
+            ..PuzzleOptions::default()
+        }
+        .with_tiers(tiers))
+    }
+
+    /// Sets the account id.
+    pub fn with_account_id(mut self, account_id: u32) -> PuzzleOptions {
+        self.account_id = account_id;
+        self
+    }
+
+    /// Sets the app id.
+    pub fn with_app_id(mut self, app_id: u32) -> PuzzleOptions {
+        self.app_id = app_id;
+        self
+    }
+
+    /// Sets the version of the puzzle format.
+    pub fn with_version(mut self, version: u8) -> PuzzleOptions {
+        self.version = version;
+        self
+    }
+
+    /// Sets the expiry in units of 5 minutes. Zero disables expiry.
+    pub fn with_expiry(mut self, expiry: u8) -> PuzzleOptions {
+        self.expiry = expiry;
+        self
+    }
+
+    /// Sets the policy deciding how costly the puzzle is.
+    pub fn with_difficulty_policy<P: DifficultyPolicy + 'static>(
+        mut self,
+        difficulty_policy: P,
+    ) -> PuzzleOptions {
+        self.difficulty_policy = Arc::new(difficulty_policy);
+        self
+    }
+
+    /// Sets the difficulty policy to `tiers`, raised with the load of the load monitor, if any.
+    pub fn with_tiers(mut self, tiers: TierTable) -> PuzzleOptions {
+        self.difficulty_policy = match &self.load_monitor {
+            Some(monitor) => Arc::new(AdaptivePolicy::new(tiers, Arc::clone(monitor))),
+            None => Arc::new(tiers),
+        };
+        self
+    }
+
+    /// Sets the network prefixes accesses are counted for.
+    pub fn with_access_prefixes(mut self, access_prefixes: AccessPrefixes) -> PuzzleOptions {
+        self.access_prefixes = access_prefixes;
+        self
+    }
+
+    /// Sets the rules for networks that are allowed, bypassed, throttled or denied.
+    pub fn with_ip_rules(mut self, ip_rules: Arc<IpRules>) -> PuzzleOptions {
+        self.ip_rules = ip_rules;
+        self
+    }
 }
 
 /// Describes an error that occurred during building a puzzle.
@@ -27,12 +185,20 @@ pub enum BuildPuzzleError {
     Encoding(#[from] EncodeSliceError),
     /// Hashing failed.
     Hashing(#[from] InvalidLength),
+    /// Signing failed: {0}
+    Signing(#[from] KeyringError),
     /// Data access failed.
     DataAccess,
+    /// Store access failed: {0}
+    Store(#[from] StoreError),
     /// Data conversion failed.
     Conversion,
     /// Failed to get the time.
     TimeError(#[from] SystemTimeError),
+    /// Puzzles are denied for the IP address.
+    Denied,
+    /// Configuration invalid: {0}
+    Config(String),
     /// Unknown error.
     Unknown,
 }
@@ -43,89 +209,10 @@ impl<T> From<PoisonError<T>> for BuildPuzzleError {
     }
 }
 
-#[derive(Clone, Debug)]
-struct Access {
-    count: u64,
-    last_access: u64,
-}
-
-impl Access {
-    fn get(ip_address: &str, timestamp: u64, access_ttl: u64) -> Result<Access, BuildPuzzleError> {
-        let mut lock = IP_ADDRESS_TO_ACCESS_MAP.lock()?;
-        Ok(lock
-            .entry(ip_address.to_string())
-            .and_modify(|access| {
-                if timestamp - access.last_access > access_ttl {
-                    access.count = 1;
-                } else {
-                    access.count += 1;
-                }
-                access.last_access = timestamp;
-            })
-            .or_insert(Access {
-                count: 1,
-                last_access: timestamp,
-            })
-            .clone())
-    }
-}
-
-#[derive(Debug)]
-struct Scaling {
-    solution_count: u8,
-    difficulty: u8,
-}
-
-impl Scaling {
-    fn new(solution_count: u8, difficulty: u8) -> Scaling {
-        Scaling {
-            solution_count,
-            difficulty,
-        }
-    }
-
-    fn get(access_count: u64) -> Scaling {
-        if access_count > 20 {
-            Scaling::new(45, 149)
-        } else if access_count > 10 {
-            Scaling::new(45, 141)
-        } else if access_count > 4 {
-            Scaling::new(51, 130)
-        } else {
-            Scaling::new(51, 122)
-        }
-    }
-}
-
-fn construct_puzzle_data(
-    timestamp: u64,
-    nonce: u64,
-    scaling: Scaling,
-    data_buffer: &mut [u8],
-) -> Result<(), BuildPuzzleError> {
-    let timestamp_truncated: u32 = timestamp
-        .try_into()
-        .map_err(|_| BuildPuzzleError::Conversion)?;
-    // TODO: Make configurable
-    let account_id: u32 = 1;
-    let app_id: u32 = 1;
-    let puzzle_ver: u8 = 1;
-    let puzzle_expiry: u8 = 12;
-
-    data_buffer[0..][..4].copy_from_slice(&timestamp_truncated.to_be_bytes());
-    data_buffer[4..][..4].copy_from_slice(&account_id.to_be_bytes());
-    data_buffer[8..][..4].copy_from_slice(&app_id.to_be_bytes());
-    data_buffer[12] = puzzle_ver;
-    data_buffer[13] = puzzle_expiry;
-    data_buffer[14] = scaling.solution_count;
-    data_buffer[15] = scaling.difficulty;
-    // [16..][..4] = Reserved: zero
-    data_buffer[24..][..8].copy_from_slice(&nonce.to_be_bytes());
-    Ok(())
-}
-
 /// Builds a new puzzle for an `ip_address`.
-/// Can be configured with the environment variables `FCAPTCHA_ACCESS_TTL` and `FCAPTCHA_SECRET_KEY`.
+/// Can be configured with the environment variable `FCAPTCHA_ACCESS_TTL` and the ones read by
+/// [Keyring::from_settings] and [PuzzleOptions::from_settings]. Fails with
+/// [BuildPuzzleError::Config] if the configuration is malformed or invalid.
 ///
 /// # Examples
 ///
@@ -136,76 +223,130 @@ fn construct_puzzle_data(
 /// println!("{:?}", puzzle.unwrap());
 /// ```
 pub fn build_puzzle(ip_address: &str) -> Result<String, BuildPuzzleError> {
-    let timestamp = util::get_timestamp()?;
-    let nonce: u64 = rand::random();
-    build_puzzle_with(ip_address, timestamp, nonce, &SECRET_KEY, *ACCESS_TTL)
+    let (fcaptcha, tenant) = default_fcaptcha().map_err(BuildPuzzleError::Config)?;
+    fcaptcha.build_puzzle(tenant, ip_address)
 }
 
 /// Builds a new puzzle. In contrast to [build_puzzle] all input variables can be controlled
-/// directly instead deriving them from environment variables.
+/// directly instead deriving them from environment variables. Accesses are counted in `store` for
+/// the network prefixes of `ip_address` given by `options`, the difficulty is decided by the
+/// policy of `options`. Addresses matching an IP rule of `options` are not counted: allowed ones
+/// are scaled as without accesses, throttled ones as with the most accesses, bypassed ones get a
+/// trivial puzzle and denied ones fail with [BuildPuzzleError::Denied]. The puzzle is signed with
+/// the active key of `keyring`. If a `context` is given, e.g. the IP address of the client or a
+/// session token, the puzzle is bound to it and only accepted when verified with the same context.
 ///
 /// # Examples
 ///
 /// ```
+/// use fcaptcha::build_puzzle::PuzzleOptions;
+/// use fcaptcha::store::MemoryStore;
+/// use fcaptcha::Keyring;
 /// use std::time::SystemTime;
+/// let store = MemoryStore::new();
 /// let ip_address = "127.0.0.1";
 /// let timestamp = SystemTime::now()
 ///     .duration_since(SystemTime::UNIX_EPOCH)
 ///     .unwrap()
 ///     .as_secs();
-/// let secret_key = "SECRET-KEY".as_bytes();
+/// let keyring = Keyring::new(1, "SECRET-KEY".as_bytes());
 /// let nonce = rand::random();
 /// let access_ttl_secs = 1800;
-/// let puzzle =
-///      fcaptcha::build_puzzle_with(ip_address, timestamp, nonce, secret_key, access_ttl_secs);
+/// let options = PuzzleOptions::default().with_app_id(2);
+/// let puzzle = fcaptcha::build_puzzle_with(
+///     &store,
+///     ip_address,
+///     None,
+///     timestamp,
+///     nonce,
+///     &keyring,
+///     access_ttl_secs,
+///     &options,
+/// );
 /// println!("{:?}", puzzle.unwrap());
 /// ```
-pub fn build_puzzle_with(
+#[allow(clippy::too_many_arguments)]
+pub fn build_puzzle_with<S: CaptchaStore + ?Sized>(
+    store: &S,
     ip_address: &str,
+    context: Option<&[u8]>,
     timestamp: u64,
     nonce: u64,
-    secret_key: &[u8],
+    keyring: &Keyring,
     access_ttl_secs: u64,
+    options: &PuzzleOptions,
 ) -> Result<String, BuildPuzzleError> {
-    let access = Access::get(ip_address, timestamp, access_ttl_secs)?;
-    let scaling = Scaling::get(access.count);
+    let rule_access = |count| Access {
+        count,
+        last_access: timestamp,
+    };
+    let access = match options.ip_rules.action(ip_address, timestamp) {
+        Some(RuleAction::Deny) => {
+            info!("Denying puzzle for ip_address: {:?}", ip_address);
+            return Err(BuildPuzzleError::Denied);
+        }
+        Some(RuleAction::Bypass) => None,
+        Some(RuleAction::Allow) => Some(rule_access(0)),
+        Some(RuleAction::Throttle) => Some(rule_access(u64::MAX)),
+        // Scale with the network prefix having the most accesses.
+        None => Some(
+            options
+                .access_prefixes
+                .keys(ip_address)
+                .iter()
+                .map(|key| store.record_access(key, timestamp, access_ttl_secs))
+                .collect::<Result<Vec<_>, _>>()?
+                .into_iter()
+                .max_by_key(|access| access.count)
+                .ok_or(BuildPuzzleError::Unknown)?,
+        ),
+    };
+    // Any solution satisfies a difficulty of zero.
+    let scaling = match &access {
+        Some(access) => options.difficulty_policy.scaling(&PuzzleRequest {
+            ip_address,
+            access,
+            timestamp,
+        }),
+        None => Scaling::new(1, 0),
+    };
 
     info!(
         "Creating puzzle for ip_address: {:?}, timestamp: {:?}, access: {:?}, scaling: {:?}",
         ip_address, timestamp, access, scaling
     );
 
-    let mut puzzle_data: [u8; 32] = [0; 32];
-    construct_puzzle_data(timestamp, nonce, scaling, &mut puzzle_data)?;
-
-    // HMAC data
-    type HmacSha256 = Hmac<Sha256>;
-    let mut macer = HmacSha256::new_from_slice(secret_key)?;
-    macer.update(&puzzle_data);
-    let hmac = macer.finalize().into_bytes();
-
-    // Base 64 encode data
-    let mut data_b64: [u8; 44] = [0; 44];
-    let data_b64_len = general_purpose::STANDARD.encode_slice(puzzle_data, &mut data_b64)?;
-    debug_assert!(data_b64_len == 44);
-
-    // Concatenate HMAC and data
-    let puzzle = format!(
-        "{}.{}",
-        hex::encode(hmac),
-        String::from_utf8_lossy(&data_b64)
-    );
+    let mut puzzle = Puzzle {
+        timestamp: timestamp
+            .try_into()
+            .map_err(|_| BuildPuzzleError::Conversion)?,
+        account_id: options.account_id,
+        app_id: options.app_id,
+        version: options.version,
+        expiry: options.expiry,
+        solution_count: scaling.solution_count,
+        difficulty: scaling.difficulty,
+        key_id: keyring.active_key_id(),
+        context_tag: [0; 4],
+        nonce,
+    };
+    if let Some(context) = context {
+        puzzle.context_tag = keyring.context_tag(puzzle.key_id, context)?;
+    }
+    let signature = keyring.sign(&mut puzzle)?;
 
+    let puzzle = SignedPuzzle { signature, puzzle }.to_string();
     Ok(puzzle)
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::store::MemoryStore;
 
     #[test]
     fn test_build_puzzle_with_timestamp_and_nonce() -> Result<(), BuildPuzzleError> {
-        let secret_key = "TEST-KEY".as_bytes();
+        let keyring = Keyring::new(0, "TEST-KEY".as_bytes());
         let ip_address = "127.0.0.1";
         let timestamp = 1693469848;
         let nonce = 0x1122334455667788;
@@ -213,59 +354,210 @@ mod tests {
         ZPBMmAAAAAEAAAABAQwzegAAAAAAAAAAESIzRFVmd4g=";
         let access_ttl = 1800;
 
-        let puzzle = build_puzzle_with(ip_address, timestamp, nonce, secret_key, access_ttl)?;
+        let store = MemoryStore::new();
+        let puzzle = build_puzzle_with(
+            &store,
+            ip_address,
+            None,
+            timestamp,
+            nonce,
+            &keyring,
+            access_ttl,
+            &PuzzleOptions::default(),
+        )?;
 
         assert_eq!(expected_puzzle, puzzle);
         Ok(())
     }
 
     #[test]
-    fn test_get_access_first() -> Result<(), BuildPuzzleError> {
-        let ip_address: &str = "192.168.0.1";
-        let timestamp = 1234_u64;
+    fn test_build_puzzle_with_scaling_per_store() -> Result<(), BuildPuzzleError> {
+        let keyring = Keyring::new(0, "TEST-KEY".as_bytes());
+        let ip_address = "127.0.0.1";
+        let timestamp = 1693469848;
         let access_ttl = 1800;
-        let access = Access::get(ip_address, timestamp, access_ttl)?;
-        assert!(access.count == 1);
-        assert!(access.last_access == timestamp);
+        let options = PuzzleOptions::default();
+        let difficulty = |puzzle: &str| puzzle.parse::<SignedPuzzle>().unwrap().puzzle.difficulty;
+
+        let store = MemoryStore::new();
+        for _ in 0..4 {
+            build_puzzle_with(
+                &store, ip_address, None, timestamp, 0, &keyring, access_ttl, &options,
+            )?;
+        }
+        let puzzle = build_puzzle_with(
+            &store, ip_address, None, timestamp, 0, &keyring, access_ttl, &options,
+        )?;
+        assert_eq!(difficulty(&puzzle), 130);
+
+        let other_store = MemoryStore::new();
+        let puzzle = build_puzzle_with(
+            &other_store,
+            ip_address,
+            None,
+            timestamp,
+            0,
+            &keyring,
+            access_ttl,
+            &options,
+        )?;
+        assert_eq!(difficulty(&puzzle), 122);
         Ok(())
     }
 
     #[test]
-    fn test_get_access_second() -> Result<(), BuildPuzzleError> {
-        let ip_address = "192.168.0.2";
-        let timestamp: u64 = 1234_u64;
-        let access_ttl = 1800;
-        Access::get(ip_address, timestamp, access_ttl)?;
-        let timestamp: u64 = 1235_u64;
-        let access = Access::get(ip_address, timestamp, access_ttl)?;
-        assert_eq!(access.count, 2);
-        assert_eq!(access.last_access, timestamp);
+    fn test_build_puzzle_with_options() -> Result<(), BuildPuzzleError> {
+        let options = PuzzleOptions::default()
+            .with_account_id(0x01020304)
+            .with_app_id(0x05060708)
+            .with_version(2)
+            .with_expiry(24);
+
+        let puzzle = build_puzzle_with(
+            &MemoryStore::new(),
+            "127.0.0.1",
+            None,
+            1693469848,
+            0,
+            &Keyring::new(0, "TEST-KEY".as_bytes()),
+            1800,
+            &options,
+        )?;
+
+        let puzzle = puzzle.parse::<SignedPuzzle>().unwrap().puzzle;
+        assert_eq!(puzzle.account_id, 0x01020304);
+        assert_eq!(puzzle.app_id, 0x05060708);
+        assert_eq!(puzzle.version, 2);
+        assert_eq!(puzzle.expiry, 24);
         Ok(())
     }
 
     #[test]
-    fn test_get_access_second_within_ttl() -> Result<(), BuildPuzzleError> {
-        let ip_address = "192.168.0.3";
-        let timestamp: u64 = 1234_u64;
-        let access_ttl = 1800;
-        Access::get(ip_address, timestamp, access_ttl)?;
-        let timestamp = 1234_u64 + *ACCESS_TTL;
-        let access = Access::get(ip_address, timestamp, access_ttl)?;
-        assert_eq!(access.count, 2);
-        assert_eq!(access.last_access, timestamp);
+    fn test_build_puzzle_with_difficulty_policy() -> Result<(), BuildPuzzleError> {
+        #[derive(Debug)]
+        struct ByAddress;
+
+        impl DifficultyPolicy for ByAddress {
+            fn scaling(&self, request: &PuzzleRequest<'_>) -> Scaling {
+                match request.ip_address {
+                    "10.0.0.1" => Scaling::new(1, 200),
+                    _ => Scaling::new(2, 100),
+                }
+            }
+        }
+
+        let options = PuzzleOptions::default().with_difficulty_policy(ByAddress);
+        let keyring = Keyring::new(0, "TEST-KEY".as_bytes());
+        let store = MemoryStore::new();
+        let scaling = |ip_address| -> Result<Scaling, BuildPuzzleError> {
+            let puzzle = build_puzzle_with(
+                &store, ip_address, None, 1693469848, 0, &keyring, 1800, &options,
+            )?;
+            let puzzle = puzzle.parse::<SignedPuzzle>().unwrap().puzzle;
+            Ok(Scaling::new(puzzle.solution_count, puzzle.difficulty))
+        };
+        assert_eq!(scaling("10.0.0.1")?, Scaling::new(1, 200));
+        assert_eq!(scaling("10.0.0.2")?, Scaling::new(2, 100));
+
+        let options = PuzzleOptions::default()
+            .with_difficulty_policy("0:10:100,2:20:200".parse::<TierTable>().unwrap());
+        let build = || {
+            build_puzzle_with(
+                &store, "10.0.0.3", None, 1693469848, 0, &keyring, 1800, &options,
+            )
+        };
+        assert_eq!(
+            build()?.parse::<SignedPuzzle>().unwrap().puzzle.difficulty,
+            100
+        );
+        assert_eq!(
+            build()?.parse::<SignedPuzzle>().unwrap().puzzle.difficulty,
+            200
+        );
         Ok(())
     }
 
     #[test]
-    fn test_get_access_second_after_ttl() -> Result<(), BuildPuzzleError> {
-        let ip_address = "192.168.0.4";
-        let timestamp: u64 = 1234_u64;
-        let access_ttl = 1800;
-        Access::get(ip_address, timestamp, access_ttl)?;
-        let timestamp = 1234_u64 + *ACCESS_TTL + 1;
-        let access = Access::get(ip_address, timestamp, access_ttl)?;
-        assert_eq!(access.count, 1);
-        assert_eq!(access.last_access, timestamp);
+    fn test_build_puzzle_with_access_prefixes() -> Result<(), BuildPuzzleError> {
+        let keyring = Keyring::new(0, "TEST-KEY".as_bytes());
+        let store = MemoryStore::new();
+        let options = PuzzleOptions::default()
+            .with_access_prefixes(AccessPrefixes::new(vec![32, 24], vec![64, 56]).unwrap());
+        let difficulty = |ip_address: &str| -> Result<u8, BuildPuzzleError> {
+            let puzzle = build_puzzle_with(
+                &store, ip_address, None, 1693469848, 0, &keyring, 1800, &options,
+            )?;
+            Ok(puzzle.parse::<SignedPuzzle>().unwrap().puzzle.difficulty)
+        };
+
+        // Rotating addresses within a /64 does not reset the access count.
+        for idx in 1..=4 {
+            assert_eq!(difficulty(&format!("2001:db8:0:1::{}", idx))?, 122);
+        }
+        assert_eq!(difficulty("2001:db8:0:1::5")?, 130);
+        // Neither does rotating /64 networks within a /56.
+        assert_eq!(difficulty("2001:db8:0:2::1")?, 130);
+
+        for idx in 1..=5 {
+            difficulty(&format!("10.0.0.{}", idx))?;
+        }
+        assert_eq!(difficulty("10.0.0.99")?, 130);
+        assert_eq!(difficulty("10.0.1.1")?, 122);
+        Ok(())
+    }
+
+    #[test]
+    fn test_build_puzzle_with_ip_rules() -> Result<(), BuildPuzzleError> {
+        let keyring = Keyring::new(0, "TEST-KEY".as_bytes());
+        let store = MemoryStore::new();
+        let rules = "allow 10.0.0.0/8, bypass 10.1.0.0/16, throttle 192.0.2.0/24, deny 192.0.2.66";
+        let options =
+            PuzzleOptions::default().with_ip_rules(Arc::new(IpRules::new(rules.parse().unwrap())));
+        let scaling = |ip_address: &str| -> Result<Scaling, BuildPuzzleError> {
+            let puzzle = build_puzzle_with(
+                &store, ip_address, None, 1693469848, 0, &keyring, 1800, &options,
+            )?;
+            let puzzle = puzzle.parse::<SignedPuzzle>().unwrap().puzzle;
+            Ok(Scaling::new(puzzle.solution_count, puzzle.difficulty))
+        };
+
+        for _ in 0..30 {
+            assert_eq!(scaling("10.0.0.1")?, Scaling::new(51, 122));
+        }
+        assert_eq!(scaling("10.1.0.1")?, Scaling::new(1, 0));
+        assert_eq!(scaling("192.0.2.1")?, Scaling::new(45, 149));
+        assert!(matches!(
+            scaling("192.0.2.66"),
+            Err(BuildPuzzleError::Denied)
+        ));
+        // Accesses matching a rule are not counted.
+        assert_eq!(
+            store.record_access("10.0.0.1/32", 1693469848, 1800)?.count,
+            1
+        );
+        assert_eq!(scaling("198.51.100.1")?, Scaling::new(51, 122));
+        Ok(())
+    }
+
+    #[test]
+    fn test_build_puzzle_with_key_id() -> Result<(), BuildPuzzleError> {
+        let keyring = Keyring::new(0x0a0b0c0d, "TEST-KEY".as_bytes());
+
+        let puzzle = build_puzzle_with(
+            &MemoryStore::new(),
+            "127.0.0.1",
+            None,
+            1693469848,
+            0,
+            &keyring,
+            1800,
+            &PuzzleOptions::default(),
+        )?;
+
+        let signed_puzzle = puzzle.parse::<SignedPuzzle>().unwrap();
+        assert_eq!(signed_puzzle.puzzle.key_id, 0x0a0b0c0d);
+        assert_eq!(signed_puzzle.puzzle.to_bytes()[16..20], [10, 11, 12, 13]);
+        keyring.verify(&signed_puzzle.puzzle, &signed_puzzle.signature)?;
         Ok(())
     }
 }
@@ -0,0 +1,103 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{SystemTime, SystemTimeError};
+
+/// A source of the current time, used for building puzzles, checking their expiry and expiring
+/// used puzzles and access counts.
+///
+/// Implement this trait to control time, e.g. for deterministic tests or for simulations running
+/// faster than real time.
+pub trait Clock: Send + Sync {
+    /// Returns the current time in seconds since the Unix epoch.
+    fn now(&self) -> Result<u64, SystemTimeError>;
+}
+
+/// The system clock.
+///
+/// # Examples
+///
+/// ```
+/// use fcaptcha::clock::{Clock, SystemClock};
+///
+/// assert!(SystemClock.now().unwrap() > 1693424664);
+/// ```
+#[derive(Clone, Copy, Debug, Default)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> Result<u64, SystemTimeError> {
+        Ok(SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)?
+            .as_secs())
+    }
+}
+
+/// A clock that always returns the same time.
+///
+/// # Examples
+///
+/// ```
+/// use fcaptcha::clock::{Clock, FixedClock};
+///
+/// assert_eq!(FixedClock::new(1693424664).now().unwrap(), 1693424664);
+/// ```
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct FixedClock {
+    timestamp: u64,
+}
+
+impl FixedClock {
+    /// Creates a clock that always returns `timestamp`.
+    pub fn new(timestamp: u64) -> FixedClock {
+        FixedClock { timestamp }
+    }
+}
+
+impl Clock for FixedClock {
+    fn now(&self) -> Result<u64, SystemTimeError> {
+        Ok(self.timestamp)
+    }
+}
+
+/// A clock that only moves when it is advanced or set. Can be shared, e.g. through an `Arc`, to
+/// control the time of an [Fcaptcha](crate::Fcaptcha) engine from a test.
+///
+/// # Examples
+///
+/// ```
+/// use fcaptcha::clock::{Clock, ManualClock};
+///
+/// let clock = ManualClock::new(1693424664);
+/// clock.advance(60);
+/// assert_eq!(clock.now().unwrap(), 1693424724);
+/// clock.set(1693424664);
+/// assert_eq!(clock.now().unwrap(), 1693424664);
+/// ```
+#[derive(Debug, Default)]
+pub struct ManualClock {
+    timestamp: AtomicU64,
+}
+
+impl ManualClock {
+    /// Creates a clock starting at `timestamp`.
+    pub fn new(timestamp: u64) -> ManualClock {
+        ManualClock {
+            timestamp: AtomicU64::new(timestamp),
+        }
+    }
+
+    /// Moves the clock forward by `secs`.
+    pub fn advance(&self, secs: u64) {
+        self.timestamp.fetch_add(secs, Ordering::Relaxed);
+    }
+
+    /// Sets the clock to `timestamp`, which may also lie in the past.
+    pub fn set(&self, timestamp: u64) {
+        self.timestamp.store(timestamp, Ordering::Relaxed);
+    }
+}
+
+impl Clock for ManualClock {
+    fn now(&self) -> Result<u64, SystemTimeError> {
+        Ok(self.timestamp.load(Ordering::Relaxed))
+    }
+}
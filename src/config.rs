@@ -1,25 +1,779 @@
-use config::Config;
+use crate::build_puzzle::PuzzleOptions;
+use crate::ip_rules::TrustedProxies;
+use crate::keyring::parse_secret_keys;
+use crate::tenant::{TenantError, TenantRegistry};
+use config::{Config, Environment, File, Source};
+use displaydoc::Display;
+use log::info;
+use serde::{Deserialize, Serialize};
+use std::env;
+use std::fmt;
+use std::fs::{self, OpenOptions};
+use std::io::{self, Write};
+#[cfg(unix)]
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, PoisonError, RwLock};
+use thiserror::Error;
 
 lazy_static! {
-    static ref CONFIG: Config = Config::builder()
-        .set_default("BIND_ADDRESS", "0.0.0.0")
-        .unwrap()
-        .set_default("BIND_PORT", 8080)
-        .unwrap()
-        .set_default("ACCESS_TTL", 1800)
-        .unwrap()
-        .set_default("PUZZLE_TTL", 3600)
-        .unwrap()
-        .set_default("SECRET_KEY", "NOT-A-SECRET-KEY".as_bytes().to_vec())
-        .unwrap()
-        .set_default("API_KEY", "NOT-AN-API-KEY".as_bytes().to_vec())
-        .unwrap()
-        .add_source(config::Environment::with_prefix("FCAPTCHA").separator("_"))
-        .build()
-        .unwrap();
-}
-
-/// Get a configuration element.
-pub fn get<'a, T: serde::Deserialize<'a>>(key: &str) -> T {
-    CONFIG.get::<T>(key).unwrap()
+    /// The process-wide configuration, or the error reading or validating it failed with.
+    static ref CONFIG: RwLock<Result<Arc<LoadedConfig>, ConfigError>> =
+        RwLock::new(load_current().map(Arc::new));
+}
+
+/// A configuration together with the settings read from it.
+#[derive(Debug)]
+pub(crate) struct LoadedConfig {
+    config: Config,
+    pub(crate) settings: Arc<Settings>,
+}
+
+/// Describes an error that occurred during loading or validating the configuration.
+#[derive(Clone, Display, Error, Debug, PartialEq)]
+pub enum ConfigError {
+    /// Reading the configuration failed: {0}
+    Malformed(String),
+    /// Configuration of {0} is invalid: {1}
+    Invalid(&'static str, String),
+}
+
+impl From<config::ConfigError> for ConfigError {
+    fn from(err: config::ConfigError) -> Self {
+        Self::Malformed(err.to_string())
+    }
+}
+
+/// Minimum length of keys puzzles are signed with in production mode.
+pub const MIN_SECRET_KEY_LEN: usize = 32;
+
+/// Minimum length of API keys and tenant secrets in production mode.
+pub const MIN_API_KEY_LEN: usize = 16;
+
+/// Settings that only take effect after a restart, as they concern the listening socket, the store,
+/// whose state is kept on [ReloadableFcaptcha::reload](crate::ReloadableFcaptcha::reload), or the
+/// load, which is measured across reloads.
+pub const RESTART_REQUIRED: &[&str] = &[
+    "bind_address",
+    "bind_port",
+    "store_backend",
+    "store_data_dir",
+    "store_redis_url",
+    "store_shards",
+    "store_max_entries",
+    "store_eviction_interval",
+    "load_window",
+    "load_decay",
+    "load_thresholds",
+];
+
+const DEFAULT_SECRET_KEY: &str = "NOT-A-SECRET-KEY";
+const DEFAULT_API_KEY: &str = "NOT-AN-API-KEY";
+
+/// The mode the server runs in.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Mode {
+    /// For development, default and short keys are accepted.
+    #[default]
+    Dev,
+    /// For production, default and short keys are rejected, see [Settings::check_keys].
+    Prod,
+}
+
+/// The settings of the server, read from an optional configuration file and overridden by the
+/// `FCAPTCHA_*` environment variables. The file is given by `FCAPTCHA_CONFIG_FILE` and its format,
+/// TOML, YAML or JSON, is detected by its extension. Keys are the names of the environment
+/// variables without prefix in lower case, e.g. `puzzle_ttl = 600`.
+///
+/// # Examples
+///
+/// ```
+/// use fcaptcha::config::Settings;
+///
+/// let settings = Settings::default();
+/// assert_eq!(settings.puzzle_ttl, 3600);
+/// assert!(settings.validate().is_ok());
+/// ```
+#[derive(Clone, PartialEq, Deserialize, Serialize)]
+#[serde(default)]
+pub struct Settings {
+    /// Mode the server runs in.
+    pub mode: Mode,
+    /// Address the server listens on.
+    pub bind_address: String,
+    /// Port the server listens on.
+    pub bind_port: u16,
+    /// Time in seconds accesses of a client are counted for.
+    pub access_ttl: u64,
+    /// Time in seconds used puzzles are remembered for.
+    pub puzzle_ttl: u64,
+    /// Account id of the puzzles.
+    pub account_id: u32,
+    /// App id of the puzzles.
+    pub app_id: u32,
+    /// Version of the puzzles.
+    pub puzzle_version: u8,
+    /// Expiry of the puzzles in units of 5 minutes, `0` for none.
+    pub puzzle_expiry: u8,
+    /// Comma separated prefix lengths IPv4 accesses are counted for.
+    pub access_prefixes_v4: String,
+    /// Comma separated prefix lengths IPv6 accesses are counted for.
+    pub access_prefixes_v6: String,
+    /// Whether puzzles are bound to the IP address of the client.
+    pub ip_binding: bool,
+    /// Rules for networks, see [RuleSet](crate::ip_rules::RuleSet).
+    pub ip_rules: String,
+    /// File with further rules for networks, empty for none.
+    pub ip_rules_file: String,
+    /// Networks of reverse proxies whose forwarded client addresses are trusted, see
+    /// [TrustedProxies].
+    pub trusted_proxies: String,
+    /// Difficulty tiers, see [TierTable](crate::difficulty::TierTable).
+    pub difficulty_tiers: String,
+    /// Time in seconds the load is measured over.
+    pub load_window: u64,
+    /// Time in seconds after which the load level is lowered.
+    pub load_decay: u64,
+    /// Load thresholds, see [LoadThresholds](crate::difficulty::LoadThresholds).
+    pub load_thresholds: String,
+    /// Store backend, `memory`, `file` or `redis`.
+    pub store_backend: String,
+    /// Directory of the `file` store.
+    pub store_data_dir: String,
+    /// URL of the `redis` store.
+    pub store_redis_url: String,
+    /// Number of shards of the `memory` store.
+    pub store_shards: usize,
+    /// Maximum number of entries of local stores.
+    pub store_max_entries: usize,
+    /// Time in seconds between evicting expired store entries.
+    pub store_eviction_interval: u64,
+    /// Key puzzles are signed with.
+    pub secret_key: String,
+    /// File the key puzzles are signed with is read from instead, empty for none.
+    pub secret_key_file: String,
+    /// Whether a random key is generated and written to `secret_key_file` if it does not exist.
+    pub secret_key_generate: bool,
+    /// Id of the key puzzles are signed with.
+    pub secret_key_id: u32,
+    /// Keys only accepted for verification, as `<id>:<key>,...`.
+    pub secret_keys: String,
+    /// API key of the single tenant served without configured tenants.
+    pub api_key: String,
+    /// File the API key is read from instead, empty for none.
+    pub api_key_file: String,
+    /// Tenants served, see [TenantRegistry::from_settings].
+    pub tenants: String,
+}
+
+impl Default for Settings {
+    fn default() -> Self {
+        Settings {
+            mode: Mode::Dev,
+            bind_address: "0.0.0.0".to_string(),
+            bind_port: 8080,
+            access_ttl: 1800,
+            puzzle_ttl: 3600,
+            account_id: 1,
+            app_id: 1,
+            puzzle_version: 1,
+            puzzle_expiry: 12,
+            access_prefixes_v4: "32".to_string(),
+            access_prefixes_v6: "64".to_string(),
+            ip_binding: false,
+            ip_rules: String::new(),
+            ip_rules_file: String::new(),
+            trusted_proxies: String::new(),
+            difficulty_tiers: "21:45:149,11:45:141,5:51:130,0:51:122".to_string(),
+            load_window: 60,
+            load_decay: 300,
+            load_thresholds: String::new(),
+            store_backend: "memory".to_string(),
+            store_data_dir: "data".to_string(),
+            store_redis_url: "redis://127.0.0.1/".to_string(),
+            store_shards: 64,
+            store_max_entries: 1_000_000,
+            store_eviction_interval: 60,
+            secret_key: DEFAULT_SECRET_KEY.to_string(),
+            secret_key_file: String::new(),
+            secret_key_generate: false,
+            secret_key_id: 0,
+            secret_keys: String::new(),
+            api_key: DEFAULT_API_KEY.to_string(),
+            api_key_file: String::new(),
+            tenants: String::new(),
+        }
+    }
+}
+
+impl fmt::Debug for Settings {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Settings")
+            .field("mode", &self.mode)
+            .field("bind_address", &self.bind_address)
+            .field("bind_port", &self.bind_port)
+            .field("access_ttl", &self.access_ttl)
+            .field("puzzle_ttl", &self.puzzle_ttl)
+            .field("account_id", &self.account_id)
+            .field("app_id", &self.app_id)
+            .field("puzzle_version", &self.puzzle_version)
+            .field("puzzle_expiry", &self.puzzle_expiry)
+            .field("access_prefixes_v4", &self.access_prefixes_v4)
+            .field("access_prefixes_v6", &self.access_prefixes_v6)
+            .field("ip_binding", &self.ip_binding)
+            .field("ip_rules", &self.ip_rules)
+            .field("ip_rules_file", &self.ip_rules_file)
+            .field("trusted_proxies", &self.trusted_proxies)
+            .field("difficulty_tiers", &self.difficulty_tiers)
+            .field("load_window", &self.load_window)
+            .field("load_decay", &self.load_decay)
+            .field("load_thresholds", &self.load_thresholds)
+            .field("store_backend", &self.store_backend)
+            .field("store_data_dir", &self.store_data_dir)
+            .field("store_shards", &self.store_shards)
+            .field("store_max_entries", &self.store_max_entries)
+            .field("store_eviction_interval", &self.store_eviction_interval)
+            .field("secret_key_file", &self.secret_key_file)
+            .field("secret_key_generate", &self.secret_key_generate)
+            .field("secret_key_id", &self.secret_key_id)
+            .field("api_key_file", &self.api_key_file)
+            .finish_non_exhaustive()
+    }
+}
+
+impl Settings {
+    /// Reads the settings from the file given by `FCAPTCHA_CONFIG_FILE`, if any, and the
+    /// `FCAPTCHA_*` environment variables and validates them.
+    pub fn from_config() -> Result<Settings, ConfigError> {
+        Settings::load(config_file().as_deref())
+    }
+
+    /// Reads the settings from `file`, if given, and the `FCAPTCHA_*` environment variables and
+    /// validates them.
+    pub fn load(file: Option<&Path>) -> Result<Settings, ConfigError> {
+        Ok(load_settings(file)?.1)
+    }
+
+    /// Returns the names of the settings that differ between `self` and `other`, in alphabetical
+    /// order.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use fcaptcha::config::Settings;
+    ///
+    /// let settings = Settings::default();
+    /// let other = Settings {
+    ///     puzzle_ttl: 7200,
+    ///     ip_binding: true,
+    ///     ..Settings::default()
+    /// };
+    /// assert_eq!(settings.changes(&other), ["ip_binding", "puzzle_ttl"]);
+    /// ```
+    pub fn changes(&self, other: &Settings) -> Vec<String> {
+        let values = |settings: &Settings| {
+            Config::try_from(settings)
+                .and_then(|config| config.collect())
+                .unwrap_or_default()
+        };
+        let (values, other_values) = (values(self), values(other));
+        let mut changes: Vec<String> = values
+            .into_iter()
+            .filter(|(key, value)| other_values.get(key) != Some(value))
+            .map(|(key, _)| key)
+            .collect();
+        changes.sort();
+        changes
+    }
+
+    /// Checks that the settings are consistent and all lists and rules can be parsed. In
+    /// [Mode::Prod], the keys have to pass [Settings::check_keys] as well.
+    pub fn validate(&self) -> Result<(), ConfigError> {
+        let invalid = |key, reason: &str| Err(ConfigError::Invalid(key, reason.to_string()));
+        if self.bind_port == 0 {
+            return invalid("bind_port", "must not be 0");
+        }
+        if self.secret_key.is_empty() {
+            return invalid("secret_key", "must not be empty");
+        }
+        if self.api_key.is_empty() {
+            return invalid("api_key", "must not be empty");
+        }
+        if self.secret_key_generate && self.secret_key_file.is_empty() {
+            return invalid("secret_key_generate", "requires secret_key_file");
+        }
+        if self.access_ttl == 0 {
+            return invalid("access_ttl", "must not be 0");
+        }
+        let expiry_secs = u64::from(self.puzzle_expiry) * 300;
+        if self.puzzle_ttl < expiry_secs {
+            return invalid(
+                "puzzle_ttl",
+                &format!(
+                    "must be at least the puzzle expiry of {} s, so that used puzzles are \
+                     remembered until they expire",
+                    expiry_secs
+                ),
+            );
+        }
+        if self.store_shards == 0 {
+            return invalid("store_shards", "must not be 0");
+        }
+        if self.store_max_entries == 0 {
+            return invalid("store_max_entries", "must not be 0");
+        }
+        if self.store_eviction_interval == 0 {
+            return invalid("store_eviction_interval", "must not be 0");
+        }
+        match self.store_backend.as_str() {
+            "memory" | "file" => {}
+            #[cfg(feature = "redis")]
+            "redis" => {}
+            backend => return invalid("store_backend", &format!("unknown backend {:?}", backend)),
+        }
+
+        if self.load_window == 0 {
+            return invalid("load_window", "must not be 0");
+        }
+
+        TrustedProxies::from_settings(self)
+            .map_err(|err| ConfigError::Invalid("trusted_proxies", err.to_string()))?;
+        // Building the tenants parses all lists and rules, reads the IP rule file and the keys.
+        let options = PuzzleOptions::from_settings(self)?;
+        TenantRegistry::from_settings(self, &options).map_err(|err| match err {
+            TenantError::Config(err) => err,
+            TenantError::Keyring(err) => ConfigError::Invalid("secret_keys", err.to_string()),
+            err => ConfigError::Invalid("tenants", err.to_string()),
+        })?;
+        if self.mode == Mode::Prod {
+            self.check_keys()?;
+        }
+        Ok(())
+    }
+
+    /// Checks that the keys in use are neither the defaults nor shorter than
+    /// [MIN_SECRET_KEY_LEN] or, for API keys and tenant secrets, [MIN_API_KEY_LEN]. Without
+    /// tenants, these are `secret_key`, `secret_keys` and `api_key`, otherwise the keys of the
+    /// tenants.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use fcaptcha::config::{Mode, Settings};
+    ///
+    /// let settings = Settings {
+    ///     mode: Mode::Prod,
+    ///     ..Settings::default()
+    /// };
+    /// assert!(settings.validate().is_err());
+    /// let settings = Settings {
+    ///     secret_key: "cbf5b3bd0e7fd9b3f3b2d38e1f1f8c1a".to_string(),
+    ///     api_key: "8d4a6c0f5b7e2d91".to_string(),
+    ///     ..settings
+    /// };
+    /// assert!(settings.validate().is_ok());
+    /// ```
+    pub fn check_keys(&self) -> Result<(), ConfigError> {
+        let check = |key, value: &str, default, min_len| {
+            if value == default {
+                Err(ConfigError::Invalid(
+                    key,
+                    "must not be the default".to_string(),
+                ))
+            } else if value.len() < min_len {
+                Err(ConfigError::Invalid(
+                    key,
+                    format!("must be at least {} characters long", min_len),
+                ))
+            } else {
+                Ok(())
+            }
+        };
+        let entries: Vec<&str> = self
+            .tenants
+            .split(',')
+            .filter(|e| !e.trim().is_empty())
+            .collect();
+        if entries.is_empty() {
+            check(
+                "secret_key",
+                &self.secret_key,
+                DEFAULT_SECRET_KEY,
+                MIN_SECRET_KEY_LEN,
+            )?;
+            check("api_key", &self.api_key, DEFAULT_API_KEY, MIN_API_KEY_LEN)?;
+            let keys = parse_secret_keys(self.secret_key_id, &self.secret_keys)
+                .map_err(|err| ConfigError::Invalid("secret_keys", err.to_string()))?;
+            for (_, key) in keys {
+                check("secret_keys", key, DEFAULT_SECRET_KEY, MIN_SECRET_KEY_LEN)?;
+            }
+        }
+        for entry in entries {
+            let fields: Vec<&str> = entry.trim().split(';').collect();
+            if let [_, secret, signing_key, ..] = fields[..] {
+                check("tenants", secret, DEFAULT_API_KEY, MIN_API_KEY_LEN)?;
+                check(
+                    "tenants",
+                    signing_key,
+                    DEFAULT_SECRET_KEY,
+                    MIN_SECRET_KEY_LEN,
+                )?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Returns the configuration file given by `FCAPTCHA_CONFIG_FILE`, if any.
+pub fn config_file() -> Option<PathBuf> {
+    env::var_os("FCAPTCHA_CONFIG_FILE")
+        .filter(|file| !file.is_empty())
+        .map(PathBuf::from)
+}
+
+/// Returns the process-wide configuration read by [get], failing if it is malformed or invalid.
+fn loaded() -> Result<Arc<LoadedConfig>, ConfigError> {
+    CONFIG
+        .read()
+        .unwrap_or_else(PoisonError::into_inner)
+        .clone()
+}
+
+/// Returns the validated settings of the process-wide configuration read by [get].
+pub(crate) fn settings() -> Result<Arc<Settings>, ConfigError> {
+    Ok(Arc::clone(&loaded()?.settings))
+}
+
+/// Reads and validates the configuration from the file given by `FCAPTCHA_CONFIG_FILE`, if any,
+/// and the `FCAPTCHA_*` environment variables, without affecting the process-wide configuration.
+pub(crate) fn load_current() -> Result<LoadedConfig, ConfigError> {
+    let (config, settings) = load_settings(config_file().as_deref())?;
+    Ok(LoadedConfig {
+        config,
+        settings: Arc::new(settings),
+    })
+}
+
+/// Makes `loaded` the process-wide configuration read by [get].
+pub(crate) fn commit(loaded: LoadedConfig) {
+    *CONFIG.write().unwrap_or_else(PoisonError::into_inner) = Ok(Arc::new(loaded));
+}
+
+/// Builds the configuration like [load] and the validated settings from it.
+fn load_settings(file: Option<&Path>) -> Result<(Config, Settings), ConfigError> {
+    let config = load(file)?;
+    let settings: Settings = config.clone().try_deserialize()?;
+    settings.validate()?;
+    Ok((config, settings))
+}
+
+/// Builds the configuration from the defaults of [Settings], `file` and the environment, with the
+/// secrets read from files.
+fn load(file: Option<&Path>) -> Result<Config, ConfigError> {
+    let mut builder = Config::builder().add_source(Config::try_from(&Settings::default())?);
+    if let Some(file) = file {
+        builder = builder.add_source(File::from(file));
+    }
+    let config = builder
+        .add_source(Environment::with_prefix("FCAPTCHA").prefix_separator("_"))
+        .build()?;
+    let credentials = env::var_os("CREDENTIALS_DIRECTORY")
+        .filter(|dir| !dir.is_empty())
+        .map(PathBuf::from);
+    load_secrets(config, credentials.as_deref())
+}
+
+/// Replaces `secret_key` and `api_key` by the content of `secret_key_file` and `api_key_file` or
+/// else of the systemd credentials of the same names in `credentials`, generating the secret key
+/// first if requested.
+fn load_secrets(config: Config, credentials: Option<&Path>) -> Result<Config, ConfigError> {
+    let secret_key_file = config.get::<String>("secret_key_file")?;
+    if config.get::<bool>("secret_key_generate")? && !secret_key_file.is_empty() {
+        generate_secret_key(Path::new(&secret_key_file))
+            .map_err(|err| ConfigError::Invalid("secret_key_file", err.to_string()))?;
+    }
+
+    let mut builder = Config::builder().add_source(config.clone());
+    for (key, file_key) in [
+        ("secret_key", "secret_key_file"),
+        ("api_key", "api_key_file"),
+    ] {
+        let file = match config.get::<String>(file_key)? {
+            file if !file.is_empty() => PathBuf::from(file),
+            _ => match credentials.map(|dir| dir.join(key)) {
+                Some(file) if file.is_file() => file,
+                _ => continue,
+            },
+        };
+        let secret = fs::read_to_string(&file).map_err(|err| {
+            ConfigError::Invalid(file_key, format!("{}: {}", file.display(), err))
+        })?;
+        builder = builder.set_override(key, secret.trim_end())?;
+    }
+    Ok(builder.build()?)
+}
+
+/// Writes a random key to `file` unless it exists, readable by the owner only.
+fn generate_secret_key(file: &Path) -> io::Result<()> {
+    let mut options = OpenOptions::new();
+    options.write(true).create_new(true);
+    #[cfg(unix)]
+    options.mode(0o600);
+    match options.open(file) {
+        Ok(mut handle) => {
+            handle.write_all(hex::encode(rand::random::<[u8; 32]>()).as_bytes())?;
+            info!("Generated secret key in {}", file.display());
+            Ok(())
+        }
+        Err(err) if err.kind() == io::ErrorKind::AlreadyExists => Ok(()),
+        Err(err) => Err(err),
+    }
+}
+
+/// Get a configuration element. Fails if the configuration or the element is malformed or the
+/// configuration is invalid, see [Settings::validate].
+pub fn get<'a, T: serde::Deserialize<'a>>(key: &str) -> Result<T, ConfigError> {
+    Ok(loaded()?.config.get::<T>(&key.to_lowercase())?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn load_file(extension: &str, content: &str) -> Result<Settings, ConfigError> {
+        let mut file = tempfile::Builder::new()
+            .suffix(extension)
+            .tempfile()
+            .unwrap();
+        file.write_all(content.as_bytes()).unwrap();
+        Settings::load(Some(file.path()))
+    }
+
+    #[test]
+    fn test_load_file_formats() -> Result<(), ConfigError> {
+        let settings = load_file(".toml", "puzzle_ttl = 7200\nip_binding = true\n")?;
+        assert_eq!((settings.puzzle_ttl, settings.ip_binding), (7200, true));
+        let settings = load_file(".yaml", "puzzle_ttl: 7200\nstore_backend: file\n")?;
+        assert_eq!(settings.store_backend, "file");
+        let settings = load_file(".json", r#"{"app_id": 2, "difficulty_tiers": "0:1:10"}"#)?;
+        assert_eq!((settings.app_id, settings.puzzle_ttl), (2, 3600));
+        assert_eq!(settings.difficulty_tiers, "0:1:10");
+        Ok(())
+    }
+
+    #[test]
+    fn test_get() {
+        assert_eq!(
+            settings().map(|settings| settings.puzzle_ttl),
+            get("PUZZLE_TTL")
+        );
+        assert!(matches!(
+            get::<u64>("tenants"),
+            Err(ConfigError::Malformed(_))
+        ));
+    }
+
+    #[test]
+    fn test_load_errors() {
+        assert!(matches!(
+            load_file(".toml", "bind_port = \"http\"\n"),
+            Err(ConfigError::Malformed(_))
+        ));
+        assert!(matches!(
+            load_file(".toml", "puzzle_ttl = 60\n"),
+            Err(ConfigError::Invalid("puzzle_ttl", _))
+        ));
+        assert!(matches!(
+            load_file(".toml", "difficulty_tiers = \"0:1\"\n"),
+            Err(ConfigError::Invalid("difficulty_tiers", _))
+        ));
+        assert!(matches!(
+            Settings::load(Some(Path::new("does-not-exist.toml"))),
+            Err(ConfigError::Malformed(_))
+        ));
+    }
+
+    #[test]
+    fn test_load_secrets() -> Result<(), ConfigError> {
+        let dir = tempfile::tempdir().unwrap();
+        let secret_key_file = dir.path().join("key");
+        fs::write(&secret_key_file, "SECRET-FROM-FILE\n").unwrap();
+        fs::write(dir.path().join("api_key"), "API-KEY-FROM-CREDENTIAL").unwrap();
+        let config = Config::builder()
+            .add_source(Config::try_from(&Settings::default())?)
+            .set_override("secret_key_file", secret_key_file.to_str())?
+            .build()?;
+
+        let settings: Settings = load_secrets(config.clone(), None)?.try_deserialize()?;
+        assert_eq!(settings.secret_key, "SECRET-FROM-FILE");
+        assert_eq!(settings.api_key, DEFAULT_API_KEY);
+        let settings: Settings = load_secrets(config, Some(dir.path()))?.try_deserialize()?;
+        assert_eq!(settings.api_key, "API-KEY-FROM-CREDENTIAL");
+
+        let config = Config::builder()
+            .add_source(Config::try_from(&Settings::default())?)
+            .set_override("api_key_file", "does-not-exist")?
+            .build()?;
+        assert!(matches!(
+            load_secrets(config, None),
+            Err(ConfigError::Invalid("api_key_file", _))
+        ));
+        Ok(())
+    }
+
+    #[test]
+    fn test_generate_secret_key() -> Result<(), ConfigError> {
+        let dir = tempfile::tempdir().unwrap();
+        let secret_key_file = dir.path().join("key");
+        let config = Config::builder()
+            .add_source(Config::try_from(&Settings::default())?)
+            .set_override("secret_key_file", secret_key_file.to_str())?
+            .set_override("secret_key_generate", true)?
+            .build()?;
+
+        let settings: Settings = load_secrets(config.clone(), None)?.try_deserialize()?;
+        assert_eq!(settings.secret_key.len(), 64);
+        assert_eq!(
+            fs::read_to_string(&secret_key_file).unwrap(),
+            settings.secret_key
+        );
+        // The key is only generated on the first run.
+        let reloaded: Settings = load_secrets(config, None)?.try_deserialize()?;
+        assert_eq!(reloaded.secret_key, settings.secret_key);
+        assert!(matches!(
+            Settings {
+                secret_key_generate: true,
+                ..Settings::default()
+            }
+            .validate(),
+            Err(ConfigError::Invalid("secret_key_generate", _))
+        ));
+        Ok(())
+    }
+
+    #[test]
+    fn test_check_keys() {
+        let secret_key = "0123456789abcdef0123456789abcdef";
+        let prod = Settings {
+            mode: Mode::Prod,
+            secret_key: secret_key.to_string(),
+            api_key: "0123456789abcdef".to_string(),
+            ..Settings::default()
+        };
+        assert_eq!(prod.validate(), Ok(()));
+        let validate = |settings: Settings| settings.validate().err();
+        assert!(matches!(
+            validate(Settings {
+                api_key: DEFAULT_API_KEY.to_string(),
+                ..prod.clone()
+            }),
+            Some(ConfigError::Invalid("api_key", _))
+        ));
+        assert!(matches!(
+            validate(Settings {
+                secret_key: "SHORT-SECRET-KEY".to_string(),
+                ..prod.clone()
+            }),
+            Some(ConfigError::Invalid("secret_key", _))
+        ));
+        assert!(matches!(
+            validate(Settings {
+                secret_keys: "1:OLD-KEY".to_string(),
+                ..prod.clone()
+            }),
+            Some(ConfigError::Invalid("secret_keys", _))
+        ));
+        // With tenants, only their keys are used.
+        assert!(matches!(
+            validate(Settings {
+                secret_key: DEFAULT_SECRET_KEY.to_string(),
+                tenants: format!("A;SECRET-A;{};1;1;", secret_key),
+                ..prod.clone()
+            }),
+            Some(ConfigError::Invalid("tenants", _))
+        ));
+        assert_eq!(
+            validate(Settings {
+                secret_key: DEFAULT_SECRET_KEY.to_string(),
+                tenants: format!("A;0123456789abcdef;{};1;1;", secret_key),
+                ..prod.clone()
+            }),
+            None
+        );
+        // Development mode accepts default keys.
+        assert_eq!(validate(Settings::default()), None);
+        assert!(Settings::default().check_keys().is_err());
+    }
+
+    #[test]
+    fn test_validate() {
+        let validate = |settings: Settings| settings.validate().err();
+        assert_eq!(validate(Settings::default()), None);
+        assert!(matches!(
+            validate(Settings {
+                bind_port: 0,
+                ..Settings::default()
+            }),
+            Some(ConfigError::Invalid("bind_port", _))
+        ));
+        assert!(matches!(
+            validate(Settings {
+                secret_key: String::new(),
+                ..Settings::default()
+            }),
+            Some(ConfigError::Invalid("secret_key", _))
+        ));
+        // Without expiry, no puzzle TTL covers the lifetime of puzzles, so none is required.
+        assert_eq!(
+            validate(Settings {
+                puzzle_expiry: 0,
+                puzzle_ttl: 0,
+                ..Settings::default()
+            }),
+            None
+        );
+        assert!(matches!(
+            validate(Settings {
+                ip_rules: "deny 192.0.2.0/33".to_string(),
+                ..Settings::default()
+            }),
+            Some(ConfigError::Invalid("ip_rules", _))
+        ));
+        assert!(matches!(
+            validate(Settings {
+                trusted_proxies: "10.0.0.0/8 proxy".to_string(),
+                ..Settings::default()
+            }),
+            Some(ConfigError::Invalid("trusted_proxies", _))
+        ));
+        assert!(matches!(
+            validate(Settings {
+                secret_keys: "0:OTHER-KEY".to_string(),
+                ..Settings::default()
+            }),
+            Some(ConfigError::Invalid("secret_keys", _))
+        ));
+        assert!(matches!(
+            validate(Settings {
+                tenants: "A;SECRET-A;KEY-A;1".to_string(),
+                ..Settings::default()
+            }),
+            Some(ConfigError::Invalid("tenants", _))
+        ));
+        assert!(matches!(
+            validate(Settings {
+                tenants: "A;SECRET-A;KEY-A;1;1;,A;SECRET-B;KEY-B;1;2;".to_string(),
+                ..Settings::default()
+            }),
+            Some(ConfigError::Invalid("tenants", _))
+        ));
+        assert!(matches!(
+            validate(Settings {
+                ip_rules_file: "does-not-exist".to_string(),
+                ..Settings::default()
+            }),
+            Some(ConfigError::Invalid("ip_rules_file", _))
+        ));
+    }
 }
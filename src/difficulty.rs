@@ -0,0 +1,248 @@
+use crate::config::Settings;
+use crate::store::Access;
+use displaydoc::Display;
+use std::fmt;
+use std::str::FromStr;
+use std::sync::Arc;
+use thiserror::Error;
+
+pub use self::adaptive::{AdaptivePolicy, LoadLevel, LoadMonitor, LoadThreshold, LoadThresholds};
+
+mod adaptive;
+
+/// Describes an error that occurred during setting up a difficulty policy.
+#[derive(Display, Error, Debug, PartialEq)]
+pub enum DifficultyError {
+    /// Difficulty configuration malformed: {0}
+    ConfigMalformed(String),
+}
+
+/// Describes how costly a puzzle is to solve.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Scaling {
+    /// Number of solutions that have to be found.
+    pub solution_count: u8,
+    /// Difficulty of finding a single solution.
+    pub difficulty: u8,
+}
+
+impl Scaling {
+    /// Creates a scaling.
+    pub fn new(solution_count: u8, difficulty: u8) -> Scaling {
+        Scaling {
+            solution_count,
+            difficulty,
+        }
+    }
+}
+
+/// Describes the client a puzzle is built for.
+#[derive(Clone, Debug, PartialEq)]
+pub struct PuzzleRequest<'a> {
+    /// IP address of the client.
+    pub ip_address: &'a str,
+    /// Accesses of the client including the current one.
+    pub access: &'a Access,
+    /// Time of the request, in seconds since the Unix epoch.
+    pub timestamp: u64,
+}
+
+/// Decides how costly the puzzle built for a request is.
+pub trait DifficultyPolicy: Send + Sync + fmt::Debug {
+    /// Returns the scaling of the puzzle built for `request`.
+    fn scaling(&self, request: &PuzzleRequest<'_>) -> Scaling;
+}
+
+impl<P: DifficultyPolicy + ?Sized> DifficultyPolicy for Arc<P> {
+    fn scaling(&self, request: &PuzzleRequest<'_>) -> Scaling {
+        (**self).scaling(request)
+    }
+}
+
+/// A tier of a [TierTable].
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Tier {
+    /// Number of accesses from which on the tier applies.
+    pub min_access_count: u64,
+    /// Scaling of the puzzles built in the tier.
+    pub scaling: Scaling,
+}
+
+/// A policy scaling the puzzle with the number of accesses of the client. The tier with the highest
+/// minimum access count not above the access count applies, the lowest tier if there is none.
+///
+/// # Examples
+///
+/// ```
+/// use fcaptcha::difficulty::{Scaling, TierTable};
+///
+/// let tiers: TierTable = "0:51:122,5:51:130".parse().unwrap();
+/// assert_eq!(tiers.get(4), Scaling::new(51, 122));
+/// assert_eq!(tiers.get(5), Scaling::new(51, 130));
+/// ```
+#[derive(Clone, Debug, PartialEq)]
+pub struct TierTable {
+    /// Tiers sorted by descending minimum access count.
+    tiers: Vec<Tier>,
+}
+
+impl Default for TierTable {
+    fn default() -> Self {
+        TierTable::new(vec![
+            Tier {
+                min_access_count: 21,
+                scaling: Scaling::new(45, 149),
+            },
+            Tier {
+                min_access_count: 11,
+                scaling: Scaling::new(45, 141),
+            },
+            Tier {
+                min_access_count: 5,
+                scaling: Scaling::new(51, 130),
+            },
+            Tier {
+                min_access_count: 0,
+                scaling: Scaling::new(51, 122),
+            },
+        ])
+        .unwrap()
+    }
+}
+
+impl TierTable {
+    /// Creates a table from `tiers` in any order. Fails if there are no tiers, multiple tiers
+    /// with the same minimum access count or tiers without solutions, which would accept any
+    /// solution and thereby disable the captcha.
+    pub fn new(mut tiers: Vec<Tier>) -> Result<TierTable, DifficultyError> {
+        tiers.sort_by_key(|tier| std::cmp::Reverse(tier.min_access_count));
+        if tiers.is_empty() {
+            return Err(DifficultyError::ConfigMalformed("no tiers".to_string()));
+        }
+        if let Some(tier) = tiers.iter().find(|tier| tier.scaling.solution_count == 0) {
+            return Err(DifficultyError::ConfigMalformed(format!(
+                "no solutions from {} accesses",
+                tier.min_access_count
+            )));
+        }
+        if let Some(tier) = tiers
+            .windows(2)
+            .find(|pair| pair[0].min_access_count == pair[1].min_access_count)
+        {
+            return Err(DifficultyError::ConfigMalformed(format!(
+                "multiple tiers from {} accesses",
+                tier[0].min_access_count
+            )));
+        }
+        Ok(TierTable { tiers })
+    }
+
+    /// Reads the table from `difficulty_tiers` of `settings` in the form parsed by
+    /// [TierTable::from_str].
+    pub fn from_settings(settings: &Settings) -> Result<TierTable, DifficultyError> {
+        settings.difficulty_tiers.parse()
+    }
+
+    /// Returns the tiers sorted by descending minimum access count.
+    pub fn tiers(&self) -> &[Tier] {
+        &self.tiers
+    }
+
+    /// Returns the scaling for a client with `access_count` accesses.
+    pub fn get(&self, access_count: u64) -> Scaling {
+        self.tiers
+            .iter()
+            .find(|tier| access_count >= tier.min_access_count)
+            .unwrap_or(&self.tiers[self.tiers.len() - 1])
+            .scaling
+    }
+}
+
+impl FromStr for TierTable {
+    type Err = DifficultyError;
+
+    /// Parses tiers in the form `<min access count>:<solution count>:<difficulty>`, separated by
+    /// commas or whitespace.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let tiers = s
+            .split(|c: char| c == ',' || c.is_whitespace())
+            .filter(|entry| !entry.is_empty())
+            .map(|entry| {
+                let malformed = || DifficultyError::ConfigMalformed(format!("{:?}", entry));
+                let mut fields = entry.split(':');
+                let mut next = || fields.next().ok_or_else(malformed);
+                let tier = Tier {
+                    min_access_count: next()?.parse().map_err(|_| malformed())?,
+                    scaling: Scaling::new(
+                        next()?.parse().map_err(|_| malformed())?,
+                        next()?.parse().map_err(|_| malformed())?,
+                    ),
+                };
+                match fields.next() {
+                    Some(_) => Err(malformed()),
+                    None => Ok(tier),
+                }
+            })
+            .collect::<Result<_, _>>()?;
+        TierTable::new(tiers)
+    }
+}
+
+impl DifficultyPolicy for TierTable {
+    fn scaling(&self, request: &PuzzleRequest<'_>) -> Scaling {
+        self.get(request.access.count)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_default_tiers() {
+        let tiers = TierTable::default();
+        assert_eq!(tiers.get(1), Scaling::new(51, 122));
+        assert_eq!(tiers.get(4), Scaling::new(51, 122));
+        assert_eq!(tiers.get(5), Scaling::new(51, 130));
+        assert_eq!(tiers.get(10), Scaling::new(51, 130));
+        assert_eq!(tiers.get(11), Scaling::new(45, 141));
+        assert_eq!(tiers.get(21), Scaling::new(45, 149));
+        assert_eq!(tiers.get(u64::MAX), Scaling::new(45, 149));
+        assert_eq!(
+            "21:45:149, 11:45:141 5:51:130,0:51:122".parse::<TierTable>(),
+            Ok(tiers)
+        );
+    }
+
+    #[test]
+    fn test_below_lowest_tier() -> Result<(), DifficultyError> {
+        let tiers: TierTable = "10:40:140,3:20:120".parse()?;
+        assert_eq!(tiers.get(0), Scaling::new(20, 120));
+        assert_eq!(tiers.get(3), Scaling::new(20, 120));
+        assert_eq!(tiers.get(10), Scaling::new(40, 140));
+        Ok(())
+    }
+
+    #[test]
+    fn test_parse_errors() {
+        for input in [
+            "",
+            "1:2",
+            "1:2:3:4",
+            "1:2:256",
+            "a:2:3",
+            "1:2:3,1:4:5",
+            "0:0:122",
+            "0:1:10,5:0:10",
+        ] {
+            assert!(
+                matches!(
+                    input.parse::<TierTable>(),
+                    Err(DifficultyError::ConfigMalformed(_))
+                ),
+                "{:?}",
+                input
+            );
+        }
+    }
+}
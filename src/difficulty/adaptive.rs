@@ -0,0 +1,321 @@
+use super::{DifficultyError, DifficultyPolicy, PuzzleRequest, Scaling};
+use crate::config::Settings;
+use std::collections::VecDeque;
+use std::str::FromStr;
+use std::sync::{Arc, Mutex, PoisonError};
+
+/// A level of a [LoadMonitor], entered when the request rate reaches `min_rate`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct LoadThreshold {
+    /// Number of requests within the window from which on the level applies.
+    pub min_rate: u64,
+    /// Increase of the difficulty of all puzzles on the level.
+    pub difficulty_increase: u8,
+}
+
+/// The load observed by a [LoadMonitor].
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct LoadLevel {
+    /// The current level, `0` if no threshold is reached.
+    pub level: usize,
+    /// Number of requests within the window.
+    pub rate: u64,
+    /// Increase of the difficulty of all puzzles on the current level.
+    pub difficulty_increase: u8,
+}
+
+#[derive(Debug, Default)]
+struct LoadState {
+    /// Number of requests per second, oldest first.
+    buckets: VecDeque<(u64, u64)>,
+    rate: u64,
+    level: usize,
+    /// Last time the rate was at or above the threshold of the current level.
+    level_confirmed: u64,
+}
+
+/// Tracks the rate of puzzle requests within a sliding window and derives a load level from it.
+/// Levels are raised as soon as the rate reaches their threshold and lowered one at a time after
+/// the rate stayed below the threshold of the current level for the decay time.
+///
+/// # Examples
+///
+/// ```
+/// use fcaptcha::difficulty::LoadMonitor;
+///
+/// let monitor = LoadMonitor::new(60, 300, "2:8,4:16".parse().unwrap()).unwrap();
+/// monitor.record(1000);
+/// assert_eq!(monitor.record(1001).level, 1);
+/// monitor.record(1002);
+/// assert_eq!(monitor.record(1003).difficulty_increase, 16);
+/// assert_eq!(monitor.level(1063).level, 2);
+/// assert_eq!(monitor.level(1303).level, 1);
+/// ```
+#[derive(Debug)]
+pub struct LoadMonitor {
+    window_secs: u64,
+    decay_secs: u64,
+    thresholds: Vec<LoadThreshold>,
+    state: Mutex<LoadState>,
+}
+
+impl LoadMonitor {
+    /// Creates a monitor counting requests within `window_secs` and lowering levels after
+    /// `decay_secs`. Fails if the window is empty or the thresholds do not strictly increase.
+    pub fn new(
+        window_secs: u64,
+        decay_secs: u64,
+        thresholds: LoadThresholds,
+    ) -> Result<LoadMonitor, DifficultyError> {
+        if window_secs == 0 {
+            return Err(DifficultyError::ConfigMalformed(
+                "empty load window".to_string(),
+            ));
+        }
+        Ok(LoadMonitor {
+            window_secs,
+            decay_secs,
+            thresholds: thresholds.0,
+            state: Mutex::new(LoadState::default()),
+        })
+    }
+
+    /// Reads the monitor from `load_window`, `load_decay` and `load_thresholds` of `settings`,
+    /// the latter given as `<min rate>:<difficulty increase>,...`. Without thresholds the load is
+    /// not tracked.
+    pub fn from_settings(settings: &Settings) -> Result<LoadMonitor, DifficultyError> {
+        LoadMonitor::new(
+            settings.load_window,
+            settings.load_decay,
+            settings.load_thresholds.parse()?,
+        )
+    }
+
+    /// Returns `true` if there are thresholds, i.e. the level can change.
+    pub fn is_enabled(&self) -> bool {
+        !self.thresholds.is_empty()
+    }
+
+    /// Records a request at `timestamp` and returns the resulting level.
+    pub fn record(&self, timestamp: u64) -> LoadLevel {
+        self.update(timestamp, 1)
+    }
+
+    /// Returns the level at `timestamp` without recording a request.
+    pub fn level(&self, timestamp: u64) -> LoadLevel {
+        self.update(timestamp, 0)
+    }
+
+    fn update(&self, timestamp: u64, requests: u64) -> LoadLevel {
+        // The state stays consistent even if a holder of the lock panicked.
+        let mut state = self.state.lock().unwrap_or_else(PoisonError::into_inner);
+
+        let window_start = timestamp.saturating_sub(self.window_secs);
+        while let Some(&(second, count)) = state.buckets.front() {
+            if second > window_start {
+                break;
+            }
+            state.buckets.pop_front();
+            state.rate -= count;
+        }
+        if requests > 0 {
+            match state.buckets.back_mut() {
+                Some((second, count)) if *second >= timestamp => *count += requests,
+                _ => state.buckets.push_back((timestamp, requests)),
+            }
+            state.rate += requests;
+        }
+
+        let target = self
+            .thresholds
+            .iter()
+            .take_while(|threshold| state.rate >= threshold.min_rate)
+            .count();
+        if target >= state.level {
+            if target > state.level {
+                info!("Load level raised to {:?} at rate {:?}", target, state.rate);
+            }
+            state.level = target;
+            state.level_confirmed = timestamp;
+        } else if timestamp >= state.level_confirmed.saturating_add(self.decay_secs) {
+            state.level -= 1;
+            state.level_confirmed = timestamp;
+            info!(
+                "Load level lowered to {:?} at rate {:?}",
+                state.level, state.rate
+            );
+        }
+
+        LoadLevel {
+            level: state.level,
+            rate: state.rate,
+            difficulty_increase: match state.level {
+                0 => 0,
+                level => self.thresholds[level - 1].difficulty_increase,
+            },
+        }
+    }
+}
+
+/// Strictly increasing thresholds of a [LoadMonitor], parsed from
+/// `<min rate>:<difficulty increase>` separated by commas.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct LoadThresholds(Vec<LoadThreshold>);
+
+impl LoadThresholds {
+    /// Creates thresholds, failing if they do not strictly increase.
+    pub fn new(thresholds: Vec<LoadThreshold>) -> Result<LoadThresholds, DifficultyError> {
+        if let Some(pair) = thresholds
+            .windows(2)
+            .find(|pair| pair[0].min_rate >= pair[1].min_rate)
+        {
+            return Err(DifficultyError::ConfigMalformed(format!(
+                "load thresholds do not increase: {:?}",
+                pair
+            )));
+        }
+        Ok(LoadThresholds(thresholds))
+    }
+}
+
+impl FromStr for LoadThresholds {
+    type Err = DifficultyError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let thresholds = s
+            .split(',')
+            .map(str::trim)
+            .filter(|entry| !entry.is_empty())
+            .map(|entry| {
+                let malformed = || DifficultyError::ConfigMalformed(format!("{:?}", entry));
+                let (min_rate, difficulty_increase) =
+                    entry.split_once(':').ok_or_else(malformed)?;
+                Ok(LoadThreshold {
+                    min_rate: min_rate.parse().map_err(|_| malformed())?,
+                    difficulty_increase: difficulty_increase.parse().map_err(|_| malformed())?,
+                })
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+        LoadThresholds::new(thresholds)
+    }
+}
+
+/// A policy raising the difficulty of another policy by the current level of a [LoadMonitor].
+/// Every puzzle built is recorded as a request.
+#[derive(Debug)]
+pub struct AdaptivePolicy<P> {
+    inner: P,
+    monitor: Arc<LoadMonitor>,
+}
+
+impl<P: DifficultyPolicy> AdaptivePolicy<P> {
+    /// Creates a policy raising the difficulty of `inner` by the level of `monitor`.
+    pub fn new(inner: P, monitor: Arc<LoadMonitor>) -> AdaptivePolicy<P> {
+        AdaptivePolicy { inner, monitor }
+    }
+}
+
+impl<P: DifficultyPolicy> DifficultyPolicy for AdaptivePolicy<P> {
+    fn scaling(&self, request: &PuzzleRequest<'_>) -> Scaling {
+        let scaling = self.inner.scaling(request);
+        let load = self.monitor.record(request.timestamp);
+        Scaling::new(
+            scaling.solution_count,
+            scaling.difficulty.saturating_add(load.difficulty_increase),
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::difficulty::TierTable;
+    use crate::store::Access;
+
+    fn monitor() -> LoadMonitor {
+        LoadMonitor::new(10, 30, "3:5,6:10".parse().unwrap()).unwrap()
+    }
+
+    #[test]
+    fn test_sliding_window() {
+        let monitor = monitor();
+        for timestamp in 1000..1005 {
+            monitor.record(timestamp);
+        }
+        assert_eq!(monitor.level(1005).rate, 5);
+        assert_eq!(monitor.level(1009).rate, 5);
+        assert_eq!(monitor.level(1010).rate, 4);
+        assert_eq!(monitor.level(1013).rate, 1);
+        assert_eq!(monitor.level(1014).rate, 0);
+    }
+
+    #[test]
+    fn test_raise_and_decay() {
+        let monitor = monitor();
+        assert_eq!(monitor.record(1000).level, 0);
+        monitor.record(1000);
+        assert_eq!(monitor.record(1000).level, 1);
+        for _ in 0..3 {
+            monitor.record(1001);
+        }
+        assert_eq!(
+            monitor.level(1001),
+            LoadLevel {
+                level: 2,
+                rate: 6,
+                difficulty_increase: 10
+            }
+        );
+
+        // The rate drops to zero after the window, levels decay one at a time.
+        assert_eq!(monitor.level(1020).level, 2);
+        assert_eq!(monitor.level(1031).level, 1);
+        assert_eq!(monitor.level(1060).level, 1);
+        assert_eq!(monitor.level(1061).level, 0);
+    }
+
+    #[test]
+    fn test_renewed_load_postpones_decay() {
+        let monitor = monitor();
+        for _ in 0..3 {
+            monitor.record(1000);
+        }
+        for _ in 0..3 {
+            monitor.record(1025);
+        }
+        assert_eq!(monitor.level(1050).level, 1);
+        assert_eq!(monitor.level(1055).level, 0);
+    }
+
+    #[test]
+    fn test_adaptive_policy() {
+        let monitor = Arc::new(monitor());
+        let policy = AdaptivePolicy::new(TierTable::default(), Arc::clone(&monitor));
+        let access = Access {
+            count: 1,
+            last_access: 1000,
+        };
+        let difficulties: Vec<u8> = (0..7)
+            .map(|_| {
+                policy
+                    .scaling(&PuzzleRequest {
+                        ip_address: "127.0.0.1",
+                        access: &access,
+                        timestamp: 1000,
+                    })
+                    .difficulty
+            })
+            .collect();
+        assert_eq!(difficulties, [122, 122, 127, 127, 127, 132, 132]);
+        assert_eq!(monitor.level(1000).level, 2);
+    }
+
+    #[test]
+    fn test_thresholds_parse() {
+        assert_eq!("".parse(), Ok(LoadThresholds::default()));
+        assert!("3:5,3:10".parse::<LoadThresholds>().is_err());
+        assert!("3:5,6".parse::<LoadThresholds>().is_err());
+        assert!("3:256".parse::<LoadThresholds>().is_err());
+        assert!(LoadMonitor::new(0, 30, LoadThresholds::default()).is_err());
+    }
+}
@@ -0,0 +1,611 @@
+use crate::build_puzzle::{build_puzzle_with, BuildPuzzleError, PuzzleOptions};
+use crate::clock::{Clock, SystemClock};
+use crate::config::{self, ConfigError, Settings};
+use crate::difficulty::{LoadLevel, LoadMonitor};
+use crate::ip_rules::TrustedProxies;
+use crate::keyring::Keyring;
+use crate::store::{self, CaptchaStore, MemoryStore, StoreError};
+use crate::tenant::{Tenant, TenantError, TenantRegistry};
+use crate::verify_puzzle_result::{verify_puzzle_result_with, Check, Failure, Verdict};
+use displaydoc::Display;
+use std::fmt;
+use std::sync::{Arc, PoisonError, RwLock};
+use std::thread::JoinHandle;
+use std::time::{Duration, SystemTimeError};
+use thiserror::Error;
+
+lazy_static! {
+    /// The engine and tenant behind the free functions [build_puzzle](crate::build_puzzle()) and
+    /// [verify_puzzle_result](crate::verify_puzzle_result()), configured once per process, or the
+    /// error setting them up failed with.
+    static ref DEFAULT_FCAPTCHA: Result<(Fcaptcha, Tenant), FcaptchaError> =
+        Fcaptcha::legacy_from_config();
+}
+
+/// Returns the engine and tenant of [DEFAULT_FCAPTCHA], or the error setting them up failed with
+/// as text.
+pub(crate) fn default_fcaptcha() -> Result<&'static (Fcaptcha, Tenant), String> {
+    DEFAULT_FCAPTCHA.as_ref().map_err(ToString::to_string)
+}
+
+/// Describes an error that occurred during setting up an [Fcaptcha] engine.
+#[derive(Display, Error, Debug, PartialEq)]
+pub enum FcaptchaError {
+    /// Tenant configuration invalid: {0}
+    Tenant(#[from] TenantError),
+    /// Store configuration invalid: {0}
+    Store(#[from] StoreError),
+    /// {0}
+    Config(#[from] ConfigError),
+}
+
+/// The configuration an [Fcaptcha] engine is built from.
+///
+/// # Examples
+///
+/// ```
+/// use fcaptcha::tenant::{Tenant, TenantRegistry};
+/// use fcaptcha::{FcaptchaConfig, Keyring};
+///
+/// let tenants = TenantRegistry::new()
+///     .with_tenant(Tenant::new("SITEKEY", "API-SECRET".as_bytes(), Keyring::from("KEY".as_bytes())))
+///     .unwrap();
+/// let config = FcaptchaConfig::new(tenants).with_puzzle_ttl(600);
+/// assert_eq!(config.access_ttl_secs, 1800);
+/// assert_eq!(config.puzzle_ttl_secs, 600);
+/// ```
+#[derive(Clone)]
+pub struct FcaptchaConfig {
+    /// The sites served, with their keys and options.
+    pub tenants: TenantRegistry,
+    /// Store for access counters and used puzzles.
+    pub store: Arc<dyn CaptchaStore>,
+    /// Clock puzzles are built and verified at.
+    pub clock: Arc<dyn Clock>,
+    /// Time in seconds accesses of a client are counted for.
+    pub access_ttl_secs: u64,
+    /// Time in seconds used puzzles are remembered for.
+    pub puzzle_ttl_secs: u64,
+    /// Reverse proxies whose forwarded client addresses are trusted by the web services.
+    pub trusted_proxies: TrustedProxies,
+    /// Monitor of the load the difficulty of the tenants read from settings is raised with, kept
+    /// by [FcaptchaConfig::reread]. `None` if the load is not considered.
+    pub load_monitor: Option<Arc<LoadMonitor>>,
+}
+
+impl fmt::Debug for FcaptchaConfig {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("FcaptchaConfig")
+            .field("tenants", &self.tenants)
+            .field("access_ttl_secs", &self.access_ttl_secs)
+            .field("puzzle_ttl_secs", &self.puzzle_ttl_secs)
+            .field("trusted_proxies", &self.trusted_proxies)
+            .field("load_monitor", &self.load_monitor)
+            .finish_non_exhaustive()
+    }
+}
+
+impl FcaptchaConfig {
+    /// Creates a configuration serving `tenants` from a [MemoryStore] at the [SystemClock], with
+    /// the default access TTL of 30 minutes and puzzle TTL of one hour.
+    pub fn new(tenants: TenantRegistry) -> FcaptchaConfig {
+        FcaptchaConfig {
+            tenants,
+            store: Arc::new(MemoryStore::new()),
+            clock: Arc::new(SystemClock),
+            access_ttl_secs: 1800,
+            puzzle_ttl_secs: 3600,
+            trusted_proxies: TrustedProxies::default(),
+            load_monitor: None,
+        }
+    }
+
+    /// Reads the configuration from `access_ttl`, `puzzle_ttl` and `trusted_proxies` of
+    /// `settings`, the tenants read by [TenantRegistry::from_settings] with the options read by
+    /// [PuzzleOptions::from_settings] and the store selected by [store::from_settings], at the
+    /// [SystemClock]. The tenants share one load monitor and one set of IP rules, which are not
+    /// shared with other engines.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use fcaptcha::config::Settings;
+    /// use fcaptcha::FcaptchaConfig;
+    ///
+    /// let settings = Settings {
+    ///     puzzle_ttl: 7200,
+    ///     ..Settings::default()
+    /// };
+    /// let config = FcaptchaConfig::from_settings(&settings).unwrap();
+    /// assert_eq!(config.puzzle_ttl_secs, 7200);
+    /// assert!(config.tenants.get(&settings.api_key).is_some());
+    /// ```
+    pub fn from_settings(settings: &Settings) -> Result<FcaptchaConfig, FcaptchaError> {
+        FcaptchaConfig::from_settings_with_clock(settings, Arc::new(SystemClock))
+    }
+
+    /// Reads the configuration like [FcaptchaConfig::from_settings], but at `clock`, which also
+    /// decides which persisted store entries are expired on opening.
+    pub fn from_settings_with_clock(
+        settings: &Settings,
+        clock: Arc<dyn Clock>,
+    ) -> Result<FcaptchaConfig, FcaptchaError> {
+        let options = PuzzleOptions::from_settings(settings)?;
+        let config = FcaptchaConfig::new(TenantRegistry::new())
+            .with_store(store::from_settings(settings, &*clock)?)
+            .with_clock(clock)
+            .with_load_monitor(options.load_monitor.clone());
+        Ok(FcaptchaConfig {
+            tenants: TenantRegistry::from_settings(settings, &options)?,
+            ..config.with_settings(settings)?
+        })
+    }
+
+    /// Reads the configuration like [FcaptchaConfig::from_settings], but keeps the store, clock
+    /// and load monitor of `self`, so that access counts, used puzzles and the load carry over.
+    pub fn reread(&self, settings: &Settings) -> Result<FcaptchaConfig, FcaptchaError> {
+        let options = PuzzleOptions::from_settings_with_load(settings, self.load_monitor.clone())?;
+        Ok(FcaptchaConfig {
+            tenants: TenantRegistry::from_settings(settings, &options)?,
+            ..self.clone().with_settings(settings)?
+        })
+    }
+
+    fn with_settings(self, settings: &Settings) -> Result<FcaptchaConfig, ConfigError> {
+        let trusted_proxies = TrustedProxies::from_settings(settings)
+            .map_err(|err| ConfigError::Invalid("trusted_proxies", err.to_string()))?;
+        Ok(self
+            .with_access_ttl(settings.access_ttl)
+            .with_puzzle_ttl(settings.puzzle_ttl)
+            .with_trusted_proxies(trusted_proxies))
+    }
+
+    /// Sets the store for access counters and used puzzles.
+    pub fn with_store(mut self, store: Arc<dyn CaptchaStore>) -> FcaptchaConfig {
+        self.store = store;
+        self
+    }
+
+    /// Sets the clock puzzles are built and verified at.
+    pub fn with_clock(mut self, clock: Arc<dyn Clock>) -> FcaptchaConfig {
+        self.clock = clock;
+        self
+    }
+
+    /// Sets the time in seconds accesses of a client are counted for.
+    pub fn with_access_ttl(mut self, access_ttl_secs: u64) -> FcaptchaConfig {
+        self.access_ttl_secs = access_ttl_secs;
+        self
+    }
+
+    /// Sets the time in seconds used puzzles are remembered for.
+    pub fn with_puzzle_ttl(mut self, puzzle_ttl_secs: u64) -> FcaptchaConfig {
+        self.puzzle_ttl_secs = puzzle_ttl_secs;
+        self
+    }
+
+    /// Sets the reverse proxies whose forwarded client addresses are trusted.
+    pub fn with_trusted_proxies(mut self, trusted_proxies: TrustedProxies) -> FcaptchaConfig {
+        self.trusted_proxies = trusted_proxies;
+        self
+    }
+
+    /// Sets the monitor of the load the difficulty of tenants read by [FcaptchaConfig::reread]
+    /// is raised with.
+    pub fn with_load_monitor(mut self, load_monitor: Option<Arc<LoadMonitor>>) -> FcaptchaConfig {
+        self.load_monitor = load_monitor;
+        self
+    }
+}
+
+/// A captcha engine that owns its tenants with their keys and policies, its store and its clock.
+/// In contrast to [build_puzzle](crate::build_puzzle()) and
+/// [verify_puzzle_result](crate::verify_puzzle_result()), which share state configured once per
+/// process, multiple differently configured engines can be used side by side.
+///
+/// # Examples
+///
+/// ```
+/// use fcaptcha::tenant::{Tenant, TenantRegistry};
+/// use fcaptcha::{Fcaptcha, FcaptchaConfig, Keyring};
+///
+/// let tenants = TenantRegistry::new()
+///     .with_tenant(Tenant::new("SITEKEY", "API-SECRET".as_bytes(), Keyring::from("KEY".as_bytes())))
+///     .unwrap();
+/// let fcaptcha = Fcaptcha::new(FcaptchaConfig::new(tenants));
+/// let tenant = fcaptcha.tenants().get("SITEKEY").unwrap();
+/// let puzzle = fcaptcha.build_puzzle(tenant, "127.0.0.1").unwrap();
+/// let solution = fcaptcha::solve_puzzle(&puzzle).unwrap();
+/// assert!(fcaptcha.verify(tenant, &solution, None).is_ok());
+/// ```
+#[derive(Clone, Debug)]
+pub struct Fcaptcha {
+    config: FcaptchaConfig,
+}
+
+impl Fcaptcha {
+    /// Creates an engine from `config`.
+    pub fn new(config: FcaptchaConfig) -> Fcaptcha {
+        Fcaptcha { config }
+    }
+
+    /// Creates an engine from the configuration read by [FcaptchaConfig::from_settings]. Expired
+    /// store entries are evicted every `store_eviction_interval` seconds.
+    pub fn from_settings(settings: &Settings) -> Result<Fcaptcha, FcaptchaError> {
+        let fcaptcha = Fcaptcha::new(FcaptchaConfig::from_settings(settings)?);
+        fcaptcha.spawn_evictor(Duration::from_secs(settings.store_eviction_interval));
+        Ok(fcaptcha)
+    }
+
+    /// Creates the engine and tenant of [DEFAULT_FCAPTCHA] from the global configuration. The
+    /// tenant uses the keys and puzzle options of the configuration, but no IP binding, as the
+    /// free functions do not know the address of the solving client.
+    fn legacy_from_config() -> Result<(Fcaptcha, Tenant), FcaptchaError> {
+        let settings = config::settings()?;
+        let fcaptcha = Fcaptcha::from_settings(&settings)?;
+        let options = PuzzleOptions::from_settings_with_load(
+            &settings,
+            fcaptcha.config().load_monitor.clone(),
+        )?;
+        let keyring = Keyring::from_settings(&settings).map_err(TenantError::from)?;
+        let tenant = Tenant::new(&settings.api_key, settings.api_key.as_bytes(), keyring)
+            .with_options(options);
+        Ok((fcaptcha, tenant))
+    }
+
+    /// Spawns a background thread evicting expired entries from the store every `interval`, at
+    /// the time of the clock of the engine. See [store::spawn_evictor].
+    pub fn spawn_evictor(&self, interval: Duration) -> JoinHandle<()> {
+        store::spawn_evictor(&self.config.store, interval, Arc::clone(&self.config.clock))
+    }
+
+    /// Creates an engine from `settings` that keeps the store, clock and load monitor of `self`,
+    /// so that access counts and used puzzles carry over. See [FcaptchaConfig::reread].
+    pub fn reconfigured(&self, settings: &Settings) -> Result<Fcaptcha, FcaptchaError> {
+        Ok(Fcaptcha::new(self.config.reread(settings)?))
+    }
+
+    /// Returns the configuration of the engine.
+    pub fn config(&self) -> &FcaptchaConfig {
+        &self.config
+    }
+
+    /// Returns the tenants served by the engine.
+    pub fn tenants(&self) -> &TenantRegistry {
+        &self.config.tenants
+    }
+
+    /// Returns the current level of the load of all puzzles built by the engine, at the time of
+    /// its clock. The level is `0` if the engine has no load monitor.
+    pub fn load_level(&self) -> Result<LoadLevel, SystemTimeError> {
+        let timestamp = self.config.clock.now()?;
+        Ok(self
+            .config
+            .load_monitor
+            .as_ref()
+            .map_or_else(LoadLevel::default, |monitor| monitor.level(timestamp)))
+    }
+
+    /// Builds a new puzzle for `tenant` and the client at `ip_address`, with the keys and options
+    /// of the tenant. The puzzle is bound to `ip_address` if the tenant uses IP binding.
+    pub fn build_puzzle(
+        &self,
+        tenant: &Tenant,
+        ip_address: &str,
+    ) -> Result<String, BuildPuzzleError> {
+        let timestamp = self.config.clock.now()?;
+        let context = tenant.ip_context(Some(ip_address));
+        build_puzzle_with(
+            &*self.config.store,
+            ip_address,
+            context.as_ref().map(String::as_bytes),
+            timestamp,
+            rand::random(),
+            &tenant.keyring,
+            self.config.access_ttl_secs,
+            &tenant.options,
+        )
+    }
+
+    /// Verifies a puzzle result, only accepting puzzles built for `tenant`. If the tenant uses IP
+    /// binding, only puzzles built for `ip_address`, the address of the client that solved the
+    /// puzzle, are accepted.
+    pub fn verify(&self, tenant: &Tenant, solution: &str, ip_address: Option<&str>) -> Verdict {
+        let timestamp = match self.config.clock.now() {
+            Ok(timestamp) => timestamp,
+            Err(err) => return Verdict::failed(Failure::at(Check::Time)(err)),
+        };
+        let context = tenant.ip_context(ip_address);
+        verify_puzzle_result_with(
+            &*self.config.store,
+            solution,
+            context.as_ref().map(String::as_bytes),
+            timestamp,
+            self.config.puzzle_ttl_secs,
+            &tenant.keyring,
+            &tenant.options,
+        )
+    }
+}
+
+/// An [Fcaptcha] engine that can be replaced while in use, e.g. to apply a changed configuration
+/// without restarting the server. Users take the [current](ReloadableFcaptcha::current) engine
+/// once per request, so that each request is served by a single engine.
+///
+/// # Examples
+///
+/// ```
+/// use fcaptcha::tenant::{Tenant, TenantRegistry};
+/// use fcaptcha::{Fcaptcha, FcaptchaConfig, Keyring, ReloadableFcaptcha};
+///
+/// let tenants = |sitekey| {
+///     TenantRegistry::new()
+///         .with_tenant(Tenant::new(sitekey, "API-SECRET".as_bytes(), Keyring::from("KEY".as_bytes())))
+///         .unwrap()
+/// };
+/// let fcaptcha = ReloadableFcaptcha::new(Fcaptcha::new(FcaptchaConfig::new(tenants("A"))));
+/// let previous = fcaptcha.current();
+/// fcaptcha.replace(Fcaptcha::new(previous.config().clone().with_puzzle_ttl(600)));
+/// assert_eq!(fcaptcha.current().config().puzzle_ttl_secs, 600);
+/// assert_eq!(previous.config().puzzle_ttl_secs, 3600);
+/// ```
+#[derive(Debug)]
+pub struct ReloadableFcaptcha {
+    engine: RwLock<Arc<Fcaptcha>>,
+}
+
+impl From<Fcaptcha> for ReloadableFcaptcha {
+    fn from(fcaptcha: Fcaptcha) -> Self {
+        ReloadableFcaptcha::new(fcaptcha)
+    }
+}
+
+impl ReloadableFcaptcha {
+    /// Wraps `fcaptcha` to be replaceable.
+    pub fn new(fcaptcha: Fcaptcha) -> ReloadableFcaptcha {
+        ReloadableFcaptcha {
+            engine: RwLock::new(Arc::new(fcaptcha)),
+        }
+    }
+
+    /// Returns the engine currently in use.
+    pub fn current(&self) -> Arc<Fcaptcha> {
+        Arc::clone(&self.engine.read().unwrap_or_else(PoisonError::into_inner))
+    }
+
+    /// Replaces the engine in use by `fcaptcha` and returns the previous one. Requests already
+    /// being served finish with the previous engine.
+    pub fn replace(&self, fcaptcha: Fcaptcha) -> Arc<Fcaptcha> {
+        let mut engine = self.engine.write().unwrap_or_else(PoisonError::into_inner);
+        std::mem::replace(&mut engine, Arc::new(fcaptcha))
+    }
+
+    /// Reads the configuration file and environment again and replaces the engine by one
+    /// [reconfigured](Fcaptcha::reconfigured) from it, keeping store and clock. The global
+    /// configuration read by [get](crate::config::get) is replaced together with the engine, while
+    /// the engine behind the free functions keeps the configuration of their first use. Returns
+    /// the new settings. On failure, both the engine and the configuration in use are kept.
+    pub fn reload(&self) -> Result<Settings, FcaptchaError> {
+        let loaded = config::load_current()?;
+        let fcaptcha = self.current().reconfigured(&loaded.settings)?;
+        let settings = Settings::clone(&loaded.settings);
+        let mut engine = self.engine.write().unwrap_or_else(PoisonError::into_inner);
+        config::commit(loaded);
+        *engine = Arc::new(fcaptcha);
+        Ok(settings)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::build_puzzle::PuzzleOptions;
+    use crate::clock::ManualClock;
+    use crate::difficulty::TierTable;
+    use crate::puzzle::SignedPuzzle;
+    use crate::verify_puzzle_result::{VerifyPuzzleResultError, MAX_CLOCK_SKEW_SECS};
+    use crate::Keyring;
+
+    const TIMESTAMP: u64 = 1693424664;
+
+    fn tenants() -> TenantRegistry {
+        let tiers: TierTable = "0:4:100,5:4:110".parse().unwrap();
+        TenantRegistry::new()
+            .with_tenant(
+                Tenant::new(
+                    "A",
+                    "SECRET-A".as_bytes(),
+                    Keyring::from("KEY-A".as_bytes()),
+                )
+                .with_options(PuzzleOptions::default().with_difficulty_policy(tiers)),
+            )
+            .unwrap()
+    }
+
+    fn manual_engine() -> (Fcaptcha, Arc<ManualClock>) {
+        let clock = Arc::new(ManualClock::new(TIMESTAMP));
+        let config = FcaptchaConfig::new(tenants()).with_clock(Arc::clone(&clock) as _);
+        (Fcaptcha::new(config), clock)
+    }
+
+    #[test]
+    fn test_clock_expiry() {
+        let (fcaptcha, clock) = manual_engine();
+        let tenant = fcaptcha.tenants().get("A").unwrap();
+        let puzzle = fcaptcha.build_puzzle(tenant, "127.0.0.1").unwrap();
+        assert_eq!(
+            puzzle.parse::<SignedPuzzle>().unwrap().puzzle.timestamp,
+            TIMESTAMP as u32
+        );
+        let solution = crate::solve_puzzle(&puzzle).unwrap();
+
+        clock.advance(3601);
+        let verdict = fcaptcha.verify(tenant, &solution, None);
+        assert_eq!(verdict.age, Some(3601));
+        assert_eq!(
+            verdict.into_result(),
+            Err(VerifyPuzzleResultError::PuzzleExpired)
+        );
+
+        // Verified puzzles are marked as used even if expired, so a fresh store is needed.
+        let fcaptcha = Fcaptcha::new(
+            fcaptcha
+                .config()
+                .clone()
+                .with_store(Arc::new(MemoryStore::new())),
+        );
+        clock.set(TIMESTAMP - MAX_CLOCK_SKEW_SECS - 1);
+        assert_eq!(
+            fcaptcha.verify(tenant, &solution, None).into_result(),
+            Err(VerifyPuzzleResultError::PuzzleFromFuture)
+        );
+    }
+
+    #[test]
+    fn test_clock_puzzle_ttl() {
+        let (fcaptcha, clock) = manual_engine();
+        let fcaptcha = Fcaptcha::new(fcaptcha.config().clone().with_puzzle_ttl(60));
+        let tenant = fcaptcha.tenants().get("A").unwrap();
+        let solution =
+            crate::solve_puzzle(&fcaptcha.build_puzzle(tenant, "127.0.0.1").unwrap()).unwrap();
+
+        assert!(fcaptcha.verify(tenant, &solution, None).is_ok());
+        clock.advance(59);
+        assert_eq!(
+            fcaptcha.verify(tenant, &solution, None).into_result(),
+            Err(VerifyPuzzleResultError::PuzzleReuse)
+        );
+        // Puzzles are forgotten after the puzzle TTL, even if they are not expired yet.
+        clock.advance(1);
+        assert!(fcaptcha.verify(tenant, &solution, None).is_ok());
+    }
+
+    #[test]
+    fn test_clock_access_ttl() {
+        let (fcaptcha, clock) = manual_engine();
+        let tenant = fcaptcha.tenants().get("A").unwrap();
+        let difficulty = || {
+            let puzzle = fcaptcha.build_puzzle(tenant, "192.0.2.1").unwrap();
+            puzzle.parse::<SignedPuzzle>().unwrap().puzzle.difficulty
+        };
+
+        for _ in 0..4 {
+            assert_eq!(difficulty(), 100);
+            clock.advance(60);
+        }
+        assert_eq!(difficulty(), 110);
+        // Accesses are counted again once the last one is older than the access TTL.
+        clock.advance(1801);
+        assert_eq!(difficulty(), 100);
+    }
+
+    #[test]
+    fn test_reconfigured_keeps_store() {
+        let (fcaptcha, _) = manual_engine();
+        let fcaptcha = Fcaptcha::new(fcaptcha.config().clone().with_puzzle_ttl(60));
+        let tenant = fcaptcha.tenants().get("A").unwrap().clone();
+        let solution =
+            crate::solve_puzzle(&fcaptcha.build_puzzle(&tenant, "127.0.0.1").unwrap()).unwrap();
+        assert!(fcaptcha.verify(&tenant, &solution, None).is_ok());
+
+        let fcaptcha = ReloadableFcaptcha::new(fcaptcha);
+        let settings = Settings::default();
+        fcaptcha.replace(fcaptcha.current().reconfigured(&settings).unwrap());
+        let reloaded = fcaptcha.current();
+        assert_eq!(reloaded.config().puzzle_ttl_secs, settings.puzzle_ttl);
+        assert!(reloaded.tenants().get(&settings.api_key).is_some());
+        assert!(reloaded.tenants().get("A").is_none());
+        // Used puzzles are still remembered by the reconfigured engine.
+        assert_eq!(
+            reloaded.verify(&tenant, &solution, None).into_result(),
+            Err(VerifyPuzzleResultError::PuzzleReuse)
+        );
+    }
+
+    #[test]
+    fn test_engines_are_isolated() {
+        let first = Fcaptcha::new(FcaptchaConfig::new(tenants()));
+        let second = Fcaptcha::new(FcaptchaConfig::new(tenants()));
+        let tenant = first.tenants().get("A").unwrap();
+        let solution =
+            crate::solve_puzzle(&first.build_puzzle(tenant, "127.0.0.1").unwrap()).unwrap();
+
+        assert!(first.verify(tenant, &solution, None).is_ok());
+        assert_eq!(
+            first.verify(tenant, &solution, None).into_result(),
+            Err(VerifyPuzzleResultError::PuzzleReuse)
+        );
+        // Used puzzles are only remembered by the engine that verified them.
+        assert!(second.verify(tenant, &solution, None).is_ok());
+    }
+
+    #[test]
+    fn test_engines_have_own_load_monitor() {
+        let settings = Settings {
+            load_thresholds: "2:8".to_string(),
+            ..Settings::default()
+        };
+        let first = Fcaptcha::new(FcaptchaConfig::from_settings(&settings).unwrap());
+        let second = Fcaptcha::new(FcaptchaConfig::from_settings(&settings).unwrap());
+        let difficulty = |fcaptcha: &Fcaptcha, ip_address| {
+            let tenant = fcaptcha.tenants().get(&settings.api_key).unwrap();
+            let puzzle = fcaptcha.build_puzzle(tenant, ip_address).unwrap();
+            puzzle.parse::<SignedPuzzle>().unwrap().puzzle.difficulty
+        };
+
+        assert_eq!(difficulty(&first, "192.0.2.1"), 122);
+        assert_eq!(difficulty(&first, "192.0.2.2"), 130);
+        // The load of one engine does not raise the difficulty of another.
+        assert_eq!(difficulty(&second, "192.0.2.3"), 122);
+        assert_eq!(first.load_level().unwrap().difficulty_increase, 8);
+        assert_eq!(second.load_level().unwrap().level, 0);
+    }
+
+    #[test]
+    fn test_load_level_at_clock() {
+        let clock = Arc::new(ManualClock::new(TIMESTAMP));
+        let monitor = Arc::new(LoadMonitor::new(60, 300, "2:8".parse().unwrap()).unwrap());
+        let fcaptcha = Fcaptcha::new(
+            FcaptchaConfig::new(tenants())
+                .with_clock(Arc::clone(&clock) as _)
+                .with_load_monitor(Some(Arc::clone(&monitor))),
+        );
+        monitor.record(TIMESTAMP);
+        monitor.record(TIMESTAMP);
+        assert_eq!(fcaptcha.load_level().unwrap().rate, 2);
+
+        clock.advance(61);
+        assert_eq!(fcaptcha.load_level().unwrap().rate, 0);
+        assert_eq!(
+            Fcaptcha::new(FcaptchaConfig::new(tenants()))
+                .load_level()
+                .unwrap(),
+            LoadLevel::default()
+        );
+    }
+
+    #[test]
+    fn test_ip_binding() {
+        let fcaptcha = Fcaptcha::new(FcaptchaConfig::new(TenantRegistry::new()));
+        let tenant = Tenant::new(
+            "C",
+            "SECRET-C".as_bytes(),
+            Keyring::from("KEY-C".as_bytes()),
+        )
+        .with_ip_binding(true);
+        let solution = format!(
+            "{}.{}==.AgAA",
+            fcaptcha.build_puzzle(&tenant, "192.0.2.1:4711").unwrap(),
+            "A".repeat(86)
+        );
+        let check = |ip_address| {
+            fcaptcha
+                .verify(&tenant, &solution, ip_address)
+                .failed_check()
+        };
+        assert_eq!(check(Some("192.0.2.2")), Some(Check::Context));
+        assert_eq!(check(None), Some(Check::Context));
+        // The context matches, only the solutions are not solved.
+        assert_eq!(check(Some("::ffff:192.0.2.1")), Some(Check::Solutions));
+    }
+}
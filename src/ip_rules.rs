@@ -0,0 +1,509 @@
+use crate::config::Settings;
+use crate::prefix::{network, parse_address};
+use displaydoc::Display;
+use std::fmt;
+use std::fs;
+use std::net::IpAddr;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{PoisonError, RwLock};
+use std::time::SystemTime;
+use thiserror::Error;
+
+/// Describes an error that occurred during loading IP rules.
+#[derive(Display, Error, Debug, PartialEq)]
+pub enum IpRulesError {
+    /// IP rule configuration malformed: {0}
+    ConfigMalformed(String),
+    /// IP rule file {0} could not be read: {1}
+    FileUnreadable(String, String),
+}
+
+/// What happens to puzzle requests from addresses matching an [IpRule].
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum RuleAction {
+    /// Puzzles get the minimum difficulty, as for a client without previous accesses.
+    Allow,
+    /// Puzzles are trivial to solve, i.e. the captcha is skipped.
+    Bypass,
+    /// Puzzles get the maximum difficulty, as for a client with countless accesses.
+    Throttle,
+    /// No puzzles are built.
+    Deny,
+}
+
+impl FromStr for RuleAction {
+    type Err = IpRulesError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "allow" => Ok(RuleAction::Allow),
+            "bypass" => Ok(RuleAction::Bypass),
+            "throttle" => Ok(RuleAction::Throttle),
+            "deny" => Ok(RuleAction::Deny),
+            _ => Err(IpRulesError::ConfigMalformed(format!(
+                "unknown action {:?}",
+                s
+            ))),
+        }
+    }
+}
+
+/// A network in CIDR notation, e.g. `192.0.2.0/24` or `2001:db8::/32`. A plain address is a
+/// network of a single address. Host bits are cleared.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Cidr {
+    network: IpAddr,
+    length: u8,
+}
+
+impl Cidr {
+    /// Returns the prefix length in bits.
+    pub fn length(&self) -> u8 {
+        self.length
+    }
+
+    /// Returns `true` if `addr` is within the network. IPv4 networks do not contain IPv6
+    /// addresses and vice versa.
+    pub fn contains(&self, addr: IpAddr) -> bool {
+        addr.is_ipv4() == self.network.is_ipv4() && network(addr, self.length) == self.network
+    }
+}
+
+impl FromStr for Cidr {
+    type Err = IpRulesError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let malformed = || IpRulesError::ConfigMalformed(format!("invalid network {:?}", s));
+        let (addr, length) = match s.split_once('/') {
+            Some((addr, length)) => (addr, Some(length)),
+            None => (s, None),
+        };
+        let addr = parse_address(addr).ok_or_else(malformed)?;
+        let max_length = if addr.is_ipv4() { 32 } else { 128 };
+        let length = match length {
+            Some(length) => length.parse().map_err(|_| malformed())?,
+            None => max_length,
+        };
+        if length > max_length {
+            return Err(malformed());
+        }
+        Ok(Cidr {
+            network: network(addr, length),
+            length,
+        })
+    }
+}
+
+impl fmt::Display for Cidr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}/{}", self.network, self.length)
+    }
+}
+
+/// Applies an action to the puzzle requests from a network.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct IpRule {
+    /// The network the rule applies to.
+    pub cidr: Cidr,
+    /// What happens to puzzle requests from the network.
+    pub action: RuleAction,
+}
+
+/// A list of [IpRule]s. The rule with the longest prefix containing an address applies, the first
+/// of them if there are multiple.
+///
+/// # Examples
+///
+/// ```
+/// use fcaptcha::ip_rules::{RuleAction, RuleSet};
+///
+/// let rules: RuleSet = "deny 192.0.2.0/24, allow 192.0.2.10".parse().unwrap();
+/// assert_eq!(rules.action("192.0.2.10"), Some(RuleAction::Allow));
+/// assert_eq!(rules.action("192.0.2.11"), Some(RuleAction::Deny));
+/// assert_eq!(rules.action("198.51.100.1"), None);
+/// ```
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct RuleSet(Vec<IpRule>);
+
+impl RuleSet {
+    /// Creates a rule set from `rules`.
+    pub fn new(rules: Vec<IpRule>) -> RuleSet {
+        RuleSet(rules)
+    }
+
+    /// Returns the rules.
+    pub fn rules(&self) -> &[IpRule] {
+        &self.0
+    }
+
+    /// Returns the action of the rule applying to `ip_address`, which may carry a port.
+    pub fn action(&self, ip_address: &str) -> Option<RuleAction> {
+        let addr = parse_address(ip_address)?;
+        self.matching(addr).map(|rule| rule.action)
+    }
+
+    fn matching(&self, addr: IpAddr) -> Option<&IpRule> {
+        self.0.iter().filter(|rule| rule.cidr.contains(addr)).fold(
+            None,
+            |best: Option<&IpRule>, rule| match best {
+                Some(best) if best.cidr.length >= rule.cidr.length => Some(best),
+                _ => Some(rule),
+            },
+        )
+    }
+
+    fn chain(&self, other: &RuleSet) -> RuleSet {
+        RuleSet(self.0.iter().chain(&other.0).copied().collect())
+    }
+}
+
+impl FromStr for RuleSet {
+    type Err = IpRulesError;
+
+    /// Parses rules in the form `<action> <network>`, separated by commas or lines. Text after a
+    /// `#` is ignored. The actions are `allow`, `bypass`, `throttle` and `deny`.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        s.lines()
+            .map(|line| line.split_once('#').map_or(line, |(rule, _)| rule))
+            .flat_map(|line| line.split(','))
+            .map(str::trim)
+            .filter(|entry| !entry.is_empty())
+            .map(|entry| {
+                let fields: Vec<&str> = entry.split_whitespace().collect();
+                let [action, cidr] = fields[..] else {
+                    return Err(IpRulesError::ConfigMalformed(format!("{:?}", entry)));
+                };
+                Ok(IpRule {
+                    cidr: cidr.parse()?,
+                    action: action.parse()?,
+                })
+            })
+            .collect::<Result<_, _>>()
+            .map(RuleSet)
+    }
+}
+
+/// Networks of reverse proxies whose `X-Forwarded-For` and `Forwarded` headers are trusted. The
+/// forwarded addresses are followed from the proxy closest to the server towards the client as long
+/// as they are trusted proxies themselves, so that addresses a client made up before its request
+/// reached the first trusted proxy are ignored.
+///
+/// # Examples
+///
+/// ```
+/// use fcaptcha::ip_rules::TrustedProxies;
+/// use std::net::IpAddr;
+///
+/// let proxies: TrustedProxies = "10.0.0.0/8, 192.0.2.1".parse().unwrap();
+/// let addr = |addr: &str| addr.parse::<IpAddr>().unwrap();
+/// let forwarded = ["203.0.113.1", "198.51.100.7", "192.0.2.1"];
+/// assert_eq!(proxies.client_address(addr("10.0.0.2"), forwarded), addr("198.51.100.7"));
+/// // Forwarded addresses are ignored for requests that do not come from a trusted proxy.
+/// let peer = addr("198.51.100.7");
+/// assert_eq!(proxies.client_address(peer, ["192.0.2.10"]), peer);
+/// ```
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct TrustedProxies(Vec<Cidr>);
+
+impl TrustedProxies {
+    /// Trusts the proxies in `networks`.
+    pub fn new(networks: Vec<Cidr>) -> TrustedProxies {
+        TrustedProxies(networks)
+    }
+
+    /// Reads the networks from `trusted_proxies` of `settings` in the form parsed by
+    /// [TrustedProxies::from_str].
+    pub fn from_settings(settings: &Settings) -> Result<TrustedProxies, IpRulesError> {
+        settings.trusted_proxies.parse()
+    }
+
+    /// Returns `true` if `addr` is a trusted proxy.
+    pub fn contains(&self, addr: IpAddr) -> bool {
+        self.0.iter().any(|cidr| cidr.contains(addr))
+    }
+
+    /// Returns the address of the client a request received from `peer` was sent by.
+    /// `forwarded_for` lists the forwarded addresses, the client first and the last proxy last.
+    /// Addresses that can not be parsed, e.g. obfuscated identifiers, end the search at the proxy
+    /// that added them.
+    pub fn client_address<'a, I>(&self, peer: IpAddr, forwarded_for: I) -> IpAddr
+    where
+        I: IntoIterator<Item = &'a str>,
+        I::IntoIter: DoubleEndedIterator,
+    {
+        let mut client = peer;
+        for addr in forwarded_for.into_iter().rev() {
+            if !self.contains(client) {
+                break;
+            }
+            let addr = addr.trim();
+            let bracketed = addr
+                .strip_prefix('[')
+                .and_then(|addr| addr.strip_suffix(']'));
+            match parse_address(bracketed.unwrap_or(addr)) {
+                Some(addr) => client = addr,
+                None => break,
+            }
+        }
+        client
+    }
+}
+
+impl FromStr for TrustedProxies {
+    type Err = IpRulesError;
+
+    /// Parses networks in CIDR notation, separated by commas or whitespace.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        s.split(|c: char| c == ',' || c.is_whitespace())
+            .filter(|entry| !entry.is_empty())
+            .map(str::parse)
+            .collect::<Result<_, _>>()
+            .map(TrustedProxies)
+    }
+}
+
+#[derive(Debug, Default)]
+struct FileState {
+    rules: RuleSet,
+    modified: Option<SystemTime>,
+}
+
+/// Rules given directly and read from an optional file. The file is read again once it changed,
+/// so rules can be updated without a restart. Changes are checked at most once per second when
+/// looking up an address, or on [IpRules::reload].
+///
+/// # Examples
+///
+/// ```
+/// use fcaptcha::ip_rules::{IpRules, RuleAction};
+/// use std::io::Write;
+///
+/// let mut file = tempfile::NamedTempFile::new().unwrap();
+/// writeln!(file, "# monitoring\nbypass 203.0.113.0/24").unwrap();
+/// let rules = IpRules::with_file("deny 0.0.0.0/0".parse().unwrap(), file.path()).unwrap();
+/// assert_eq!(rules.action("203.0.113.5", 0), Some(RuleAction::Bypass));
+/// assert_eq!(rules.action("198.51.100.1", 0), Some(RuleAction::Deny));
+/// ```
+#[derive(Debug, Default)]
+pub struct IpRules {
+    rules: RuleSet,
+    file: Option<PathBuf>,
+    file_state: RwLock<FileState>,
+    last_check: AtomicU64,
+}
+
+impl IpRules {
+    /// Creates fixed rules.
+    pub fn new(rules: RuleSet) -> IpRules {
+        IpRules {
+            rules,
+            ..IpRules::default()
+        }
+    }
+
+    /// Creates `rules` extended by the ones read from `file`, see [RuleSet::from_str]. Fails if
+    /// the file can not be read or parsed.
+    pub fn with_file<P: AsRef<Path>>(rules: RuleSet, file: P) -> Result<IpRules, IpRulesError> {
+        let ip_rules = IpRules {
+            file: Some(file.as_ref().to_path_buf()),
+            ..IpRules::new(rules)
+        };
+        ip_rules.reload()?;
+        Ok(ip_rules)
+    }
+
+    /// Reads the rules from `ip_rules` of `settings` and the file given by `ip_rules_file`, if
+    /// any, in the form parsed by [RuleSet::from_str].
+    pub fn from_settings(settings: &Settings) -> Result<IpRules, IpRulesError> {
+        let rules = settings.ip_rules.parse()?;
+        match settings.ip_rules_file.as_str() {
+            "" => Ok(IpRules::new(rules)),
+            file => IpRules::with_file(rules, file),
+        }
+    }
+
+    /// Reads the file again if it changed. Returns `true` if the rules were replaced. On failure
+    /// the previous rules are kept.
+    pub fn reload(&self) -> Result<bool, IpRulesError> {
+        let Some(file) = &self.file else {
+            return Ok(false);
+        };
+        let unreadable = |err: std::io::Error| {
+            IpRulesError::FileUnreadable(file.display().to_string(), err.to_string())
+        };
+        let modified = fs::metadata(file).and_then(|meta| meta.modified()).ok();
+        {
+            let state = self
+                .file_state
+                .read()
+                .unwrap_or_else(PoisonError::into_inner);
+            if modified.is_some() && state.modified == modified {
+                return Ok(false);
+            }
+        }
+        let rules: RuleSet = fs::read_to_string(file).map_err(unreadable)?.parse()?;
+        let mut state = self
+            .file_state
+            .write()
+            .unwrap_or_else(PoisonError::into_inner);
+        info!(
+            "Loaded {:?} IP rules from {:?}",
+            rules.rules().len(),
+            file.display()
+        );
+        *state = FileState { rules, modified };
+        Ok(true)
+    }
+
+    /// Returns all rules currently in effect, the ones given directly first.
+    pub fn rules(&self) -> RuleSet {
+        let state = self
+            .file_state
+            .read()
+            .unwrap_or_else(PoisonError::into_inner);
+        self.rules.chain(&state.rules)
+    }
+
+    /// Returns the action of the rule applying to `ip_address` at `timestamp`, in seconds since
+    /// the Unix epoch. The file is checked for changes if it was not checked within that second.
+    pub fn action(&self, ip_address: &str, timestamp: u64) -> Option<RuleAction> {
+        if self.file.is_some()
+            && self.last_check.fetch_max(timestamp, Ordering::Relaxed) < timestamp
+        {
+            if let Err(err) = self.reload() {
+                error!("Reloading IP rules failed, keeping previous rules: {}", err);
+            }
+        }
+        let addr = parse_address(ip_address)?;
+        let state = self
+            .file_state
+            .read()
+            .unwrap_or_else(PoisonError::into_inner);
+        match (self.rules.matching(addr), state.rules.matching(addr)) {
+            (Some(rule), Some(file_rule)) if file_rule.cidr.length > rule.cidr.length => {
+                Some(file_rule.action)
+            }
+            (Some(rule), _) | (None, Some(rule)) => Some(rule.action),
+            (None, None) => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Write;
+
+    #[test]
+    fn test_longest_prefix_applies() -> Result<(), IpRulesError> {
+        let rules: RuleSet = "throttle 10.0.0.0/8, allow 10.1.0.0/16 # office\n\
+            deny 10.1.2.3, bypass 10.1.2.3/32\n\
+            deny 2001:db8::/32"
+            .parse()?;
+        assert_eq!(rules.action("10.2.0.1"), Some(RuleAction::Throttle));
+        assert_eq!(rules.action("10.1.0.1:443"), Some(RuleAction::Allow));
+        assert_eq!(rules.action("10.1.2.3"), Some(RuleAction::Deny));
+        assert_eq!(rules.action("::ffff:10.1.2.3"), Some(RuleAction::Deny));
+        assert_eq!(rules.action("[2001:db8::1]:443"), Some(RuleAction::Deny));
+        assert_eq!(rules.action("2001:db9::1"), None);
+        assert_eq!(rules.action("unknown"), None);
+        Ok(())
+    }
+
+    #[test]
+    fn test_parse() -> Result<(), IpRulesError> {
+        let cidr: Cidr = "192.0.2.77/24".parse()?;
+        assert_eq!(cidr.to_string(), "192.0.2.0/24");
+        assert_eq!(
+            "2001:db8::1".parse::<Cidr>()?.to_string(),
+            "2001:db8::1/128"
+        );
+        assert_eq!("".parse(), Ok(RuleSet::default()));
+        for input in [
+            "deny",
+            "deny 10.0.0.0/33",
+            "deny ::/129",
+            "deny 10.0.0.0/8 extra",
+            "block 10.0.0.0/8",
+            "deny 10.0.0/8",
+        ] {
+            assert!(
+                matches!(
+                    input.parse::<RuleSet>(),
+                    Err(IpRulesError::ConfigMalformed(_))
+                ),
+                "{:?}",
+                input
+            );
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_trusted_proxies() -> Result<(), IpRulesError> {
+        let proxies: TrustedProxies = "10.0.0.0/8 2001:db8::/32".parse()?;
+        let addr = |addr: &str| addr.parse::<IpAddr>().unwrap();
+        let client = |peer, forwarded: &[&str]| {
+            proxies.client_address(addr(peer), forwarded.iter().copied())
+        };
+
+        assert_eq!(client("10.0.0.1", &[]), addr("10.0.0.1"));
+        assert_eq!(client("10.0.0.1", &["192.0.2.1"]), addr("192.0.2.1"));
+        assert_eq!(
+            client("10.0.0.1", &["192.0.2.1", "10.0.0.2:8080", " 10.0.0.3"]),
+            addr("192.0.2.1")
+        );
+        // Addresses before the first untrusted one may be made up by the client.
+        assert_eq!(
+            client("10.0.0.1", &["203.0.113.1", "192.0.2.1", "10.0.0.2"]),
+            addr("192.0.2.1")
+        );
+        assert_eq!(client("192.0.2.1", &["10.0.0.2"]), addr("192.0.2.1"));
+        assert_eq!(
+            client("2001:db8::1", &["[2001:db8:1::1]", "[2001:db8::2]:4711"]),
+            addr("2001:db8:1::1")
+        );
+        assert_eq!(
+            client("10.0.0.1", &["192.0.2.1", "unknown"]),
+            addr("10.0.0.1")
+        );
+        assert_eq!(
+            TrustedProxies::default().client_address(addr("10.0.0.1"), ["192.0.2.1"]),
+            addr("10.0.0.1")
+        );
+        assert!(matches!(
+            "10.0.0.0/8, proxy".parse::<TrustedProxies>(),
+            Err(IpRulesError::ConfigMalformed(_))
+        ));
+        Ok(())
+    }
+
+    #[test]
+    fn test_file_reload() -> Result<(), IpRulesError> {
+        let mut file = tempfile::NamedTempFile::new().unwrap();
+        writeln!(file, "deny 192.0.2.0/24").unwrap();
+        let rules = IpRules::with_file("allow 192.0.2.1".parse()?, file.path())?;
+        assert_eq!(rules.action("192.0.2.1", 1000), Some(RuleAction::Allow));
+        assert_eq!(rules.action("192.0.2.2", 1000), Some(RuleAction::Deny));
+        assert!(!rules.reload()?);
+
+        fs::write(file.path(), "throttle 192.0.2.0/24").unwrap();
+        file.as_file().set_modified(SystemTime::UNIX_EPOCH).unwrap();
+        assert_eq!(rules.action("192.0.2.2", 1000), Some(RuleAction::Deny));
+        assert_eq!(rules.action("192.0.2.2", 1001), Some(RuleAction::Throttle));
+        assert_eq!(rules.rules().rules().len(), 2);
+
+        // Broken files keep the previous rules.
+        fs::write(file.path(), "invalid").unwrap();
+        assert!(rules.reload().is_err());
+        assert_eq!(rules.action("192.0.2.2", 1002), Some(RuleAction::Throttle));
+
+        assert!(matches!(
+            IpRules::with_file(RuleSet::default(), "/nonexistent/rules"),
+            Err(IpRulesError::FileUnreadable(_, _))
+        ));
+        Ok(())
+    }
+}
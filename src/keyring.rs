@@ -0,0 +1,257 @@
+use crate::config::Settings;
+use crate::puzzle::Puzzle;
+use digest::InvalidLength;
+use displaydoc::Display;
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+use std::collections::BTreeMap;
+use std::fmt;
+use thiserror::Error;
+
+type HmacSha256 = Hmac<Sha256>;
+
+/// Describes an error that occurred during using a keyring.
+#[derive(Display, Error, Debug, PartialEq)]
+pub enum KeyringError {
+    /// Key {0} is unknown or retired.
+    UnknownKey(u32),
+    /// Key is invalid.
+    InvalidKey(#[from] InvalidLength),
+    /// Signatures do not match.
+    SignatureMismatch,
+    /// Key configuration malformed: {0}
+    ConfigMalformed(String),
+}
+
+/// A set of secret keys identified by ids. Puzzles are signed with the active key and carry its id,
+/// so that they can still be verified after another key became active. Keys are accepted for
+/// verification until they are retired.
+///
+/// # Examples
+///
+/// ```
+/// use fcaptcha::Keyring;
+///
+/// let keyring = Keyring::new(2, "NEW-SECRET-KEY".as_bytes())
+///     .with_verification_key(1, "OLD-SECRET-KEY".as_bytes());
+/// assert_eq!(keyring.active_key_id(), 2);
+/// assert_eq!(keyring.key_ids().collect::<Vec<_>>(), [1, 2]);
+/// ```
+#[derive(Clone)]
+pub struct Keyring {
+    active_key_id: u32,
+    keys: BTreeMap<u32, Vec<u8>>,
+}
+
+impl fmt::Debug for Keyring {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Keyring")
+            .field("active_key_id", &self.active_key_id)
+            .field("key_ids", &self.keys.keys().collect::<Vec<_>>())
+            .finish()
+    }
+}
+
+impl From<&[u8]> for Keyring {
+    /// Creates a keyring with the single key `secret_key` with the id 0.
+    fn from(secret_key: &[u8]) -> Self {
+        Keyring::new(0, secret_key)
+    }
+}
+
+impl Keyring {
+    /// Creates a keyring with the single active key `secret_key` identified by `key_id`.
+    pub fn new(key_id: u32, secret_key: &[u8]) -> Keyring {
+        Keyring {
+            active_key_id: key_id,
+            keys: BTreeMap::from([(key_id, secret_key.to_vec())]),
+        }
+    }
+
+    /// Reads the keyring from `secret_key` and `secret_key_id` of `settings` for the active key
+    /// and `secret_keys` for additional verification keys in the form `<id>:<key>,<id>:<key>`.
+    pub fn from_settings(settings: &Settings) -> Result<Keyring, KeyringError> {
+        let active_key_id = settings.secret_key_id;
+        parse_secret_keys(active_key_id, &settings.secret_keys)?
+            .into_iter()
+            .try_fold(
+                Keyring::new(active_key_id, settings.secret_key.as_bytes()),
+                |keyring, (key_id, secret_key)| {
+                    Ok(keyring.with_verification_key(key_id, secret_key.as_bytes()))
+                },
+            )
+    }
+
+    /// Adds or replaces a key that is accepted for verification only. The active key can not be
+    /// replaced.
+    pub fn with_verification_key(mut self, key_id: u32, secret_key: &[u8]) -> Keyring {
+        if key_id != self.active_key_id {
+            self.keys.insert(key_id, secret_key.to_vec());
+        }
+        self
+    }
+
+    /// Makes the key `key_id` the one used for signing. The previously active key stays accepted
+    /// for verification.
+    pub fn activate(&mut self, key_id: u32) -> Result<(), KeyringError> {
+        if !self.keys.contains_key(&key_id) {
+            return Err(KeyringError::UnknownKey(key_id));
+        }
+        self.active_key_id = key_id;
+        Ok(())
+    }
+
+    /// Retires the key `key_id`, so that puzzles signed with it are no longer accepted. Returns
+    /// `false` if the key is unknown or active.
+    pub fn retire(&mut self, key_id: u32) -> bool {
+        key_id != self.active_key_id && self.keys.remove(&key_id).is_some()
+    }
+
+    /// Returns the id of the key used for signing.
+    pub fn active_key_id(&self) -> u32 {
+        self.active_key_id
+    }
+
+    /// Returns the ids of all keys accepted for verification.
+    pub fn key_ids(&self) -> impl Iterator<Item = u32> + '_ {
+        self.keys.keys().copied()
+    }
+
+    /// Sets the key id of `puzzle` to the active key and returns the signature of the puzzle.
+    pub fn sign(&self, puzzle: &mut Puzzle) -> Result<Vec<u8>, KeyringError> {
+        puzzle.key_id = self.active_key_id;
+        let mut macer = self.macer(puzzle.key_id)?;
+        macer.update(&puzzle.to_bytes());
+        Ok(macer.finalize().into_bytes().to_vec())
+    }
+
+    /// Verifies that `signature` was created for `puzzle` with the key given by its key id.
+    pub fn verify(&self, puzzle: &Puzzle, signature: &[u8]) -> Result<(), KeyringError> {
+        let mut macer = self.macer(puzzle.key_id)?;
+        macer.update(&puzzle.to_bytes());
+        macer
+            .verify_slice(signature)
+            .map_err(|_| KeyringError::SignatureMismatch)
+    }
+
+    /// Returns the tag binding a puzzle signed with key `key_id` to `context`, a keyed hash that
+    /// can not be computed without the key. Tags are never zero, which marks unbound puzzles.
+    pub fn context_tag(&self, key_id: u32, context: &[u8]) -> Result<[u8; 4], KeyringError> {
+        let mut macer = self.macer(key_id)?;
+        // Separates tags from signatures made with the same key.
+        macer.update(b"fcaptcha-context");
+        macer.update(context);
+        let mut tag = [0; 4];
+        tag.copy_from_slice(&macer.finalize().into_bytes()[..4]);
+        if tag == [0; 4] {
+            tag[3] = 1;
+        }
+        Ok(tag)
+    }
+
+    fn macer(&self, key_id: u32) -> Result<HmacSha256, KeyringError> {
+        let secret_key = self
+            .keys
+            .get(&key_id)
+            .ok_or(KeyringError::UnknownKey(key_id))?;
+        Ok(HmacSha256::new_from_slice(secret_key)?)
+    }
+}
+
+/// Parses keys only accepted for verification given as `<id>:<key>,...`. None of them may use the
+/// id of the active key.
+pub(crate) fn parse_secret_keys(
+    active_key_id: u32,
+    secret_keys: &str,
+) -> Result<Vec<(u32, &str)>, KeyringError> {
+    secret_keys
+        .split(',')
+        .filter(|entry| !entry.is_empty())
+        .map(|entry| {
+            let (key_id, secret_key) = entry
+                .split_once(':')
+                .and_then(|(key_id, secret_key)| Some((key_id.parse().ok()?, secret_key)))
+                .ok_or_else(|| KeyringError::ConfigMalformed(format!("{:?}", entry)))?;
+            if key_id == active_key_id {
+                return Err(KeyringError::ConfigMalformed(format!(
+                    "key id {} is used by the active key",
+                    key_id
+                )));
+            }
+            Ok((key_id, secret_key))
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_sign_and_verify_after_rotation() -> Result<(), KeyringError> {
+        let mut keyring = Keyring::new(1, "OLD-KEY".as_bytes());
+        let mut old_puzzle = Puzzle::default();
+        let old_signature = keyring.sign(&mut old_puzzle)?;
+        assert_eq!(old_puzzle.key_id, 1);
+
+        keyring = keyring.with_verification_key(2, "NEW-KEY".as_bytes());
+        keyring.activate(2)?;
+        let mut new_puzzle = Puzzle::default();
+        let new_signature = keyring.sign(&mut new_puzzle)?;
+        assert_eq!(new_puzzle.key_id, 2);
+
+        keyring.verify(&old_puzzle, &old_signature)?;
+        keyring.verify(&new_puzzle, &new_signature)?;
+        assert_eq!(
+            keyring.verify(&new_puzzle, &old_signature),
+            Err(KeyringError::SignatureMismatch)
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_retire() -> Result<(), KeyringError> {
+        let mut keyring =
+            Keyring::new(2, "NEW-KEY".as_bytes()).with_verification_key(1, "OLD-KEY".as_bytes());
+        let mut puzzle = Puzzle {
+            key_id: 1,
+            ..Puzzle::default()
+        };
+        let signature = Keyring::new(1, "OLD-KEY".as_bytes()).sign(&mut puzzle)?;
+        keyring.verify(&puzzle, &signature)?;
+
+        assert!(!keyring.retire(2));
+        assert!(keyring.retire(1));
+        assert_eq!(
+            keyring.verify(&puzzle, &signature),
+            Err(KeyringError::UnknownKey(1))
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_context_tag() -> Result<(), KeyringError> {
+        let keyring = Keyring::new(1, "KEY".as_bytes());
+        let tag = keyring.context_tag(1, "192.0.2.1".as_bytes())?;
+        assert_eq!(tag, keyring.context_tag(1, "192.0.2.1".as_bytes())?);
+        assert_ne!(tag, keyring.context_tag(1, "192.0.2.2".as_bytes())?);
+        assert_ne!(
+            tag,
+            Keyring::new(1, "OTHER-KEY".as_bytes()).context_tag(1, "192.0.2.1".as_bytes())?
+        );
+        assert_eq!(
+            keyring.context_tag(2, "192.0.2.1".as_bytes()),
+            Err(KeyringError::UnknownKey(2))
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_debug_hides_keys() {
+        let keyring = Keyring::new(0, "SECRET".as_bytes());
+        assert_eq!(
+            format!("{:?}", keyring),
+            "Keyring { active_key_id: 0, key_ids: [0] }"
+        );
+    }
+}
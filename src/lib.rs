@@ -15,15 +15,40 @@ extern crate log;
 
 pub use crate::build_puzzle::{build_puzzle, build_puzzle_with};
 pub use crate::config::get;
+pub use crate::engine::{Fcaptcha, FcaptchaConfig, ReloadableFcaptcha};
+pub use crate::keyring::Keyring;
+pub use crate::puzzle::{Puzzle, PuzzleSolution, SignedPuzzle};
+pub use crate::solve_puzzle::{solve_puzzle, solve_puzzle_with};
+pub use crate::store::{CaptchaStore, FileStore, MemoryStore, ShardedStore};
 pub use crate::util::get_timestamp;
-pub use crate::verify_puzzle_result::{verify_puzzle_result, verify_puzzle_result_with};
+pub use crate::verify_puzzle_result::{verify_puzzle_result, verify_puzzle_result_with, Verdict};
 #[cfg(feature = "web")]
 pub use crate::web::{build_puzzle_service, verify_puzzle_result_service};
 
 /// Implements building puzzles..
 pub mod build_puzzle;
+/// Implements sources of the current time.
+pub mod clock;
 /// Implements configuration of the crate.
 pub mod config;
+/// Implements policies deciding how costly puzzles are.
+pub mod difficulty;
+/// Implements the captcha engine owning its configuration and state.
+pub mod engine;
+/// Implements rules for allowing, throttling and denying networks.
+pub mod ip_rules;
+/// Implements management of the secret keys used for signing puzzles.
+pub mod keyring;
+/// Implements aggregating IP addresses to network prefixes.
+pub mod prefix;
+/// Implements the puzzle and solution formats.
+pub mod puzzle;
+/// Implements solving puzzles.
+pub mod solve_puzzle;
+/// Implements storage of access counters and used puzzles.
+pub mod store;
+/// Implements serving multiple sites with their own keys and options.
+pub mod tenant;
 /// Implements utility functionality.
 pub mod util;
 /// Implements verifying puzzle results.
@@ -1,23 +1,192 @@
 use actix_cors::Cors;
-use actix_web::{web, App, HttpServer};
-use fcaptcha::config::get;
-use fcaptcha::web::{build_puzzle_service, verify_puzzle_result_service};
+#[cfg(unix)]
+use actix_web::rt::signal::unix::{signal, SignalKind};
+use actix_web::{rt, web, App, HttpServer};
+use fcaptcha::config::{config_file, Mode, Settings, RESTART_REQUIRED};
+use fcaptcha::web::{
+    build_puzzle_service, legacy_verify_puzzle_result_service, verify_puzzle_result_service,
+};
+use fcaptcha::{Fcaptcha, ReloadableFcaptcha};
+use log::{error, info, warn};
+use std::env;
+use std::fs;
+use std::process::ExitCode;
+use std::sync::{Arc, Mutex, PoisonError};
+use std::thread;
+use std::time::Duration;
 
-#[actix_web::main]
-async fn main() -> std::io::Result<()> {
+/// Interval the configuration file is checked for changes in.
+const WATCH_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Settings that decide whether the keys in use are checked by [Settings::check_keys].
+const KEY_SETTINGS: &[&str] = &["mode", "secret_key", "secret_keys", "api_key", "tenants"];
+
+const USAGE: &str = "Usage: fcaptcha-server [--check-config]
+
+Serves puzzles and verifies solutions, configured by the file given by FCAPTCHA_CONFIG_FILE and
+the FCAPTCHA_* environment variables. The configuration is reloaded when the file changes or on
+SIGHUP.
+
+Options:
+  --check-config  Validate the configuration and exit
+  -h, --help      Print help";
+
+fn main() -> ExitCode {
     env_logger::init();
-    HttpServer::new(|| {
+    let mut check_config = false;
+    for arg in env::args().skip(1) {
+        match arg.as_str() {
+            "--check-config" => check_config = true,
+            "-h" | "--help" => {
+                println!("{}", USAGE);
+                return ExitCode::SUCCESS;
+            }
+            _ => {
+                eprintln!("Unknown argument {:?}\n\n{}", arg, USAGE);
+                return ExitCode::from(2);
+            }
+        }
+    }
+
+    // Malformed settings and, in production mode, weak keys are reported here instead of at
+    // their first use.
+    let settings = match Settings::from_config() {
+        Ok(settings) => settings,
+        Err(err) => {
+            eprintln!("Error: {}", err);
+            return ExitCode::FAILURE;
+        }
+    };
+    warn_weak_keys(&settings);
+    if check_config {
+        match config_file() {
+            Some(file) => println!("Configuration in {} is valid", file.display()),
+            None => println!("Configuration is valid"),
+        }
+        return ExitCode::SUCCESS;
+    }
+    let fcaptcha = match Fcaptcha::from_settings(&settings) {
+        Ok(fcaptcha) => web::Data::new(ReloadableFcaptcha::new(fcaptcha)),
+        Err(err) => {
+            eprintln!("Error: {}", err);
+            return ExitCode::FAILURE;
+        }
+    };
+
+    match serve(fcaptcha, settings) {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(err) => {
+            eprintln!("Error: {}", err);
+            ExitCode::FAILURE
+        }
+    }
+}
+
+/// Warns about default or short keys, which are only accepted in development mode.
+fn warn_weak_keys(settings: &Settings) {
+    if settings.mode == Mode::Dev {
+        if let Err(err) = settings.check_keys() {
+            warn!("{}, which is only accepted in development mode", err);
+        }
+    }
+}
+
+/// Reloads the configuration and replaces the engine, keeping its store, and logs which settings
+/// changed compared to `settings`.
+fn reload(fcaptcha: &ReloadableFcaptcha, settings: &Mutex<Settings>) {
+    let mut settings = settings.lock().unwrap_or_else(PoisonError::into_inner);
+    let reloaded = match fcaptcha.reload() {
+        Ok(reloaded) => reloaded,
+        Err(err) => {
+            error!(
+                "Reloading configuration failed, keeping previous configuration: {}",
+                err
+            );
+            return;
+        }
+    };
+    let changes = settings.changes(&reloaded);
+    if changes.is_empty() {
+        info!("Reloaded configuration without changes");
+    } else {
+        info!("Reloaded configuration, changed {}", changes.join(", "));
+    }
+    let restart_required: Vec<&str> = changes
+        .iter()
+        .map(String::as_str)
+        .filter(|key| RESTART_REQUIRED.contains(key))
+        .collect();
+    if !restart_required.is_empty() {
+        warn!(
+            "Changes of {} only take effect after a restart",
+            restart_required.join(", ")
+        );
+    }
+    if changes
+        .iter()
+        .any(|key| KEY_SETTINGS.contains(&key.as_str()))
+    {
+        warn_weak_keys(&reloaded);
+    }
+    *settings = reloaded;
+}
+
+#[actix_web::main]
+async fn serve(fcaptcha: web::Data<ReloadableFcaptcha>, settings: Settings) -> std::io::Result<()> {
+    let bind = (settings.bind_address.clone(), settings.bind_port);
+    let settings = Arc::new(Mutex::new(settings));
+    #[cfg(unix)]
+    {
+        let mut hangup = signal(SignalKind::hangup())?;
+        let (fcaptcha, settings) = (fcaptcha.clone(), Arc::clone(&settings));
+        rt::spawn(async move {
+            while hangup.recv().await.is_some() {
+                info!("Reloading configuration on SIGHUP");
+                // Reloading reads files, so it runs on the blocking thread pool.
+                let (fcaptcha, settings) = (fcaptcha.clone(), Arc::clone(&settings));
+                if let Err(err) = web::block(move || reload(&fcaptcha, &settings)).await {
+                    error!("Reloading configuration failed: {}", err);
+                }
+            }
+        });
+    }
+    if let Some(file) = config_file() {
+        // Checking the file and reloading block, so they run on a thread of their own.
+        let (fcaptcha, settings) = (fcaptcha.clone(), Arc::clone(&settings));
+        thread::Builder::new()
+            .name("config-watcher".to_string())
+            .spawn(move || {
+                let modified = || fs::metadata(&file).and_then(|meta| meta.modified()).ok();
+                let mut last_modified = modified();
+                loop {
+                    thread::sleep(WATCH_INTERVAL);
+                    let current = modified();
+                    if current != last_modified {
+                        last_modified = current;
+                        info!("Reloading configuration on change of {}", file.display());
+                        reload(&fcaptcha, &settings);
+                    }
+                }
+            })?;
+    }
+
+    HttpServer::new(move || {
         // TODO: Switch to non-permissive
         let cors = Cors::permissive();
         App::new()
             .wrap(cors)
+            .app_data(fcaptcha.clone())
             .route("/build-puzzle", web::get().to(build_puzzle_service))
             .route(
                 "/verify-puzzle-result",
+                web::post().to(legacy_verify_puzzle_result_service),
+            )
+            .route(
+                "/api/v1/siteverify",
                 web::post().to(verify_puzzle_result_service),
             )
     })
-    .bind((get::<String>("BIND_ADDRESS"), get::<u16>("BIND_PORT")))?
+    .bind(bind)?
     .run()
     .await
 }
@@ -0,0 +1,158 @@
+use crate::config::Settings;
+use displaydoc::Display;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use thiserror::Error;
+
+/// Describes an error that occurred during setting up network prefixes.
+#[derive(Display, Error, Debug, PartialEq)]
+pub enum PrefixError {
+    /// Prefix configuration malformed: {0}
+    ConfigMalformed(String),
+}
+
+/// The network prefixes accesses are counted for. Clients sharing a network, e.g. the addresses of
+/// an IPv6 /64 or behind a carrier-grade NAT, share their accesses. Accesses are counted for every
+/// prefix length given for the address family, so that e.g. both a /64 and its /56 are accounted.
+///
+/// # Examples
+///
+/// ```
+/// use fcaptcha::prefix::AccessPrefixes;
+///
+/// let prefixes = AccessPrefixes::new(vec![24], vec![64, 56]).unwrap();
+/// assert_eq!(prefixes.keys("192.168.1.17"), ["192.168.1.0/24"]);
+/// assert_eq!(
+///     prefixes.keys("2001:db8:1:2:3:4:5:6"),
+///     ["2001:db8:1:2::/64", "2001:db8:1::/56"]
+/// );
+/// ```
+#[derive(Clone, Debug, PartialEq)]
+pub struct AccessPrefixes {
+    v4: Vec<u8>,
+    v6: Vec<u8>,
+}
+
+impl Default for AccessPrefixes {
+    fn default() -> Self {
+        AccessPrefixes {
+            v4: vec![32],
+            v6: vec![64],
+        }
+    }
+}
+
+impl AccessPrefixes {
+    /// Creates prefixes with the lengths `v4` for IPv4 and `v6` for IPv6 addresses. Fails if a list
+    /// is empty or a length exceeds the address length.
+    pub fn new(v4: Vec<u8>, v6: Vec<u8>) -> Result<AccessPrefixes, PrefixError> {
+        for (lengths, max_length) in [(&v4, 32), (&v6, 128)] {
+            if lengths.is_empty() || lengths.iter().any(|length| *length > max_length) {
+                return Err(PrefixError::ConfigMalformed(format!(
+                    "prefix lengths {:?} not within 0 to {}",
+                    lengths, max_length
+                )));
+            }
+        }
+        Ok(AccessPrefixes { v4, v6 })
+    }
+
+    /// Reads the prefix lengths from `access_prefixes_v4` and `access_prefixes_v6` of `settings`,
+    /// each a comma separated list.
+    pub fn from_settings(settings: &Settings) -> Result<AccessPrefixes, PrefixError> {
+        let parse = |lengths: &str| {
+            lengths
+                .split(',')
+                .map(|length| length.trim().parse())
+                .collect::<Result<Vec<u8>, _>>()
+                .map_err(|_| PrefixError::ConfigMalformed(format!("{:?}", lengths)))
+        };
+        AccessPrefixes::new(
+            parse(&settings.access_prefixes_v4)?,
+            parse(&settings.access_prefixes_v6)?,
+        )
+    }
+
+    /// Returns the keys accesses from `ip_address` are counted for, one per prefix length.
+    /// Addresses may carry a port. Addresses that can not be parsed are used as the only key.
+    pub fn keys(&self, ip_address: &str) -> Vec<String> {
+        let Some(addr) = parse_address(ip_address) else {
+            return vec![ip_address.to_string()];
+        };
+        let lengths = match addr {
+            IpAddr::V4(_) => &self.v4,
+            IpAddr::V6(_) => &self.v6,
+        };
+        lengths
+            .iter()
+            .map(|length| format!("{}/{}", network(addr, *length), length))
+            .collect()
+    }
+}
+
+/// Parses an IP address that may carry a port. IPv4-mapped IPv6 addresses are returned as IPv4.
+pub(crate) fn parse_address(ip_address: &str) -> Option<IpAddr> {
+    let addr = ip_address
+        .parse::<IpAddr>()
+        .or_else(|_| ip_address.parse::<SocketAddr>().map(|addr| addr.ip()))
+        .ok()?;
+    match addr {
+        IpAddr::V6(v6) => Some(v6.to_ipv4_mapped().map_or(addr, IpAddr::V4)),
+        IpAddr::V4(_) => Some(addr),
+    }
+}
+
+/// Returns the network of `addr` with a prefix of `length` bits.
+pub(crate) fn network(addr: IpAddr, length: u8) -> IpAddr {
+    match addr {
+        IpAddr::V4(addr) => {
+            let mask = u32::MAX.checked_shl(32 - u32::from(length)).unwrap_or(0);
+            IpAddr::V4(Ipv4Addr::from(u32::from(addr) & mask))
+        }
+        IpAddr::V6(addr) => {
+            let mask = u128::MAX.checked_shl(128 - u32::from(length)).unwrap_or(0);
+            IpAddr::V6(Ipv6Addr::from(u128::from(addr) & mask))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_keys() -> Result<(), PrefixError> {
+        let prefixes = AccessPrefixes::new(vec![32, 24, 0], vec![128, 48])?;
+        assert_eq!(
+            prefixes.keys("10.1.2.3"),
+            ["10.1.2.3/32", "10.1.2.0/24", "0.0.0.0/0"]
+        );
+        assert_eq!(
+            prefixes.keys("10.1.2.3:8080"),
+            ["10.1.2.3/32", "10.1.2.0/24", "0.0.0.0/0"]
+        );
+        assert_eq!(
+            prefixes.keys("::ffff:10.1.2.3"),
+            ["10.1.2.3/32", "10.1.2.0/24", "0.0.0.0/0"]
+        );
+        assert_eq!(
+            prefixes.keys("[2001:db8:aa:bb::1]:443"),
+            ["2001:db8:aa:bb::1/128", "2001:db8:aa::/48"]
+        );
+        assert_eq!(prefixes.keys("unknown"), ["unknown"]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_default_aggregates_ipv6_networks() {
+        let prefixes = AccessPrefixes::default();
+        assert_eq!(prefixes.keys("2001:db8::1"), prefixes.keys("2001:db8::2"));
+        assert_ne!(prefixes.keys("192.168.0.1"), prefixes.keys("192.168.0.2"));
+    }
+
+    #[test]
+    fn test_new_errors() {
+        assert!(AccessPrefixes::new(vec![33], vec![64]).is_err());
+        assert!(AccessPrefixes::new(vec![32], vec![129]).is_err());
+        assert!(AccessPrefixes::new(vec![], vec![64]).is_err());
+    }
+}
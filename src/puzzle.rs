@@ -0,0 +1,470 @@
+use base64::DecodeError;
+use base64::{engine::general_purpose, Engine as _};
+use displaydoc::Display;
+use hex::FromHexError;
+use serde::{de, Deserialize, Deserializer, Serialize, Serializer};
+use std::fmt;
+use std::str::FromStr;
+use thiserror::Error;
+
+/// Length of a binary puzzle in bytes.
+pub const PUZZLE_LEN_BYTE: usize = 32;
+/// Length of a single solution in bytes.
+pub const SOLUTION_LEN_BYTE: usize = 8;
+
+const SOLUTION_PARTS_COUNT: usize = 4;
+
+/// `2^(63 + 0.999875 - j / 8)` rounded down for `j` in `0..8`, the fractional part of the
+/// threshold exponent for difficulties `8 * k + j`.
+const THRESHOLD_MANTISSAS: [u64; 8] = [
+    0xfffa526d8fd7b11b,
+    0xeabb91f96b67794f,
+    0xd740367d2429e772,
+    0xc562c935dff04607,
+    0xb500ef5f555f04b6,
+    0xa5fb282354e007d5,
+    0x98349004fc2248c1,
+    0x8b92a95314812971,
+];
+
+/// Returns the threshold the leading four bytes of a solution hash, read as little endian integer,
+/// have to be below for `difficulty`. Equals `floor(2^((255.999 - difficulty) / 8))` as computed by
+/// the FriendlyCaptcha widget, but uses integer arithmetic only so that solvers and the server
+/// agree on every platform.
+///
+/// # Examples
+///
+/// ```
+/// use fcaptcha::puzzle::difficulty_threshold;
+///
+/// assert_eq!(difficulty_threshold(0), 4294595181);
+/// assert_eq!(difficulty_threshold(122), 110208);
+/// assert_eq!(difficulty_threshold(255), 1);
+/// ```
+pub fn difficulty_threshold(difficulty: u8) -> u32 {
+    // With difficulty = 8 * k + j the threshold is 2^(31 - k) * 2^(0.999875 - j / 8). Shifting
+    // the rounded down mantissa rounds down the exact product.
+    let mantissa = THRESHOLD_MANTISSAS[usize::from(difficulty % 8)];
+    (mantissa >> (32 + u32::from(difficulty / 8))) as u32
+}
+
+/// Describes an error that occurred during parsing a puzzle or a solution.
+#[derive(Display, Error, Debug, PartialEq)]
+pub enum ParsePuzzleError {
+    /// Input malformed
+    InputMalformed,
+    /// Puzzle has an invalid length.
+    InvalidLength,
+    /// Decoding hex failed.
+    DecodeHex(#[from] FromHexError),
+    /// Decoding base64 failed.
+    DecodeBase64(#[from] DecodeError),
+}
+
+/// A puzzle as it is sent to the client.
+///
+/// # Examples
+///
+/// ```
+/// use fcaptcha::Puzzle;
+///
+/// let puzzle: Puzzle = "ZPBMmAAAAAEAAAABAQwzegAAAAAAAAAAESIzRFVmd4g=".parse().unwrap();
+/// assert_eq!(puzzle.timestamp, 1693469848);
+/// assert_eq!(puzzle.difficulty, 122);
+/// assert_eq!(puzzle.to_string(), "ZPBMmAAAAAEAAAABAQwzegAAAAAAAAAAESIzRFVmd4g=");
+/// ```
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct Puzzle {
+    /// Time the puzzle was built, in seconds since the Unix epoch.
+    pub timestamp: u32,
+    /// Identifies the account the puzzle is issued for.
+    pub account_id: u32,
+    /// Identifies the app the puzzle is issued for.
+    pub app_id: u32,
+    /// Version of the puzzle format.
+    pub version: u8,
+    /// Time after which the puzzle expires, in units of 5 minutes. Zero disables expiry.
+    pub expiry: u8,
+    /// Number of solutions that have to be found.
+    pub solution_count: u8,
+    /// Difficulty of finding a single solution.
+    pub difficulty: u8,
+    /// Identifies the key the puzzle is signed with.
+    pub key_id: u32,
+    /// Keyed hash of the context, e.g. the client IP address, the puzzle is bound to. Zero if it
+    /// is not bound to a context.
+    pub context_tag: [u8; 4],
+    /// Random value making the puzzle unique.
+    pub nonce: u64,
+}
+
+impl Puzzle {
+    /// Returns the binary representation of the puzzle.
+    pub fn to_bytes(&self) -> [u8; PUZZLE_LEN_BYTE] {
+        let mut bytes = [0; PUZZLE_LEN_BYTE];
+        bytes[0..][..4].copy_from_slice(&self.timestamp.to_be_bytes());
+        bytes[4..][..4].copy_from_slice(&self.account_id.to_be_bytes());
+        bytes[8..][..4].copy_from_slice(&self.app_id.to_be_bytes());
+        bytes[12] = self.version;
+        bytes[13] = self.expiry;
+        bytes[14] = self.solution_count;
+        bytes[15] = self.difficulty;
+        bytes[16..][..4].copy_from_slice(&self.key_id.to_be_bytes());
+        bytes[20..][..4].copy_from_slice(&self.context_tag);
+        bytes[24..][..8].copy_from_slice(&self.nonce.to_be_bytes());
+        bytes
+    }
+
+    /// Parses the binary representation of a puzzle.
+    pub fn from_bytes(bytes: &[u8]) -> Result<Puzzle, ParsePuzzleError> {
+        let bytes: &[u8; PUZZLE_LEN_BYTE] = bytes
+            .try_into()
+            .map_err(|_| ParsePuzzleError::InvalidLength)?;
+        let read_u32 = |offset: usize| {
+            u32::from_be_bytes([
+                bytes[offset],
+                bytes[offset + 1],
+                bytes[offset + 2],
+                bytes[offset + 3],
+            ])
+        };
+        let mut context_tag = [0; 4];
+        context_tag.copy_from_slice(&bytes[20..24]);
+        let mut nonce = [0; 8];
+        nonce.copy_from_slice(&bytes[24..32]);
+
+        Ok(Puzzle {
+            timestamp: read_u32(0),
+            account_id: read_u32(4),
+            app_id: read_u32(8),
+            version: bytes[12],
+            expiry: bytes[13],
+            solution_count: bytes[14],
+            difficulty: bytes[15],
+            key_id: read_u32(16),
+            context_tag,
+            nonce: u64::from_be_bytes(nonce),
+        })
+    }
+
+    /// Returns the time after which the puzzle expires in seconds, or `None` if it does not.
+    pub fn expiry_secs(&self) -> Option<u64> {
+        (self.expiry != 0).then(|| u64::from(self.expiry) * 300)
+    }
+}
+
+impl fmt::Display for Puzzle {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&general_purpose::STANDARD.encode(self.to_bytes()))
+    }
+}
+
+impl FromStr for Puzzle {
+    type Err = ParsePuzzleError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Puzzle::from_bytes(&general_purpose::STANDARD.decode(s)?)
+    }
+}
+
+/// A puzzle together with its signature, in the form `<hex signature>.<base64 puzzle>`.
+///
+/// # Examples
+///
+/// ```
+/// use fcaptcha::SignedPuzzle;
+///
+/// let signed_puzzle: SignedPuzzle =
+///     "86505156a95e735652e7fd6d9eaaa9e5f839fc0a886268bebf5b8d2ad1038df5.\
+///     ZPBMmAAAAAEAAAABAQwzegAAAAAAAAAAESIzRFVmd4g="
+///         .parse()
+///         .unwrap();
+/// assert_eq!(signed_puzzle.puzzle.nonce, 0x1122334455667788);
+/// ```
+#[derive(Clone, Debug, PartialEq)]
+pub struct SignedPuzzle {
+    /// HMAC of the binary puzzle.
+    pub signature: Vec<u8>,
+    /// The signed puzzle.
+    pub puzzle: Puzzle,
+}
+
+impl fmt::Display for SignedPuzzle {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}.{}", hex::encode(&self.signature), self.puzzle)
+    }
+}
+
+impl FromStr for SignedPuzzle {
+    type Err = ParsePuzzleError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (signature, puzzle) = s.split_once('.').ok_or(ParsePuzzleError::InputMalformed)?;
+        Ok(SignedPuzzle {
+            signature: hex::decode(signature)?,
+            puzzle: puzzle.parse()?,
+        })
+    }
+}
+
+/// A solved puzzle as it is submitted by the client, in the form
+/// `<hex signature>.<base64 puzzle>.<base64 solutions>.<base64 diagnostics>`.
+///
+/// # Examples
+///
+/// ```
+/// use fcaptcha::PuzzleSolution;
+///
+/// let solution: PuzzleSolution =
+///     "86505156a95e735652e7fd6d9eaaa9e5f839fc0a886268bebf5b8d2ad1038df5.\
+///     ZPBMmAAAAAEAAAABAQwzegAAAAAAAAAAESIzRFVmd4g=.AAAAAAAAAAA=.AgAA"
+///         .parse()
+///         .unwrap();
+/// assert_eq!(solution.solutions, [0; 8]);
+/// assert_eq!(solution.diagnostics, [2, 0, 0]);
+/// ```
+#[derive(Clone, Debug, PartialEq)]
+pub struct PuzzleSolution {
+    /// HMAC of the binary puzzle.
+    pub signature: Vec<u8>,
+    /// The solved puzzle.
+    pub puzzle: Puzzle,
+    /// The concatenated solutions, [SOLUTION_LEN_BYTE] bytes each.
+    pub solutions: Vec<u8>,
+    /// Diagnostic information provided by the solver.
+    pub diagnostics: Vec<u8>,
+}
+
+impl PuzzleSolution {
+    /// Returns the signed puzzle the solution belongs to.
+    pub fn signed_puzzle(&self) -> SignedPuzzle {
+        SignedPuzzle {
+            signature: self.signature.clone(),
+            puzzle: self.puzzle.clone(),
+        }
+    }
+}
+
+/// Diagnostic information reported by the solver, in the form `<solver id><u16 BE solve time>`.
+///
+/// # Examples
+///
+/// ```
+/// use fcaptcha::puzzle::Diagnostics;
+///
+/// let diagnostics = Diagnostics::from_bytes(&[2, 0, 12]).unwrap();
+/// assert_eq!(diagnostics.solver_id, 2);
+/// assert_eq!(diagnostics.solve_time_secs, 12);
+/// ```
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Diagnostics {
+    /// Identifies the solver implementation, `1` for JavaScript, `2` for WebAssembly and `3` for
+    /// the native solver of this crate.
+    pub solver_id: u8,
+    /// Time the client needed to find all solutions, in seconds.
+    pub solve_time_secs: u16,
+}
+
+impl Diagnostics {
+    /// Parses the binary representation of diagnostics, returns `None` if it is malformed.
+    pub fn from_bytes(bytes: &[u8]) -> Option<Diagnostics> {
+        match *bytes {
+            [solver_id, time_high, time_low] => Some(Diagnostics {
+                solver_id,
+                solve_time_secs: u16::from_be_bytes([time_high, time_low]),
+            }),
+            _ => None,
+        }
+    }
+
+    /// Returns the binary representation of the diagnostics.
+    pub fn to_bytes(&self) -> [u8; 3] {
+        let [time_high, time_low] = self.solve_time_secs.to_be_bytes();
+        [self.solver_id, time_high, time_low]
+    }
+}
+
+impl fmt::Display for PuzzleSolution {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{}.{}.{}.{}",
+            hex::encode(&self.signature),
+            self.puzzle,
+            general_purpose::STANDARD.encode(&self.solutions),
+            general_purpose::STANDARD.encode(&self.diagnostics)
+        )
+    }
+}
+
+impl FromStr for PuzzleSolution {
+    type Err = ParsePuzzleError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let parts: Vec<&str> = s.splitn(SOLUTION_PARTS_COUNT, '.').collect();
+        let [signature, puzzle, solutions, diagnostics] = parts[..] else {
+            return Err(ParsePuzzleError::InputMalformed);
+        };
+        Ok(PuzzleSolution {
+            signature: hex::decode(signature)?,
+            puzzle: puzzle.parse()?,
+            solutions: general_purpose::STANDARD.decode(solutions)?,
+            diagnostics: general_purpose::STANDARD.decode(diagnostics)?,
+        })
+    }
+}
+
+macro_rules! impl_serde_via_str {
+    ($type:ty) => {
+        impl Serialize for $type {
+            fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+                serializer.collect_str(self)
+            }
+        }
+
+        impl<'de> Deserialize<'de> for $type {
+            fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+                String::deserialize(deserializer)?
+                    .parse()
+                    .map_err(de::Error::custom)
+            }
+        }
+    };
+}
+
+impl_serde_via_str!(SignedPuzzle);
+impl_serde_via_str!(PuzzleSolution);
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Thresholds of all difficulties, computed with arbitrary precision.
+    const THRESHOLDS: [u32; 256] = [
+        4294595181, 3938161145, 3611309693, 3311585589, 3036737375, 2784700451, 2553581572,
+        2341644627, 2147297590, 1969080572, 1805654846, 1655792794, 1518368687, 1392350225,
+        1276790786, 1170822313, 1073648795, 984540286, 902827423, 827896397, 759184343, 696175112,
+        638395393, 585411156, 536824397, 492270143, 451413711, 413948198, 379592171, 348087556,
+        319197696, 292705578, 268412198, 246135071, 225706855, 206974099, 189796085, 174043778,
+        159598848, 146352789, 134206099, 123067535, 112853427, 103487049, 94898042, 87021889,
+        79799424, 73176394, 67103049, 61533767, 56426713, 51743524, 47449021, 43510944, 39899712,
+        36588197, 33551524, 30766883, 28213356, 25871762, 23724510, 21755472, 19949856, 18294098,
+        16775762, 15383441, 14106678, 12935881, 11862255, 10877736, 9974928, 9147049, 8387881,
+        7691720, 7053339, 6467940, 5931127, 5438868, 4987464, 4573524, 4193940, 3845860, 3526669,
+        3233970, 2965563, 2719434, 2493732, 2286762, 2096970, 1922930, 1763334, 1616985, 1482781,
+        1359717, 1246866, 1143381, 1048485, 961465, 881667, 808492, 741390, 679858, 623433, 571690,
+        524242, 480732, 440833, 404246, 370695, 339929, 311716, 285845, 262121, 240366, 220416,
+        202123, 185347, 169964, 155858, 142922, 131060, 120183, 110208, 101061, 92673, 84982,
+        77929, 71461, 65530, 60091, 55104, 50530, 46336, 42491, 38964, 35730, 32765, 30045, 27552,
+        25265, 23168, 21245, 19482, 17865, 16382, 15022, 13776, 12632, 11584, 10622, 9741, 8932,
+        8191, 7511, 6888, 6316, 5792, 5311, 4870, 4466, 4095, 3755, 3444, 3158, 2896, 2655, 2435,
+        2233, 2047, 1877, 1722, 1579, 1448, 1327, 1217, 1116, 1023, 938, 861, 789, 724, 663, 608,
+        558, 511, 469, 430, 394, 362, 331, 304, 279, 255, 234, 215, 197, 181, 165, 152, 139, 127,
+        117, 107, 98, 90, 82, 76, 69, 63, 58, 53, 49, 45, 41, 38, 34, 31, 29, 26, 24, 22, 20, 19,
+        17, 15, 14, 13, 12, 11, 10, 9, 8, 7, 7, 6, 6, 5, 5, 4, 4, 3, 3, 3, 3, 2, 2, 2, 2, 1, 1, 1,
+        1, 1, 1, 1, 1,
+    ];
+
+    fn puzzle() -> Puzzle {
+        Puzzle {
+            timestamp: 1693469848,
+            account_id: 1,
+            app_id: 2,
+            version: 1,
+            expiry: 12,
+            solution_count: 51,
+            difficulty: 122,
+            key_id: 7,
+            context_tag: [0, 0, 0, 9],
+            nonce: 0x1122334455667788,
+        }
+    }
+
+    #[test]
+    fn test_puzzle_bytes_round_trip() -> Result<(), ParsePuzzleError> {
+        let puzzle = puzzle();
+        assert_eq!(Puzzle::from_bytes(&puzzle.to_bytes())?, puzzle);
+        assert_eq!(
+            Puzzle::from_bytes(&[0; 31]),
+            Err(ParsePuzzleError::InvalidLength)
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_diagnostics_from_bytes() {
+        assert_eq!(
+            Diagnostics::from_bytes(&[1, 1, 2]),
+            Some(Diagnostics {
+                solver_id: 1,
+                solve_time_secs: 258
+            })
+        );
+        assert_eq!(Diagnostics::from_bytes(&[]), None);
+        assert_eq!(Diagnostics::from_bytes(&[1, 1, 2, 3]), None);
+        assert_eq!(
+            Diagnostics::from_bytes(&[3, 1, 2]).unwrap().to_bytes(),
+            [3, 1, 2]
+        );
+    }
+
+    #[test]
+    fn test_puzzle_solution_round_trip() -> Result<(), ParsePuzzleError> {
+        let solution = PuzzleSolution {
+            signature: vec![0xab; 32],
+            puzzle: puzzle(),
+            solutions: vec![1; 16],
+            diagnostics: vec![2, 0, 0],
+        };
+        let parsed: PuzzleSolution = solution.to_string().parse()?;
+        assert_eq!(parsed, solution);
+        assert_eq!(
+            parsed.signed_puzzle().to_string().parse::<SignedPuzzle>()?,
+            parsed.signed_puzzle()
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_puzzle_solution_malformed() {
+        assert_eq!(
+            "abcd.ZPBMmAAAAAEAAAABAQwzegAAAAAAAAAAESIzRFVmd4g=.AAAA".parse::<PuzzleSolution>(),
+            Err(ParsePuzzleError::InputMalformed)
+        );
+        assert_eq!(
+            "abcd.AAAA.AAAA.AAAA".parse::<PuzzleSolution>(),
+            Err(ParsePuzzleError::InvalidLength)
+        );
+        assert!(matches!(
+            "xyz.AAAA.AAAA.AAAA".parse::<PuzzleSolution>(),
+            Err(ParsePuzzleError::DecodeHex(_))
+        ));
+    }
+
+    #[test]
+    fn test_serde() {
+        let solution = PuzzleSolution {
+            signature: vec![0xab; 32],
+            puzzle: puzzle(),
+            solutions: vec![1; 8],
+            diagnostics: vec![2, 0, 0],
+        };
+        let json = serde_json::to_string(&solution).unwrap();
+        assert_eq!(json, format!("\"{}\"", solution));
+        assert_eq!(
+            serde_json::from_str::<PuzzleSolution>(&json).unwrap(),
+            solution
+        );
+    }
+
+    #[test]
+    fn test_difficulty_threshold() {
+        for difficulty in 0..=u8::MAX {
+            let threshold = difficulty_threshold(difficulty);
+            assert_eq!(threshold, THRESHOLDS[usize::from(difficulty)]);
+            // The FriendlyCaptcha widget computes `Math.pow(2, (255.999 - d) / 8.0) >>> 0`.
+            let reference = 2_f64.powf((255.999 - f64::from(difficulty)) / 8.0) as u32;
+            assert_eq!(threshold, reference, "difficulty {}", difficulty);
+        }
+    }
+}
@@ -0,0 +1,392 @@
+use crate::puzzle::{
+    difficulty_threshold, Diagnostics, ParsePuzzleError, Puzzle, PuzzleSolution, SignedPuzzle,
+    PUZZLE_LEN_BYTE, SOLUTION_LEN_BYTE,
+};
+use blake2::{digest::consts::U32, Blake2b, Digest};
+use displaydoc::Display;
+use std::fmt;
+use std::num::NonZeroUsize;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, PoisonError};
+use std::thread;
+use std::time::Instant;
+use thiserror::Error;
+
+/// Identifies this solver in the diagnostics of its solutions.
+pub const SOLVER_ID: u8 = 3;
+
+/// Number of hashes computed between checks for cancellation.
+const CANCEL_CHECK_INTERVAL: u32 = 1 << 12;
+
+/// Describes an error that occurred during solving a puzzle.
+#[derive(Display, Error, Debug, PartialEq)]
+pub enum SolvePuzzleError {
+    /// Parsing the puzzle failed: {0}
+    Parse(#[from] ParsePuzzleError),
+    /// Solving was cancelled.
+    Cancelled,
+    /// No solution exists for solution {0}.
+    Unsolvable(u8),
+}
+
+/// Cancels solving a puzzle from another thread. Clones cancel the same solving.
+///
+/// # Examples
+///
+/// ```
+/// use fcaptcha::solve_puzzle::{CancelToken, SolveOptions, SolvePuzzleError};
+///
+/// let token = CancelToken::new();
+/// let options = SolveOptions::default().with_cancel_token(token.clone());
+/// token.cancel();
+/// let puzzle = "86505156a95e735652e7fd6d9eaaa9e5f839fc0a886268bebf5b8d2ad1038df5.\
+/// ZPBMmAAAAAEAAAABAQwzegAAAAAAAAAAESIzRFVmd4g=";
+/// assert_eq!(
+///     fcaptcha::solve_puzzle_with(puzzle, &options),
+///     Err(SolvePuzzleError::Cancelled)
+/// );
+/// ```
+#[derive(Clone, Debug, Default)]
+pub struct CancelToken(Arc<AtomicBool>);
+
+impl CancelToken {
+    /// Creates a token that is not cancelled.
+    pub fn new() -> CancelToken {
+        CancelToken::default()
+    }
+
+    /// Cancels solving, which then fails with [SolvePuzzleError::Cancelled].
+    pub fn cancel(&self) {
+        self.0.store(true, Ordering::Relaxed);
+    }
+
+    /// Returns `true` if solving was cancelled.
+    pub fn is_cancelled(&self) -> bool {
+        self.0.load(Ordering::Relaxed)
+    }
+}
+
+/// The progress of solving a puzzle, reported after each solution found.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Progress {
+    /// Number of solutions found so far.
+    pub solutions_found: u8,
+    /// Number of solutions that have to be found.
+    pub solution_count: u8,
+    /// Number of hashes computed for the solutions found so far.
+    pub hashes: u64,
+}
+
+type ProgressCallback = Arc<dyn Fn(Progress) + Send + Sync>;
+
+/// Describes how a puzzle is solved.
+///
+/// # Examples
+///
+/// ```
+/// use fcaptcha::solve_puzzle::SolveOptions;
+///
+/// let options = SolveOptions::default()
+///     .with_threads(2)
+///     .with_progress(|progress| println!("{:?}", progress));
+/// assert_eq!(options.threads, 2);
+/// ```
+#[derive(Clone)]
+pub struct SolveOptions {
+    /// Number of threads searching for solutions, at least one.
+    pub threads: usize,
+    /// Token cancelling the solving.
+    pub cancel_token: CancelToken,
+    progress: Option<ProgressCallback>,
+}
+
+impl fmt::Debug for SolveOptions {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("SolveOptions")
+            .field("threads", &self.threads)
+            .field("cancel_token", &self.cancel_token)
+            .finish_non_exhaustive()
+    }
+}
+
+impl Default for SolveOptions {
+    /// Uses as many threads as the system offers.
+    fn default() -> Self {
+        SolveOptions {
+            threads: thread::available_parallelism().map_or(1, NonZeroUsize::get),
+            cancel_token: CancelToken::default(),
+            progress: None,
+        }
+    }
+}
+
+impl SolveOptions {
+    /// Sets the number of threads searching for solutions. Zero is treated as one.
+    pub fn with_threads(mut self, threads: usize) -> SolveOptions {
+        self.threads = threads.max(1);
+        self
+    }
+
+    /// Sets the token cancelling the solving.
+    pub fn with_cancel_token(mut self, cancel_token: CancelToken) -> SolveOptions {
+        self.cancel_token = cancel_token;
+        self
+    }
+
+    /// Sets a function called with the progress after each solution found. It is called from the
+    /// solving threads.
+    pub fn with_progress<F: Fn(Progress) + Send + Sync + 'static>(
+        mut self,
+        progress: F,
+    ) -> SolveOptions {
+        self.progress = Some(Arc::new(progress));
+        self
+    }
+}
+
+/// Solves a puzzle in the form `<hex signature>.<base64 puzzle>` as served by
+/// [build_puzzle](crate::build_puzzle()), using all available threads. Returns the solution in the
+/// form accepted by [verify_puzzle_result](crate::verify_puzzle_result()).
+///
+/// # Examples
+///
+/// ```
+/// use fcaptcha::build_puzzle::PuzzleOptions;
+/// use fcaptcha::difficulty::TierTable;
+/// use fcaptcha::store::MemoryStore;
+/// use fcaptcha::Keyring;
+///
+/// let keyring = Keyring::new(0, "SECRET-KEY".as_bytes());
+/// let tiers: TierTable = "0:4:100".parse().unwrap();
+/// let options = PuzzleOptions::default().with_difficulty_policy(tiers);
+/// let store = MemoryStore::new();
+/// let timestamp = fcaptcha::get_timestamp().unwrap();
+/// let puzzle = fcaptcha::build_puzzle_with(
+///     &store, "127.0.0.1", None, timestamp, 0, &keyring, 1800, &options,
+/// )
+/// .unwrap();
+///
+/// let solution = fcaptcha::solve_puzzle(&puzzle).unwrap();
+/// let verdict = fcaptcha::verify_puzzle_result_with(
+///     &store, &solution, None, timestamp, 3600, &keyring, &options,
+/// );
+/// assert!(verdict.is_ok());
+/// ```
+pub fn solve_puzzle(puzzle: &str) -> Result<String, SolvePuzzleError> {
+    solve_puzzle_with(puzzle, &SolveOptions::default())
+}
+
+/// Solves a puzzle like [solve_puzzle], but with the threads, cancellation and progress reporting
+/// given by `options`.
+pub fn solve_puzzle_with(puzzle: &str, options: &SolveOptions) -> Result<String, SolvePuzzleError> {
+    let SignedPuzzle { signature, puzzle } = puzzle.parse()?;
+    let start = Instant::now();
+    let solutions = find_solutions(&puzzle, options)?;
+    let diagnostics = Diagnostics {
+        solver_id: SOLVER_ID,
+        solve_time_secs: start.elapsed().as_secs().try_into().unwrap_or(u16::MAX),
+    };
+    info!(
+        "Solved puzzle with difficulty: {:?}, solution count: {:?}, diagnostics: {:?}",
+        puzzle.difficulty, puzzle.solution_count, diagnostics
+    );
+
+    Ok(PuzzleSolution {
+        signature,
+        puzzle,
+        solutions,
+        diagnostics: diagnostics.to_bytes().to_vec(),
+    }
+    .to_string())
+}
+
+#[derive(Default)]
+struct SolveState {
+    solutions: Vec<u8>,
+    solutions_found: u8,
+    hashes: u64,
+    error: Option<SolvePuzzleError>,
+}
+
+/// Searches the solutions of `puzzle`, distributing them over the threads of `options`.
+fn find_solutions(puzzle: &Puzzle, options: &SolveOptions) -> Result<Vec<u8>, SolvePuzzleError> {
+    let solution_count = puzzle.solution_count;
+    let threshold = difficulty_threshold(puzzle.difficulty);
+    let puzzle_bytes = puzzle.to_bytes();
+    let next_index = AtomicUsize::new(0);
+    // Stops the other threads once one failed, without cancelling the token of the caller.
+    let failed = AtomicBool::new(false);
+    let is_cancelled = || failed.load(Ordering::Relaxed) || options.cancel_token.is_cancelled();
+    let state = Mutex::new(SolveState {
+        solutions: vec![0; usize::from(solution_count) * SOLUTION_LEN_BYTE],
+        ..SolveState::default()
+    });
+
+    thread::scope(|scope| {
+        for _ in 0..options.threads.min(usize::from(solution_count)) {
+            scope.spawn(|| loop {
+                let index = next_index.fetch_add(1, Ordering::Relaxed);
+                let Some(index) = u8::try_from(index).ok().filter(|i| *i < solution_count) else {
+                    break;
+                };
+                let result = find_solution(&puzzle_bytes, index, threshold, &is_cancelled);
+                // Progress is reported under the lock, so that reports are ordered.
+                let mut state = state.lock().unwrap_or_else(PoisonError::into_inner);
+                match result {
+                    Ok((solution, hashes)) => {
+                        let offset = usize::from(index) * SOLUTION_LEN_BYTE;
+                        state.solutions[offset..offset + SOLUTION_LEN_BYTE]
+                            .copy_from_slice(&solution);
+                        state.solutions_found += 1;
+                        state.hashes += hashes;
+                        if let Some(progress) = &options.progress {
+                            progress(Progress {
+                                solutions_found: state.solutions_found,
+                                solution_count,
+                                hashes: state.hashes,
+                            });
+                        }
+                    }
+                    Err(err) => {
+                        failed.store(true, Ordering::Relaxed);
+                        state.error.get_or_insert(err);
+                        break;
+                    }
+                }
+            });
+        }
+    });
+
+    let state = state.into_inner().unwrap_or_else(PoisonError::into_inner);
+    match state.error {
+        Some(err) => Err(err),
+        None => Ok(state.solutions),
+    }
+}
+
+/// Searches the solution with `index`, returning it and the number of hashes computed. A solution
+/// consists of its index and a counter, both as u32 LE, placed at the end of a 128 byte block
+/// starting with the puzzle. It is valid if the first four bytes of the Blake2b hash of the block,
+/// read as u32 LE, are below the threshold.
+fn find_solution(
+    puzzle_bytes: &[u8; PUZZLE_LEN_BYTE],
+    index: u8,
+    threshold: u32,
+    is_cancelled: &dyn Fn() -> bool,
+) -> Result<([u8; SOLUTION_LEN_BYTE], u64), SolvePuzzleError> {
+    type Blake2b256 = Blake2b<U32>;
+
+    let mut block = [0; 128];
+    block[..PUZZLE_LEN_BYTE].copy_from_slice(puzzle_bytes);
+    block[120..124].copy_from_slice(&u32::from(index).to_le_bytes());
+
+    for counter in 0..=u32::MAX {
+        if counter % CANCEL_CHECK_INTERVAL == 0 && is_cancelled() {
+            return Err(SolvePuzzleError::Cancelled);
+        }
+        block[124..128].copy_from_slice(&counter.to_le_bytes());
+        let hash = Blake2b256::digest(block);
+        if u32::from_le_bytes([hash[0], hash[1], hash[2], hash[3]]) < threshold {
+            let mut solution = [0; SOLUTION_LEN_BYTE];
+            solution.copy_from_slice(&block[120..128]);
+            return Ok((solution, u64::from(counter) + 1));
+        }
+    }
+    Err(SolvePuzzleError::Unsolvable(index))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::build_puzzle::PuzzleOptions;
+    use crate::difficulty::TierTable;
+    use crate::keyring::Keyring;
+    use crate::store::MemoryStore;
+    use crate::verify_puzzle_result::verify_puzzle_result_with;
+
+    fn build(tiers: &str) -> String {
+        let options =
+            PuzzleOptions::default().with_difficulty_policy(tiers.parse::<TierTable>().unwrap());
+        crate::build_puzzle_with(
+            &MemoryStore::new(),
+            "127.0.0.1",
+            None,
+            1693469848,
+            rand::random(),
+            &Keyring::new(0, "TEST-KEY".as_bytes()),
+            1800,
+            &options,
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn test_round_trip() -> Result<(), SolvePuzzleError> {
+        let keyring = Keyring::new(0, "TEST-KEY".as_bytes());
+        let puzzle = build("0:16:100");
+        for threads in [1, 3] {
+            let options = SolveOptions::default().with_threads(threads);
+            let solution: PuzzleSolution = solve_puzzle_with(&puzzle, &options)?.parse()?;
+            assert_eq!(solution.solutions.len(), 16 * SOLUTION_LEN_BYTE);
+            assert_eq!(solution.diagnostics[0], SOLVER_ID);
+            for (index, solution) in solution.solutions.chunks(SOLUTION_LEN_BYTE).enumerate() {
+                assert_eq!(solution[..4], (index as u32).to_le_bytes());
+            }
+
+            let verdict = verify_puzzle_result_with(
+                &MemoryStore::new(),
+                &solution.to_string(),
+                None,
+                1693469848,
+                3600,
+                &keyring,
+                &PuzzleOptions::default(),
+            );
+            assert!(verdict.is_ok(), "{:?}", verdict);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_progress() -> Result<(), SolvePuzzleError> {
+        let reports = Arc::new(Mutex::new(Vec::new()));
+        let options = SolveOptions::default().with_threads(2).with_progress({
+            let reports = Arc::clone(&reports);
+            move |progress| reports.lock().unwrap().push(progress)
+        });
+        solve_puzzle_with(&build("0:5:100"), &options)?;
+
+        let reports = reports.lock().unwrap();
+        let found: Vec<u8> = reports.iter().map(|p| p.solutions_found).collect();
+        assert_eq!(found, [1, 2, 3, 4, 5]);
+        assert!(reports.iter().all(|p| p.solution_count == 5));
+        assert!(reports
+            .windows(2)
+            .all(|pair| pair[0].hashes <= pair[1].hashes));
+        Ok(())
+    }
+
+    #[test]
+    fn test_cancel() {
+        let token = CancelToken::new();
+        let options = SolveOptions::default()
+            .with_threads(2)
+            .with_cancel_token(token.clone());
+        // Practically unsolvable, so only the cancellation ends the search.
+        let puzzle = build("0:2:255");
+        let canceller = thread::spawn(move || token.cancel());
+        assert_eq!(
+            solve_puzzle_with(&puzzle, &options),
+            Err(SolvePuzzleError::Cancelled)
+        );
+        canceller.join().unwrap();
+    }
+
+    #[test]
+    fn test_parse_error() {
+        assert!(matches!(
+            solve_puzzle("not-a-puzzle"),
+            Err(SolvePuzzleError::Parse(_))
+        ));
+    }
+}
@@ -0,0 +1,177 @@
+use crate::clock::Clock;
+use crate::config::Settings;
+use displaydoc::Display;
+use std::io;
+use std::sync::{Arc, PoisonError, Weak};
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+use thiserror::Error;
+
+pub use self::file::FileStore;
+pub use self::memory::{MemoryStore, StoreMetrics};
+#[cfg(feature = "redis")]
+pub use self::redis::RedisStore;
+pub use self::sharded::ShardedStore;
+
+mod file;
+mod memory;
+#[cfg(feature = "redis")]
+mod redis;
+mod sharded;
+
+/// Describes an error that occurred during accessing a store.
+#[derive(Display, Error, Debug, PartialEq)]
+pub enum StoreError {
+    /// Data access failed.
+    DataAccess,
+    /// Store backend failed: {0}
+    Backend(String),
+    /// Input/output failed: {0}
+    Io(String),
+    /// Store is full.
+    Full,
+}
+
+impl From<io::Error> for StoreError {
+    fn from(err: io::Error) -> Self {
+        Self::Io(err.to_string())
+    }
+}
+
+impl<T> From<PoisonError<T>> for StoreError {
+    fn from(_err: PoisonError<T>) -> Self {
+        Self::DataAccess
+    }
+}
+
+/// Describes the accesses of a single client.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Access {
+    /// Number of accesses within the access TTL.
+    pub count: u64,
+    /// Timestamp of the last access in seconds since the Unix epoch.
+    pub last_access: u64,
+}
+
+/// Stores the state needed for scaling puzzles and for preventing puzzle reuse.
+///
+/// Implement this trait to plug in a custom storage backend, e.g. one that is shared between
+/// multiple instances.
+pub trait CaptchaStore: Send + Sync {
+    /// Records an access identified by `key` at `timestamp` and returns the updated [Access].
+    /// The access count is restarted if the last access is more than `access_ttl` seconds ago.
+    fn record_access(
+        &self,
+        key: &str,
+        timestamp: u64,
+        access_ttl: u64,
+    ) -> Result<Access, StoreError>;
+
+    /// Marks `puzzle` as used at `timestamp`.
+    /// Returns `false` if the puzzle was already used within the last `puzzle_ttl` seconds.
+    /// Stores with a limited capacity fail with [StoreError::Full] instead of forgetting puzzles
+    /// that were used within the last `puzzle_ttl` seconds.
+    fn mark_puzzle_used(
+        &self,
+        puzzle: &[u8],
+        timestamp: u64,
+        puzzle_ttl: u64,
+    ) -> Result<bool, StoreError>;
+
+    /// Removes all entries that are no longer relevant at `timestamp` and returns their number.
+    /// Stores that expire entries by themselves can rely on the default implementation.
+    fn evict_expired(&self, _timestamp: u64) -> Result<usize, StoreError> {
+        Ok(0)
+    }
+}
+
+impl<S: CaptchaStore + ?Sized> CaptchaStore for Arc<S> {
+    fn record_access(
+        &self,
+        key: &str,
+        timestamp: u64,
+        access_ttl: u64,
+    ) -> Result<Access, StoreError> {
+        (**self).record_access(key, timestamp, access_ttl)
+    }
+
+    fn mark_puzzle_used(
+        &self,
+        puzzle: &[u8],
+        timestamp: u64,
+        puzzle_ttl: u64,
+    ) -> Result<bool, StoreError> {
+        (**self).mark_puzzle_used(puzzle, timestamp, puzzle_ttl)
+    }
+
+    fn evict_expired(&self, timestamp: u64) -> Result<usize, StoreError> {
+        (**self).evict_expired(timestamp)
+    }
+}
+
+/// Creates the store selected by `store_backend` of `settings`: `memory` for a [ShardedStore]
+/// with `store_shards` shards, `file` for a [FileStore] in `store_data_dir` or, with the `redis`
+/// feature, `redis` for a `RedisStore` connecting to `store_redis_url`. The number of entries of
+/// local stores is limited by `store_max_entries`. Persisted entries expired at the time of
+/// `clock` are dropped on opening.
+pub fn from_settings(
+    settings: &Settings,
+    clock: &dyn Clock,
+) -> Result<Arc<dyn CaptchaStore>, StoreError> {
+    let max_entries = settings.store_max_entries;
+    match settings.store_backend.as_str() {
+        "memory" => Ok(Arc::new(ShardedStore::with_max_entries(
+            settings.store_shards,
+            max_entries,
+        ))),
+        "file" => Ok(Arc::new(FileStore::open_with_max_entries(
+            &settings.store_data_dir,
+            max_entries,
+            clock,
+        )?)),
+        #[cfg(feature = "redis")]
+        "redis" => Ok(Arc::new(RedisStore::open(&settings.store_redis_url)?)),
+        backend => Err(StoreError::Backend(format!(
+            "unknown store backend {:?}",
+            backend
+        ))),
+    }
+}
+
+/// Spawns a background thread that calls [CaptchaStore::evict_expired] on `store` every
+/// `interval`, at the time of `clock`. The thread stops once all other references to `store` are
+/// dropped.
+///
+/// # Examples
+///
+/// ```
+/// use fcaptcha::clock::SystemClock;
+/// use fcaptcha::store::{spawn_evictor, MemoryStore};
+/// use std::sync::Arc;
+/// use std::time::Duration;
+///
+/// let store = Arc::new(MemoryStore::new());
+/// spawn_evictor(&store, Duration::from_secs(60), Arc::new(SystemClock));
+/// ```
+pub fn spawn_evictor<S: CaptchaStore + ?Sized + 'static>(
+    store: &Arc<S>,
+    interval: Duration,
+    clock: Arc<dyn Clock>,
+) -> JoinHandle<()> {
+    let store: Weak<S> = Arc::downgrade(store);
+    thread::spawn(move || loop {
+        thread::sleep(interval);
+        let Some(store) = store.upgrade() else {
+            debug!("Store dropped, stopping evictor");
+            return;
+        };
+        let evicted = clock
+            .now()
+            .map_err(|_| StoreError::DataAccess)
+            .and_then(|timestamp| store.evict_expired(timestamp));
+        match evicted {
+            Ok(count) => debug!("Evicted {:?} expired store entries", count),
+            Err(err) => warn!("Evicting expired store entries failed: {:?}", err),
+        }
+    })
+}
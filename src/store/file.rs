@@ -0,0 +1,341 @@
+use super::memory::{access_expiry, used_puzzle_expiry};
+use super::{Access, CaptchaStore, MemoryStore, StoreError, StoreMetrics};
+use crate::clock::Clock;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufRead, BufReader, BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+/// Name of the log file inside the data directory.
+const LOG_FILE_NAME: &str = "store.log";
+/// Minimum number of records in the log before it is compacted.
+const COMPACTION_MIN_RECORDS: usize = 1024;
+
+#[derive(Debug)]
+struct Log {
+    writer: BufWriter<File>,
+    record_count: usize,
+}
+
+#[derive(Debug, PartialEq)]
+enum Record {
+    Access(String, Access, u64),
+    UsedPuzzle(Vec<u8>, u64, u64),
+}
+
+impl Record {
+    fn write<W: Write>(&self, writer: &mut W) -> io::Result<()> {
+        match self {
+            Record::Access(key, access, expiry) => writeln!(
+                writer,
+                "A {} {} {} {}",
+                hex::encode(key),
+                access.count,
+                access.last_access,
+                expiry
+            ),
+            Record::UsedPuzzle(puzzle, timestamp, expiry) => {
+                writeln!(writer, "P {} {} {}", hex::encode(puzzle), timestamp, expiry)
+            }
+        }
+    }
+
+    fn parse(line: &str) -> Option<Record> {
+        let mut fields = line.split(' ');
+        let record = match (fields.next()?, hex::decode(fields.next()?).ok()?) {
+            ("A", key) => Record::Access(
+                String::from_utf8(key).ok()?,
+                Access {
+                    count: fields.next()?.parse().ok()?,
+                    last_access: fields.next()?.parse().ok()?,
+                },
+                fields.next()?.parse().ok()?,
+            ),
+            ("P", puzzle) => Record::UsedPuzzle(
+                puzzle,
+                fields.next()?.parse().ok()?,
+                fields.next()?.parse().ok()?,
+            ),
+            _ => return None,
+        };
+        fields.next().is_none().then_some(record)
+    }
+}
+
+/// A store keeping all state in memory and additionally appending every change to a log file in a
+/// data directory, so that the state survives restarts.
+///
+/// The log is loaded on opening and compacted, i.e. rewritten with only the entries not yet
+/// expired, on opening and whenever [CaptchaStore::evict_expired] finds it mostly consists of
+/// outdated records. Records are written to the operating system on every change, but not
+/// synchronized to disk.
+///
+/// # Examples
+///
+/// ```
+/// use fcaptcha::clock::SystemClock;
+/// use fcaptcha::store::{CaptchaStore, FileStore};
+///
+/// let data_dir = std::env::temp_dir().join("fcaptcha-doc-file-store");
+/// let store = FileStore::open(&data_dir, &SystemClock).unwrap();
+/// assert!(store.mark_puzzle_used(&[0; 32], 1693469848, 3600).unwrap());
+/// ```
+#[derive(Debug)]
+pub struct FileStore {
+    memory: MemoryStore,
+    log: Mutex<Log>,
+    path: PathBuf,
+}
+
+impl FileStore {
+    /// Opens the store in `data_dir`, creating the directory if necessary, without a limit on
+    /// the number of entries. Entries expired at the time of `clock` are dropped.
+    pub fn open<P: AsRef<Path>>(data_dir: P, clock: &dyn Clock) -> Result<FileStore, StoreError> {
+        FileStore::open_with_max_entries(data_dir, usize::MAX, clock)
+    }
+
+    /// Opens the store in `data_dir`, creating the directory if necessary, holding at most
+    /// `max_entries` accesses and at most `max_entries` used puzzles. Entries expired at the time
+    /// of `clock` are dropped.
+    pub fn open_with_max_entries<P: AsRef<Path>>(
+        data_dir: P,
+        max_entries: usize,
+        clock: &dyn Clock,
+    ) -> Result<FileStore, StoreError> {
+        fs::create_dir_all(&data_dir)?;
+        let path = data_dir.as_ref().join(LOG_FILE_NAME);
+        let memory = MemoryStore::with_max_entries(max_entries);
+        let timestamp = clock.now().map_err(|_| StoreError::DataAccess)?;
+        if path.exists() {
+            load(&memory, &path, timestamp)?;
+        }
+        memory.evict_expired(timestamp)?;
+
+        let store = FileStore {
+            memory,
+            log: Mutex::new(Log {
+                writer: open_log(&path)?,
+                record_count: 0,
+            }),
+            path,
+        };
+        store.compact(&mut *store.log.lock()?)?;
+        Ok(store)
+    }
+
+    /// Returns the current number of entries and the number of evictions so far.
+    pub fn metrics(&self) -> Result<StoreMetrics, StoreError> {
+        self.memory.metrics()
+    }
+
+    /// Rewrites the log with the current state only.
+    fn compact(&self, log: &mut Log) -> Result<(), StoreError> {
+        let (accesses, used_puzzles) = self.memory.records()?;
+        let record_count = accesses.len() + used_puzzles.len();
+        let compacted_path = self.path.with_extension("log.tmp");
+
+        let mut writer = BufWriter::new(File::create(&compacted_path)?);
+        for (key, access, expiry) in accesses {
+            Record::Access(key, access, expiry).write(&mut writer)?;
+        }
+        for (puzzle, timestamp, expiry) in used_puzzles {
+            Record::UsedPuzzle(puzzle, timestamp, expiry).write(&mut writer)?;
+        }
+        writer
+            .into_inner()
+            .map_err(|err| err.into_error())?
+            .sync_all()?;
+        fs::rename(&compacted_path, &self.path)?;
+
+        info!(
+            "Compacted store log from {:?} to {:?} records",
+            log.record_count, record_count
+        );
+        *log = Log {
+            writer: open_log(&self.path)?,
+            record_count,
+        };
+        Ok(())
+    }
+
+    fn append(log: &mut Log, record: Record) -> Result<(), StoreError> {
+        record.write(&mut log.writer)?;
+        log.writer.flush()?;
+        log.record_count += 1;
+        Ok(())
+    }
+}
+
+fn open_log(path: &Path) -> io::Result<BufWriter<File>> {
+    Ok(BufWriter::new(
+        OpenOptions::new().create(true).append(true).open(path)?,
+    ))
+}
+
+/// Restores the records of the log at `path` into `memory`, skipping those expired at `timestamp`.
+fn load(memory: &MemoryStore, path: &Path, timestamp: u64) -> Result<(), StoreError> {
+    let reader = BufReader::new(File::open(path)?);
+    for line in reader.lines() {
+        let line = line?;
+        match Record::parse(&line) {
+            Some(Record::Access(_, _, expiry) | Record::UsedPuzzle(_, _, expiry))
+                if expiry <= timestamp => {}
+            Some(Record::Access(key, access, expiry)) => {
+                memory.restore_access(key, access, expiry)?
+            }
+            Some(Record::UsedPuzzle(puzzle, used, expiry)) => {
+                memory.restore_used_puzzle(puzzle, used, expiry)?
+            }
+            // An interrupted write can leave a partial last line.
+            None => warn!("Skipping malformed store record: {:?}", line),
+        }
+    }
+    Ok(())
+}
+
+impl CaptchaStore for FileStore {
+    fn record_access(
+        &self,
+        key: &str,
+        timestamp: u64,
+        access_ttl: u64,
+    ) -> Result<Access, StoreError> {
+        let mut log = self.log.lock()?;
+        let access = self.memory.record_access(key, timestamp, access_ttl)?;
+        let expiry = access_expiry(timestamp, access_ttl);
+        FileStore::append(
+            &mut log,
+            Record::Access(key.to_string(), access.clone(), expiry),
+        )?;
+        Ok(access)
+    }
+
+    fn mark_puzzle_used(
+        &self,
+        puzzle: &[u8],
+        timestamp: u64,
+        puzzle_ttl: u64,
+    ) -> Result<bool, StoreError> {
+        let mut log = self.log.lock()?;
+        if !self
+            .memory
+            .mark_puzzle_used(puzzle, timestamp, puzzle_ttl)?
+        {
+            return Ok(false);
+        }
+        let expiry = used_puzzle_expiry(timestamp, puzzle_ttl);
+        FileStore::append(
+            &mut log,
+            Record::UsedPuzzle(puzzle.to_vec(), timestamp, expiry),
+        )?;
+        Ok(true)
+    }
+
+    fn evict_expired(&self, timestamp: u64) -> Result<usize, StoreError> {
+        let mut log = self.log.lock()?;
+        let evicted = self.memory.evict_expired(timestamp)?;
+        let metrics = self.memory.metrics()?;
+        let live_count = metrics.access_entries + metrics.used_puzzle_entries;
+        if log.record_count > COMPACTION_MIN_RECORDS && log.record_count > 2 * live_count {
+            self.compact(&mut log)?;
+        }
+        Ok(evicted)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clock::{FixedClock, SystemClock};
+    use crate::util;
+    use tempfile::tempdir;
+
+    #[test]
+    fn test_record_parse() {
+        let access = Record::Access(
+            "127.0.0.1".to_string(),
+            Access {
+                count: 3,
+                last_access: 1000,
+            },
+            2801,
+        );
+        let mut line = Vec::new();
+        access.write(&mut line).unwrap();
+        let line = String::from_utf8(line).unwrap();
+        assert_eq!(Record::parse(line.trim_end()), Some(access));
+
+        assert_eq!(Record::parse("P 0102 1000"), None);
+        assert_eq!(Record::parse("X 0102 1000 2000"), None);
+    }
+
+    #[test]
+    fn test_state_survives_reopen() -> Result<(), StoreError> {
+        let data_dir = tempdir()?;
+        let timestamp = util::get_timestamp().unwrap();
+        let puzzle = [1_u8; 32];
+        {
+            let store = FileStore::open(data_dir.path(), &SystemClock)?;
+            store.record_access("192.168.0.1", timestamp, 1800)?;
+            store.record_access("192.168.0.1", timestamp, 1800)?;
+            assert!(store.mark_puzzle_used(&puzzle, timestamp, 3600)?);
+        }
+
+        let store = FileStore::open(data_dir.path(), &SystemClock)?;
+        assert!(!store.mark_puzzle_used(&puzzle, timestamp, 3600)?);
+        assert_eq!(
+            store.record_access("192.168.0.1", timestamp, 1800)?.count,
+            3
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_expired_entries_dropped_on_reopen() -> Result<(), StoreError> {
+        let data_dir = tempdir()?;
+        {
+            let store = FileStore::open(data_dir.path(), &SystemClock)?;
+            store.record_access("192.168.0.2", 1000, 1800)?;
+            store.mark_puzzle_used(&[2_u8; 32], 1000, 3600)?;
+        }
+
+        let store = FileStore::open(data_dir.path(), &SystemClock)?;
+        let metrics = store.metrics()?;
+        assert_eq!(metrics.access_entries, 0);
+        assert_eq!(metrics.used_puzzle_entries, 0);
+        let log = fs::read_to_string(data_dir.path().join(LOG_FILE_NAME))?;
+        assert!(log.is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn test_reopen_at_clock() -> Result<(), StoreError> {
+        let data_dir = tempdir()?;
+        {
+            let store = FileStore::open(data_dir.path(), &FixedClock::new(1000))?;
+            store.mark_puzzle_used(&[3_u8; 32], 1000, 3600)?;
+        }
+
+        // Entries are kept while they are not expired at the time of the clock.
+        let store = FileStore::open(data_dir.path(), &FixedClock::new(4599))?;
+        assert_eq!(store.metrics()?.used_puzzle_entries, 1);
+        drop(store);
+        let store = FileStore::open(data_dir.path(), &FixedClock::new(10_000))?;
+        assert_eq!(store.metrics()?.used_puzzle_entries, 0);
+        Ok(())
+    }
+
+    #[test]
+    fn test_compaction() -> Result<(), StoreError> {
+        let data_dir = tempdir()?;
+        let store = FileStore::open(data_dir.path(), &SystemClock)?;
+        for idx in 0..2 * COMPACTION_MIN_RECORDS {
+            store.record_access("192.168.0.3", 1000 + idx as u64, 1800)?;
+        }
+        store.evict_expired(1000)?;
+
+        let log = fs::read_to_string(data_dir.path().join(LOG_FILE_NAME))?;
+        assert_eq!(log.lines().count(), 1);
+        Ok(())
+    }
+}
@@ -0,0 +1,497 @@
+use super::{Access, CaptchaStore, StoreError};
+use std::borrow::Borrow;
+use std::collections::{BTreeMap, HashMap};
+use std::hash::Hash;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+
+/// Width of the time buckets entries are grouped in for eviction.
+const BUCKET_SECS: u64 = 60;
+
+/// Returns the timestamp from which on an access at `timestamp` is no longer relevant.
+pub(super) fn access_expiry(timestamp: u64, access_ttl: u64) -> u64 {
+    timestamp.saturating_add(access_ttl).saturating_add(1)
+}
+
+/// Returns the timestamp from which on a puzzle used at `timestamp` may be used again.
+pub(super) fn used_puzzle_expiry(timestamp: u64, puzzle_ttl: u64) -> u64 {
+    timestamp.saturating_add(puzzle_ttl)
+}
+
+/// An access together with its key and expiry.
+pub(super) type AccessRecord = (String, Access, u64);
+/// A used puzzle together with the timestamp of its use and its expiry.
+pub(super) type UsedPuzzleRecord = (Vec<u8>, u64, u64);
+
+/// Describes the state of a [MemoryStore] and the evictions it performed.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct StoreMetrics {
+    /// Number of currently stored accesses.
+    pub access_entries: usize,
+    /// Number of currently stored used puzzles.
+    pub used_puzzle_entries: usize,
+    /// Number of entries evicted because they expired.
+    pub expired_evictions: u64,
+    /// Number of entries evicted because the maximum number of entries was reached.
+    pub capacity_evictions: u64,
+}
+
+#[derive(Debug)]
+struct Entry<V> {
+    value: V,
+    expiry: u64,
+}
+
+/// A map whose entries expire. Keys are additionally indexed by time buckets of their expiry, so
+/// expired entries can be found without scanning the whole map.
+#[derive(Debug)]
+struct ExpiringMap<K, V> {
+    entries: HashMap<K, Entry<V>>,
+    buckets: BTreeMap<u64, Vec<K>>,
+}
+
+impl<K, V> Default for ExpiringMap<K, V> {
+    fn default() -> Self {
+        ExpiringMap {
+            entries: HashMap::new(),
+            buckets: BTreeMap::new(),
+        }
+    }
+}
+
+impl<K: Clone + Eq + Hash, V> ExpiringMap<K, V> {
+    fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    /// Sets the value and expiry of the existing entry of `key`. The key is only added to the
+    /// bucket of `expiry` if the bucket changed.
+    fn update<Q>(&mut self, key: &Q, value: V, expiry: u64)
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq + ToOwned<Owned = K> + ?Sized,
+    {
+        if let Some(entry) = self.entries.get_mut(key) {
+            let previous_bucket = entry.expiry / BUCKET_SECS;
+            *entry = Entry { value, expiry };
+            if previous_bucket != expiry / BUCKET_SECS {
+                self.buckets
+                    .entry(expiry / BUCKET_SECS)
+                    .or_default()
+                    .push(key.to_owned());
+            }
+        }
+    }
+
+    fn insert(&mut self, key: K, value: V, expiry: u64) {
+        self.buckets
+            .entry(expiry / BUCKET_SECS)
+            .or_default()
+            .push(key.clone());
+        self.entries.insert(key, Entry { value, expiry });
+    }
+
+    /// Removes all entries that expired at `timestamp`.
+    fn evict_expired(&mut self, timestamp: u64) -> usize {
+        let mut evicted = 0;
+        while let Some(entry) = self.buckets.first_entry() {
+            if entry.key().saturating_add(1).saturating_mul(BUCKET_SECS) > timestamp {
+                break;
+            }
+            for key in entry.remove() {
+                // Keys whose expiry was moved to a later bucket are kept.
+                if self
+                    .entries
+                    .get(&key)
+                    .is_some_and(|entry| entry.expiry <= timestamp)
+                {
+                    self.entries.remove(&key);
+                    evicted += 1;
+                }
+            }
+        }
+        evicted
+    }
+
+    /// Removes the entry that expires next. Returns `false` if the map is empty.
+    fn evict_next(&mut self) -> bool {
+        while let Some(mut bucket_entry) = self.buckets.first_entry() {
+            let bucket = *bucket_entry.key();
+            while let Some(key) = bucket_entry.get_mut().pop() {
+                if self
+                    .entries
+                    .get(&key)
+                    .is_some_and(|entry| entry.expiry / BUCKET_SECS == bucket)
+                {
+                    self.entries.remove(&key);
+                    return true;
+                }
+            }
+            bucket_entry.remove();
+        }
+        false
+    }
+}
+
+/// A store keeping all state in memory of the current process.
+///
+/// Entries are removed once they expire by calling [CaptchaStore::evict_expired], e.g. from
+/// [spawn_evictor](super::spawn_evictor). If the maximum number of entries is reached, the accesses
+/// expiring next are evicted early. Used puzzles are never evicted before they expire, as this would
+/// re-enable their reuse, so marking further puzzles as used fails with [StoreError::Full] instead.
+/// The maximum should be chosen well above the expected number of puzzles solved within the puzzle
+/// TTL.
+///
+/// # Examples
+///
+/// ```
+/// use fcaptcha::store::{CaptchaStore, MemoryStore};
+///
+/// let store = MemoryStore::new();
+/// let access = store.record_access("127.0.0.1", 1693469848, 1800).unwrap();
+/// assert_eq!(access.count, 1);
+/// ```
+#[derive(Debug)]
+pub struct MemoryStore {
+    accesses: Mutex<ExpiringMap<String, Access>>,
+    used_puzzles: Mutex<ExpiringMap<Vec<u8>, u64>>,
+    max_entries: usize,
+    expired_evictions: AtomicU64,
+    capacity_evictions: AtomicU64,
+}
+
+impl Default for MemoryStore {
+    fn default() -> Self {
+        MemoryStore::with_max_entries(usize::MAX)
+    }
+}
+
+impl MemoryStore {
+    /// Creates a new empty store without a limit on the number of entries.
+    pub fn new() -> MemoryStore {
+        MemoryStore::default()
+    }
+
+    /// Creates a new empty store holding at most `max_entries` accesses and at most
+    /// `max_entries` used puzzles.
+    pub fn with_max_entries(max_entries: usize) -> MemoryStore {
+        MemoryStore {
+            accesses: Mutex::new(ExpiringMap::default()),
+            used_puzzles: Mutex::new(ExpiringMap::default()),
+            max_entries: max_entries.max(1),
+            expired_evictions: AtomicU64::new(0),
+            capacity_evictions: AtomicU64::new(0),
+        }
+    }
+
+    /// Returns the current number of entries and the number of evictions so far.
+    pub fn metrics(&self) -> Result<StoreMetrics, StoreError> {
+        Ok(StoreMetrics {
+            access_entries: self.accesses.lock()?.len(),
+            used_puzzle_entries: self.used_puzzles.lock()?.len(),
+            expired_evictions: self.expired_evictions.load(Ordering::Relaxed),
+            capacity_evictions: self.capacity_evictions.load(Ordering::Relaxed),
+        })
+    }
+
+    /// Inserts or replaces an access with a known `expiry`, e.g. when restoring persisted state.
+    pub(super) fn restore_access(
+        &self,
+        key: String,
+        access: Access,
+        expiry: u64,
+    ) -> Result<(), StoreError> {
+        let mut map = self.accesses.lock()?;
+        if map.entries.contains_key(&key) {
+            map.update(&key, access, expiry);
+        } else {
+            self.make_room(&mut map);
+            map.insert(key, access, expiry);
+        }
+        Ok(())
+    }
+
+    /// Inserts or replaces a used puzzle with a known `expiry`, e.g. when restoring persisted
+    /// state.
+    pub(super) fn restore_used_puzzle(
+        &self,
+        puzzle: Vec<u8>,
+        timestamp: u64,
+        expiry: u64,
+    ) -> Result<(), StoreError> {
+        let mut map = self.used_puzzles.lock()?;
+        if map.entries.contains_key(&puzzle) {
+            map.update(&puzzle, timestamp, expiry);
+        } else {
+            self.check_room(&map)?;
+            map.insert(puzzle, timestamp, expiry);
+        }
+        Ok(())
+    }
+
+    /// Returns a copy of all stored accesses and used puzzles.
+    pub(super) fn records(&self) -> Result<(Vec<AccessRecord>, Vec<UsedPuzzleRecord>), StoreError> {
+        let accesses = self
+            .accesses
+            .lock()?
+            .entries
+            .iter()
+            .map(|(key, entry)| (key.clone(), entry.value.clone(), entry.expiry))
+            .collect();
+        let used_puzzles = self
+            .used_puzzles
+            .lock()?
+            .entries
+            .iter()
+            .map(|(puzzle, entry)| (puzzle.clone(), entry.value, entry.expiry))
+            .collect();
+        Ok((accesses, used_puzzles))
+    }
+
+    fn make_room<K: Clone + Eq + Hash, V>(&self, map: &mut ExpiringMap<K, V>) {
+        while map.len() >= self.max_entries && map.evict_next() {
+            self.capacity_evictions.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    fn check_room<K: Clone + Eq + Hash, V>(
+        &self,
+        map: &ExpiringMap<K, V>,
+    ) -> Result<(), StoreError> {
+        if map.len() >= self.max_entries {
+            warn!("Store is full with {:?} entries", map.len());
+            return Err(StoreError::Full);
+        }
+        Ok(())
+    }
+}
+
+impl CaptchaStore for MemoryStore {
+    fn record_access(
+        &self,
+        key: &str,
+        timestamp: u64,
+        access_ttl: u64,
+    ) -> Result<Access, StoreError> {
+        let mut map = self.accesses.lock()?;
+        let expiry = access_expiry(timestamp, access_ttl);
+
+        let access = match map.entries.get(key) {
+            Some(entry) => {
+                let previous = &entry.value;
+                // Concurrent requests may arrive with timestamps slightly out of order.
+                let access = Access {
+                    count: if timestamp.saturating_sub(previous.last_access) > access_ttl {
+                        1
+                    } else {
+                        previous.count + 1
+                    },
+                    last_access: timestamp.max(previous.last_access),
+                };
+                map.update(key, access.clone(), expiry);
+                access
+            }
+            None => {
+                self.make_room(&mut map);
+                let access = Access {
+                    count: 1,
+                    last_access: timestamp,
+                };
+                map.insert(key.to_string(), access.clone(), expiry);
+                access
+            }
+        };
+        Ok(access)
+    }
+
+    fn mark_puzzle_used(
+        &self,
+        puzzle: &[u8],
+        timestamp: u64,
+        puzzle_ttl: u64,
+    ) -> Result<bool, StoreError> {
+        let mut map = self.used_puzzles.lock()?;
+        let expiry = used_puzzle_expiry(timestamp, puzzle_ttl);
+
+        match map.entries.get(puzzle) {
+            Some(entry) => {
+                if timestamp.saturating_sub(entry.value) < puzzle_ttl {
+                    info!("Puzzle reuse with: {:?}", puzzle);
+                    return Ok(false);
+                }
+                info!("Expired puzzle reuse with: {:?}", puzzle);
+                map.update(puzzle, timestamp, expiry);
+            }
+            None => {
+                info!("New puzzle with: {:?}", puzzle);
+                if map.len() >= self.max_entries {
+                    let evicted = map.evict_expired(timestamp);
+                    self.expired_evictions
+                        .fetch_add(evicted as u64, Ordering::Relaxed);
+                }
+                self.check_room(&map)?;
+                map.insert(puzzle.to_vec(), timestamp, expiry);
+            }
+        }
+        Ok(true)
+    }
+
+    fn evict_expired(&self, timestamp: u64) -> Result<usize, StoreError> {
+        let evicted = self.accesses.lock()?.evict_expired(timestamp)
+            + self.used_puzzles.lock()?.evict_expired(timestamp);
+        self.expired_evictions
+            .fetch_add(evicted as u64, Ordering::Relaxed);
+        if evicted > 0 {
+            info!("Evicted {:?} expired entries", evicted);
+        }
+        Ok(evicted)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_record_access_first() -> Result<(), StoreError> {
+        let store = MemoryStore::new();
+        let timestamp = 1234_u64;
+        let access = store.record_access("192.168.0.1", timestamp, 1800)?;
+        assert_eq!(access.count, 1);
+        assert_eq!(access.last_access, timestamp);
+        Ok(())
+    }
+
+    #[test]
+    fn test_record_access_second() -> Result<(), StoreError> {
+        let store = MemoryStore::new();
+        store.record_access("192.168.0.2", 1234, 1800)?;
+        let access = store.record_access("192.168.0.2", 1235, 1800)?;
+        assert_eq!(access.count, 2);
+        assert_eq!(access.last_access, 1235);
+        Ok(())
+    }
+
+    #[test]
+    fn test_record_access_second_within_ttl() -> Result<(), StoreError> {
+        let store = MemoryStore::new();
+        let access_ttl = 1800;
+        store.record_access("192.168.0.3", 1234, access_ttl)?;
+        let timestamp = 1234 + access_ttl;
+        let access = store.record_access("192.168.0.3", timestamp, access_ttl)?;
+        assert_eq!(access.count, 2);
+        assert_eq!(access.last_access, timestamp);
+        Ok(())
+    }
+
+    #[test]
+    fn test_record_access_second_after_ttl() -> Result<(), StoreError> {
+        let store = MemoryStore::new();
+        let access_ttl = 1800;
+        store.record_access("192.168.0.4", 1234, access_ttl)?;
+        let timestamp = 1234 + access_ttl + 1;
+        let access = store.record_access("192.168.0.4", timestamp, access_ttl)?;
+        assert_eq!(access.count, 1);
+        assert_eq!(access.last_access, timestamp);
+        Ok(())
+    }
+
+    #[test]
+    fn test_record_access_out_of_order() -> Result<(), StoreError> {
+        let store = MemoryStore::new();
+        store.record_access("192.168.0.6", 1234, 1800)?;
+        let access = store.record_access("192.168.0.6", 1233, 1800)?;
+        assert_eq!(access.count, 2);
+        assert_eq!(access.last_access, 1234);
+        Ok(())
+    }
+
+    #[test]
+    fn test_record_access_isolated_stores() -> Result<(), StoreError> {
+        let first = MemoryStore::new();
+        let second = MemoryStore::new();
+        first.record_access("192.168.0.5", 1234, 1800)?;
+        let access = second.record_access("192.168.0.5", 1235, 1800)?;
+        assert_eq!(access.count, 1);
+        Ok(())
+    }
+
+    #[test]
+    fn test_mark_puzzle_used() -> Result<(), StoreError> {
+        let store = MemoryStore::new();
+        let puzzle = [1_u8; 32];
+        assert!(store.mark_puzzle_used(&puzzle, 1234, 3600)?);
+        assert!(!store.mark_puzzle_used(&puzzle, 1235, 3600)?);
+        assert!(!store.mark_puzzle_used(&puzzle, 1233, 3600)?);
+        assert!(store.mark_puzzle_used(&puzzle, 1234 + 3600, 3600)?);
+        Ok(())
+    }
+
+    #[test]
+    fn test_evict_expired() -> Result<(), StoreError> {
+        let store = MemoryStore::new();
+        store.record_access("192.168.0.6", 1000, 1800)?;
+        store.record_access("192.168.0.7", 1000, 1800)?;
+        store.record_access("192.168.0.7", 2000, 1800)?;
+        store.mark_puzzle_used(&[1_u8; 32], 1000, 3600)?;
+
+        assert_eq!(store.evict_expired(2800)?, 0);
+        assert_eq!(store.evict_expired(3000)?, 1);
+        assert_eq!(store.evict_expired(4600)?, 1);
+        assert_eq!(store.evict_expired(10000)?, 1);
+
+        let metrics = store.metrics()?;
+        assert_eq!(metrics.access_entries, 0);
+        assert_eq!(metrics.used_puzzle_entries, 0);
+        assert_eq!(metrics.expired_evictions, 3);
+        Ok(())
+    }
+
+    #[test]
+    fn test_evict_expired_keeps_state() -> Result<(), StoreError> {
+        let store = MemoryStore::new();
+        let puzzle = [2_u8; 32];
+        store.record_access("192.168.0.8", 1000, 1800)?;
+        store.mark_puzzle_used(&puzzle, 1000, 3600)?;
+        store.evict_expired(2000)?;
+
+        assert_eq!(store.record_access("192.168.0.8", 2000, 1800)?.count, 2);
+        assert!(!store.mark_puzzle_used(&puzzle, 2000, 3600)?);
+        Ok(())
+    }
+
+    #[test]
+    fn test_max_entries() -> Result<(), StoreError> {
+        let store = MemoryStore::with_max_entries(2);
+        store.record_access("192.168.0.9", 1000, 1800)?;
+        store.record_access("192.168.0.10", 2000, 1800)?;
+        store.record_access("192.168.0.11", 3000, 1800)?;
+
+        let metrics = store.metrics()?;
+        assert_eq!(metrics.access_entries, 2);
+        assert_eq!(metrics.capacity_evictions, 1);
+        // The entry expiring first was evicted.
+        assert_eq!(store.record_access("192.168.0.9", 3000, 1800)?.count, 1);
+        assert_eq!(store.record_access("192.168.0.11", 3000, 1800)?.count, 2);
+        Ok(())
+    }
+
+    #[test]
+    fn test_max_entries_keeps_used_puzzles() -> Result<(), StoreError> {
+        let store = MemoryStore::with_max_entries(2);
+        assert!(store.mark_puzzle_used(&[1_u8; 32], 1000, 3600)?);
+        assert!(store.mark_puzzle_used(&[2_u8; 32], 1000, 3600)?);
+        assert_eq!(
+            store.mark_puzzle_used(&[3_u8; 32], 2000, 3600),
+            Err(StoreError::Full)
+        );
+        assert!(!store.mark_puzzle_used(&[1_u8; 32], 2000, 3600)?);
+
+        // Expired used puzzles make room again.
+        assert!(store.mark_puzzle_used(&[3_u8; 32], 10_000, 3600)?);
+        let metrics = store.metrics()?;
+        assert_eq!(metrics.used_puzzle_entries, 1);
+        assert_eq!(metrics.capacity_evictions, 0);
+        Ok(())
+    }
+}
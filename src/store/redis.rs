@@ -0,0 +1,249 @@
+#![cfg(feature = "redis")]
+
+use super::{Access, CaptchaStore, StoreError};
+use redis::{Client, Connection, RedisError};
+use std::fmt;
+use std::sync::Mutex;
+
+/// Default prefix of all keys written by a [RedisStore].
+const DEFAULT_KEY_PREFIX: &str = "fcaptcha:";
+
+impl From<RedisError> for StoreError {
+    fn from(err: RedisError) -> Self {
+        Self::Backend(err.to_string())
+    }
+}
+
+/// A store keeping all state in a server speaking the Redis protocol, so that multiple instances
+/// can share it. Requires the `redis` feature.
+///
+/// Accesses are counted with `INCR` and expired with `EXPIRE`, used puzzles are recorded with
+/// `SET NX EX`. Expiry is handled by the server based on its own clock, the timestamps passed to
+/// the store are only used for the returned [Access].
+///
+/// # Examples
+///
+/// ```no_run
+/// use fcaptcha::store::{CaptchaStore, RedisStore};
+///
+/// let store = RedisStore::open("redis://127.0.0.1/").unwrap();
+/// let access = store.record_access("127.0.0.1", 1693469848, 1800).unwrap();
+/// println!("{:?}", access);
+/// ```
+pub struct RedisStore {
+    client: Client,
+    connections: Mutex<Vec<Connection>>,
+    key_prefix: String,
+}
+
+impl fmt::Debug for RedisStore {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("RedisStore")
+            .field("client", &self.client)
+            .field("key_prefix", &self.key_prefix)
+            .finish_non_exhaustive()
+    }
+}
+
+impl RedisStore {
+    /// Creates a store connecting to the server at `url`, e.g. `redis://127.0.0.1:6379/0`.
+    /// Connections are established on demand.
+    pub fn open(url: &str) -> Result<RedisStore, StoreError> {
+        Ok(RedisStore {
+            client: Client::open(url)?,
+            connections: Mutex::new(Vec::new()),
+            key_prefix: DEFAULT_KEY_PREFIX.to_string(),
+        })
+    }
+
+    /// Sets the prefix of all keys, so that multiple isolated instances can share a server.
+    pub fn with_key_prefix(mut self, key_prefix: &str) -> RedisStore {
+        self.key_prefix = key_prefix.to_string();
+        self
+    }
+
+    /// Runs `op` on a pooled connection. Connections are only returned to the pool if `op`
+    /// succeeded.
+    fn with_connection<T, F>(&self, op: F) -> Result<T, StoreError>
+    where
+        F: FnOnce(&mut Connection) -> Result<T, RedisError>,
+    {
+        let pooled = self.connections.lock()?.pop();
+        let mut connection = match pooled {
+            Some(connection) => connection,
+            None => self.client.get_connection()?,
+        };
+        let result = op(&mut connection)?;
+        self.connections.lock()?.push(connection);
+        Ok(result)
+    }
+}
+
+impl CaptchaStore for RedisStore {
+    fn record_access(
+        &self,
+        key: &str,
+        timestamp: u64,
+        access_ttl: u64,
+    ) -> Result<Access, StoreError> {
+        let key = format!("{}access:{}", self.key_prefix, key);
+        let (count,): (u64,) = self.with_connection(|connection| {
+            redis::pipe()
+                .cmd("INCR")
+                .arg(&key)
+                .cmd("EXPIRE")
+                .arg(&key)
+                .arg(access_ttl.saturating_add(1))
+                .ignore()
+                .query(connection)
+        })?;
+        Ok(Access {
+            count,
+            last_access: timestamp,
+        })
+    }
+
+    fn mark_puzzle_used(
+        &self,
+        puzzle: &[u8],
+        timestamp: u64,
+        puzzle_ttl: u64,
+    ) -> Result<bool, StoreError> {
+        // A zero expiry is rejected by the server and allows immediate reuse anyway.
+        if puzzle_ttl == 0 {
+            return Ok(true);
+        }
+        let key = format!("{}puzzle:{}", self.key_prefix, hex::encode(puzzle));
+        let reply: Option<String> = self.with_connection(|connection| {
+            redis::cmd("SET")
+                .arg(&key)
+                .arg(timestamp)
+                .arg("NX")
+                .arg("EX")
+                .arg(puzzle_ttl)
+                .query(connection)
+        })?;
+        if reply.is_none() {
+            info!("Puzzle reuse with: {:?}", puzzle);
+        }
+        Ok(reply.is_some())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+    use std::io::{BufRead, BufReader, Write};
+    use std::net::{TcpListener, TcpStream};
+    use std::sync::Arc;
+    use std::thread;
+    use std::time::{Duration, Instant};
+
+    type Data = Arc<Mutex<HashMap<String, (String, Option<Instant>)>>>;
+
+    /// Starts a minimal stand-in server supporting the commands used by [RedisStore] and returns
+    /// its URL.
+    fn start_server() -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("redis://{}/", listener.local_addr().unwrap());
+        let data = Data::default();
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                let data = Arc::clone(&data);
+                thread::spawn(move || serve(stream.unwrap(), data));
+            }
+        });
+        url
+    }
+
+    fn read_command(reader: &mut BufReader<TcpStream>) -> Option<Vec<String>> {
+        let mut line = String::new();
+        reader.read_line(&mut line).ok()?;
+        let arg_count: usize = line.trim_end().strip_prefix('*')?.parse().ok()?;
+        let mut args = Vec::new();
+        for _ in 0..arg_count {
+            line.clear();
+            reader.read_line(&mut line).ok()?;
+            line.clear();
+            reader.read_line(&mut line).ok()?;
+            args.push(line.trim_end().to_string());
+        }
+        Some(args)
+    }
+
+    fn serve(stream: TcpStream, data: Data) {
+        let mut writer = stream.try_clone().unwrap();
+        let mut reader = BufReader::new(stream);
+        while let Some(args) = read_command(&mut reader) {
+            let mut data = data.lock().unwrap();
+            data.retain(|_, (_, expiry)| !expiry.is_some_and(|expiry| expiry <= Instant::now()));
+            let expiry_in =
+                |secs: &str| Some(Instant::now() + Duration::from_secs(secs.parse().unwrap()));
+            let reply = match args.iter().map(String::as_str).collect::<Vec<_>>()[..] {
+                ["INCR", key] => {
+                    let entry = data
+                        .entry(key.to_string())
+                        .or_insert(("0".to_string(), None));
+                    let value = entry.0.parse::<u64>().unwrap() + 1;
+                    entry.0 = value.to_string();
+                    format!(":{}\r\n", value)
+                }
+                ["EXPIRE", key, secs] => match data.get_mut(key) {
+                    Some(entry) => {
+                        entry.1 = expiry_in(secs);
+                        ":1\r\n".to_string()
+                    }
+                    None => ":0\r\n".to_string(),
+                },
+                ["SET", key, value, "NX", "EX", secs] => {
+                    if data.contains_key(key) {
+                        "$-1\r\n".to_string()
+                    } else {
+                        data.insert(key.to_string(), (value.to_string(), expiry_in(secs)));
+                        "+OK\r\n".to_string()
+                    }
+                }
+                _ => "-ERR unknown command\r\n".to_string(),
+            };
+            writer.write_all(reply.as_bytes()).unwrap();
+        }
+    }
+
+    #[test]
+    fn test_record_access() -> Result<(), StoreError> {
+        let store = RedisStore::open(&start_server())?;
+        assert_eq!(store.record_access("192.168.0.1", 1234, 1800)?.count, 1);
+        let access = store.record_access("192.168.0.1", 1235, 1800)?;
+        assert_eq!(access.count, 2);
+        assert_eq!(access.last_access, 1235);
+        assert_eq!(store.record_access("192.168.0.2", 1235, 1800)?.count, 1);
+        Ok(())
+    }
+
+    #[test]
+    fn test_mark_puzzle_used_shared() -> Result<(), StoreError> {
+        let url = start_server();
+        let first = RedisStore::open(&url)?;
+        let second = RedisStore::open(&url)?;
+        let puzzle = [1_u8; 32];
+        assert!(first.mark_puzzle_used(&puzzle, 1234, 3600)?);
+        assert!(!second.mark_puzzle_used(&puzzle, 1235, 3600)?);
+
+        let isolated = RedisStore::open(&url)?.with_key_prefix("other:");
+        assert!(isolated.mark_puzzle_used(&puzzle, 1235, 3600)?);
+        Ok(())
+    }
+
+    #[test]
+    fn test_unreachable_server() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("redis://{}/", listener.local_addr().unwrap());
+        drop(listener);
+        let store = RedisStore::open(&url).unwrap();
+        assert!(matches!(
+            store.record_access("192.168.0.3", 1234, 1800),
+            Err(StoreError::Backend(_))
+        ));
+    }
+}
@@ -0,0 +1,134 @@
+use super::{Access, CaptchaStore, MemoryStore, StoreError, StoreMetrics};
+use std::collections::hash_map::RandomState;
+use std::hash::{BuildHasher, Hash};
+
+/// A store keeping all state in memory of the current process, split into independently locked
+/// shards. Concurrent accesses to different keys rarely contend for the same lock.
+///
+/// # Examples
+///
+/// ```
+/// use fcaptcha::store::{CaptchaStore, ShardedStore};
+///
+/// let store = ShardedStore::new(16);
+/// let access = store.record_access("127.0.0.1", 1693469848, 1800).unwrap();
+/// assert_eq!(access.count, 1);
+/// ```
+#[derive(Debug)]
+pub struct ShardedStore {
+    shards: Box<[MemoryStore]>,
+    hasher: RandomState,
+}
+
+impl ShardedStore {
+    /// Creates a new empty store with `shard_count` shards and without a limit on the number of
+    /// entries.
+    pub fn new(shard_count: usize) -> ShardedStore {
+        ShardedStore::with_max_entries(shard_count, usize::MAX)
+    }
+
+    /// Creates a new empty store with `shard_count` shards holding at most about `max_entries`
+    /// accesses and `max_entries` used puzzles in total.
+    pub fn with_max_entries(shard_count: usize, max_entries: usize) -> ShardedStore {
+        let shard_count = shard_count.max(1);
+        let max_entries_per_shard = max_entries / shard_count + 1;
+        ShardedStore {
+            shards: (0..shard_count)
+                .map(|_| MemoryStore::with_max_entries(max_entries_per_shard))
+                .collect(),
+            hasher: RandomState::new(),
+        }
+    }
+
+    /// Returns the current number of entries and the number of evictions so far, summed over
+    /// all shards.
+    pub fn metrics(&self) -> Result<StoreMetrics, StoreError> {
+        self.shards
+            .iter()
+            .try_fold(StoreMetrics::default(), |total, shard| {
+                let metrics = shard.metrics()?;
+                Ok(StoreMetrics {
+                    access_entries: total.access_entries + metrics.access_entries,
+                    used_puzzle_entries: total.used_puzzle_entries + metrics.used_puzzle_entries,
+                    expired_evictions: total.expired_evictions + metrics.expired_evictions,
+                    capacity_evictions: total.capacity_evictions + metrics.capacity_evictions,
+                })
+            })
+    }
+
+    fn shard<K: Hash + ?Sized>(&self, key: &K) -> &MemoryStore {
+        let hash = self.hasher.hash_one(key);
+        &self.shards[(hash % self.shards.len() as u64) as usize]
+    }
+}
+
+impl CaptchaStore for ShardedStore {
+    fn record_access(
+        &self,
+        key: &str,
+        timestamp: u64,
+        access_ttl: u64,
+    ) -> Result<Access, StoreError> {
+        self.shard(key).record_access(key, timestamp, access_ttl)
+    }
+
+    fn mark_puzzle_used(
+        &self,
+        puzzle: &[u8],
+        timestamp: u64,
+        puzzle_ttl: u64,
+    ) -> Result<bool, StoreError> {
+        self.shard(puzzle)
+            .mark_puzzle_used(puzzle, timestamp, puzzle_ttl)
+    }
+
+    fn evict_expired(&self, timestamp: u64) -> Result<usize, StoreError> {
+        self.shards.iter().try_fold(
+            0,
+            |total, shard| Ok(total + shard.evict_expired(timestamp)?),
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Arc;
+    use std::thread;
+
+    #[test]
+    fn test_record_access_concurrent() -> Result<(), StoreError> {
+        let store = Arc::new(ShardedStore::new(4));
+        let handles: Vec<_> = (0..8)
+            .map(|_| {
+                let store = Arc::clone(&store);
+                thread::spawn(move || {
+                    for idx in 0..100 {
+                        store
+                            .record_access(&format!("10.0.0.{}", idx), 1000, 1800)
+                            .unwrap();
+                    }
+                })
+            })
+            .collect();
+        for handle in handles {
+            handle.join().unwrap();
+        }
+
+        assert_eq!(store.record_access("10.0.0.1", 1000, 1800)?.count, 9);
+        assert_eq!(store.metrics()?.access_entries, 100);
+        Ok(())
+    }
+
+    #[test]
+    fn test_mark_puzzle_used_and_evict() -> Result<(), StoreError> {
+        let store = ShardedStore::new(4);
+        for idx in 0..10_u8 {
+            assert!(store.mark_puzzle_used(&[idx; 32], 1000, 3600)?);
+        }
+        assert!(!store.mark_puzzle_used(&[3; 32], 2000, 3600)?);
+        assert_eq!(store.evict_expired(5000)?, 10);
+        assert_eq!(store.metrics()?.used_puzzle_entries, 0);
+        Ok(())
+    }
+}
@@ -0,0 +1,448 @@
+use crate::build_puzzle::PuzzleOptions;
+use crate::config::{ConfigError, Settings};
+use crate::keyring::{Keyring, KeyringError};
+use crate::prefix::parse_address;
+use displaydoc::Display;
+use std::collections::HashMap;
+use std::fmt;
+use subtle::ConstantTimeEq;
+use thiserror::Error;
+
+/// Describes an error that occurred during setting up tenants.
+#[derive(Display, Error, Debug, PartialEq)]
+pub enum TenantError {
+    /// Sitekey {0} is used by multiple tenants.
+    DuplicateSitekey(String),
+    /// API secret is used by multiple tenants.
+    DuplicateSecret,
+    /// Account {0} and app {1} are used by multiple tenants.
+    DuplicateOrigin(u32, u32),
+    /// Tenant configuration malformed: {0}
+    ConfigMalformed(String),
+    /// {0}
+    Config(#[from] ConfigError),
+    /// Keys of the default tenant malformed: {0}
+    Keyring(#[from] KeyringError),
+}
+
+/// A site protected by the server, identified by its public sitekey. Each tenant has its own API
+/// secret for verifying solutions, keys for signing puzzles, account and app id, difficulty policy
+/// and allowed origins.
+///
+/// # Examples
+///
+/// ```
+/// use fcaptcha::tenant::Tenant;
+/// use fcaptcha::Keyring;
+///
+/// let tenant = Tenant::new("SITEKEY", "API-SECRET".as_bytes(), Keyring::from("KEY".as_bytes()))
+///     .with_allowed_origins(vec!["https://example.com".to_string()]);
+/// assert!(tenant.matches_secret("API-SECRET".as_bytes()));
+/// assert!(tenant.allows_origin(Some("https://example.com")));
+/// assert!(!tenant.allows_origin(Some("https://example.org")));
+/// ```
+#[derive(Clone)]
+pub struct Tenant {
+    /// Public key identifying the tenant in puzzle requests.
+    pub sitekey: String,
+    /// Keys for signing and verifying puzzles.
+    pub keyring: Keyring,
+    /// Options of the puzzles built for the tenant.
+    pub options: PuzzleOptions,
+    /// Origins puzzles may be requested from. Empty allows all origins.
+    pub allowed_origins: Vec<String>,
+    /// Whether puzzles are bound to the IP address they are requested from.
+    pub ip_binding: bool,
+    secret: Vec<u8>,
+}
+
+impl fmt::Debug for Tenant {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Tenant")
+            .field("sitekey", &self.sitekey)
+            .field("keyring", &self.keyring)
+            .field("options", &self.options)
+            .field("allowed_origins", &self.allowed_origins)
+            .field("ip_binding", &self.ip_binding)
+            .finish_non_exhaustive()
+    }
+}
+
+impl Tenant {
+    /// Creates a tenant with default puzzle options that allows all origins.
+    pub fn new(sitekey: &str, secret: &[u8], keyring: Keyring) -> Tenant {
+        Tenant {
+            sitekey: sitekey.to_string(),
+            keyring,
+            options: PuzzleOptions::default(),
+            allowed_origins: Vec::new(),
+            ip_binding: false,
+            secret: secret.to_vec(),
+        }
+    }
+
+    /// Sets the options of the puzzles built for the tenant.
+    pub fn with_options(mut self, options: PuzzleOptions) -> Tenant {
+        self.options = options;
+        self
+    }
+
+    /// Sets the origins puzzles may be requested from. Empty allows all origins.
+    pub fn with_allowed_origins(mut self, allowed_origins: Vec<String>) -> Tenant {
+        self.allowed_origins = allowed_origins;
+        self
+    }
+
+    /// Sets whether puzzles are bound to the IP address they are requested from, so that they are
+    /// only accepted if verified for the same address.
+    pub fn with_ip_binding(mut self, ip_binding: bool) -> Tenant {
+        self.ip_binding = ip_binding;
+        self
+    }
+
+    /// Returns `true` if `secret` is the API secret of the tenant. The comparison takes the same
+    /// time for all secrets of the same length, so that it does not reveal matching prefixes.
+    pub fn matches_secret(&self, secret: &[u8]) -> bool {
+        self.secret.ct_eq(secret).into()
+    }
+
+    /// Returns `true` if puzzles may be requested from `origin`. Requests without an origin are
+    /// only allowed if all origins are.
+    pub fn allows_origin(&self, origin: Option<&str>) -> bool {
+        self.allowed_origins.is_empty()
+            || origin.is_some_and(|origin| self.allowed_origins.iter().any(|o| o == origin))
+    }
+
+    /// Returns the context puzzles are bound to, the normalized `ip_address` if the tenant uses IP
+    /// binding. A missing address gives the empty context, which no puzzle for a client is bound
+    /// to.
+    pub(crate) fn ip_context(&self, ip_address: Option<&str>) -> Option<String> {
+        if !self.ip_binding {
+            return None;
+        }
+        let ip_address = ip_address.unwrap_or_default();
+        Some(parse_address(ip_address).map_or_else(|| ip_address.to_string(), |a| a.to_string()))
+    }
+
+    /// Parses a tenant in the form
+    /// `<sitekey>;<secret>;<signing key>;<account id>;<app id>;<origin> <origin>[;<tiers>]`,
+    /// with the other options taken from `base_options`.
+    pub(crate) fn parse(entry: &str, base_options: &PuzzleOptions) -> Result<Tenant, TenantError> {
+        let malformed = || TenantError::ConfigMalformed(format!("{:?}", entry));
+        let fields: Vec<&str> = entry.trim().split(';').collect();
+        let [sitekey, secret, signing_key, account_id, app_id, origins, ref tiers @ ..] =
+            fields[..]
+        else {
+            return Err(malformed());
+        };
+        let mut options = base_options
+            .clone()
+            .with_account_id(account_id.parse().map_err(|_| malformed())?)
+            .with_app_id(app_id.parse().map_err(|_| malformed())?);
+        match tiers {
+            [] => {}
+            [tiers] => options = options.with_tiers(tiers.parse().map_err(|_| malformed())?),
+            _ => return Err(malformed()),
+        }
+        Ok(Tenant::new(
+            sitekey,
+            secret.as_bytes(),
+            Keyring::from(signing_key.as_bytes()),
+        )
+        .with_options(options)
+        .with_allowed_origins(origins.split_whitespace().map(String::from).collect()))
+    }
+}
+
+/// The tenants served, looked up by sitekey or API secret.
+///
+/// # Examples
+///
+/// ```
+/// use fcaptcha::tenant::{Tenant, TenantRegistry};
+/// use fcaptcha::build_puzzle::PuzzleOptions;
+/// use fcaptcha::Keyring;
+///
+/// let registry = TenantRegistry::new()
+///     .with_tenant(Tenant::new("A", "SECRET-A".as_bytes(), Keyring::from("KEY-A".as_bytes())))
+///     .unwrap()
+///     .with_tenant(
+///         Tenant::new("B", "SECRET-B".as_bytes(), Keyring::from("KEY-B".as_bytes()))
+///             .with_options(PuzzleOptions::default().with_app_id(2)),
+///     )
+///     .unwrap();
+/// assert_eq!(registry.get("B").unwrap().options.app_id, 2);
+/// assert_eq!(registry.resolve(None, "SECRET-A".as_bytes()).unwrap().sitekey, "A");
+/// assert!(registry.resolve(Some("B"), "SECRET-A".as_bytes()).is_none());
+/// ```
+#[derive(Clone, Debug, Default)]
+pub struct TenantRegistry {
+    tenants: HashMap<String, Tenant>,
+}
+
+impl TenantRegistry {
+    /// Creates a registry without tenants.
+    pub fn new() -> TenantRegistry {
+        TenantRegistry::default()
+    }
+
+    /// Reads the tenants from `tenants` of `settings` as
+    /// `<sitekey>;<secret>;<signing key>;<account id>;<app id>;<origin> <origin>[;<tiers>],...`,
+    /// where an empty origin list allows all origins and the optional difficulty tiers are given as
+    /// for [TierTable](crate::difficulty::TierTable), separated by whitespace. The other options,
+    /// e.g. puzzle version, expiry and the default difficulty tiers, are taken from `base_options`.
+    /// All tenants bind puzzles to the client IP address if `ip_binding` is `true`.
+    ///
+    /// Without configured tenants, a single tenant is served that uses `api_key` as sitekey and
+    /// secret, the keys read by [Keyring::from_settings] and `base_options`.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use fcaptcha::build_puzzle::PuzzleOptions;
+    /// use fcaptcha::config::Settings;
+    /// use fcaptcha::tenant::TenantRegistry;
+    ///
+    /// let settings = Settings {
+    ///     tenants: "A;SECRET-A;KEY-A;1;1;,B;SECRET-B;KEY-B;1;2;;0:1:10".to_string(),
+    ///     ..Settings::default()
+    /// };
+    /// let options = PuzzleOptions::from_settings(&settings).unwrap();
+    /// let registry = TenantRegistry::from_settings(&settings, &options).unwrap();
+    /// assert_eq!(registry.get("B").unwrap().options.app_id, 2);
+    /// ```
+    pub fn from_settings(
+        settings: &Settings,
+        base_options: &PuzzleOptions,
+    ) -> Result<TenantRegistry, TenantError> {
+        if settings.tenants.trim().is_empty() {
+            let api_key = &settings.api_key;
+            let tenant = Tenant::new(
+                api_key,
+                api_key.as_bytes(),
+                Keyring::from_settings(settings)?,
+            )
+            .with_options(base_options.clone())
+            .with_ip_binding(settings.ip_binding);
+            return TenantRegistry::new().with_tenant(tenant);
+        }
+
+        settings
+            .tenants
+            .split(',')
+            .filter(|entry| !entry.trim().is_empty())
+            .map(|entry| Tenant::parse(entry, base_options))
+            .try_fold(TenantRegistry::new(), |registry, tenant| {
+                registry.with_tenant(tenant?.with_ip_binding(settings.ip_binding))
+            })
+    }
+
+    /// Adds a tenant. Sitekey, secret and the combination of account and app id must be unique, so
+    /// that puzzles of one tenant are never accepted for another.
+    pub fn with_tenant(mut self, tenant: Tenant) -> Result<TenantRegistry, TenantError> {
+        if self.tenants.contains_key(&tenant.sitekey) {
+            return Err(TenantError::DuplicateSitekey(tenant.sitekey));
+        }
+        for other in self.tenants.values() {
+            if other.matches_secret(&tenant.secret) {
+                return Err(TenantError::DuplicateSecret);
+            }
+            if (other.options.account_id, other.options.app_id)
+                == (tenant.options.account_id, tenant.options.app_id)
+            {
+                return Err(TenantError::DuplicateOrigin(
+                    tenant.options.account_id,
+                    tenant.options.app_id,
+                ));
+            }
+        }
+        self.tenants.insert(tenant.sitekey.clone(), tenant);
+        Ok(self)
+    }
+
+    /// Returns the tenant with the given `sitekey`.
+    pub fn get(&self, sitekey: &str) -> Option<&Tenant> {
+        self.tenants.get(sitekey)
+    }
+
+    /// Returns the tenant a verification request with `secret` is for. If `sitekey` is given, the
+    /// secret must belong to the tenant with that sitekey.
+    pub fn resolve(&self, sitekey: Option<&str>, secret: &[u8]) -> Option<&Tenant> {
+        match sitekey {
+            Some(sitekey) => self.get(sitekey),
+            // All secrets are compared, so that the time taken does not reveal the tenant.
+            None => self.tenants.values().fold(None, |found, tenant| {
+                if tenant.matches_secret(secret) {
+                    Some(tenant)
+                } else {
+                    found
+                }
+            }),
+        }
+        .filter(|tenant| tenant.matches_secret(secret))
+    }
+
+    /// Returns the number of tenants.
+    pub fn len(&self) -> usize {
+        self.tenants.len()
+    }
+
+    /// Returns `true` if there are no tenants.
+    pub fn is_empty(&self) -> bool {
+        self.tenants.is_empty()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::build_puzzle::{build_puzzle_with, BuildPuzzleError};
+    use crate::puzzle::SignedPuzzle;
+    use crate::store::MemoryStore;
+    use crate::verify_puzzle_result::{verify_puzzle_result_with, VerifyPuzzleResultError};
+
+    fn parse(entry: &str) -> Result<Tenant, TenantError> {
+        Tenant::parse(entry, &PuzzleOptions::default())
+    }
+
+    fn registry() -> TenantRegistry {
+        TenantRegistry::new()
+            .with_tenant(parse("A;SECRET-A;KEY-A;1;1;").unwrap())
+            .unwrap()
+            .with_tenant(
+                parse(
+                    "B;SECRET-B;KEY-A;1;2;https://b.example https://b.example:8080;0:1:10 3:2:20",
+                )
+                .unwrap(),
+            )
+            .unwrap()
+    }
+
+    #[test]
+    fn test_parse() {
+        let registry = registry();
+        let tenant = registry.get("B").unwrap();
+        assert_eq!(tenant.options.app_id, 2);
+        assert_eq!(
+            tenant.allowed_origins,
+            ["https://b.example", "https://b.example:8080"]
+        );
+        assert!(tenant.matches_secret("SECRET-B".as_bytes()));
+        assert!(registry.get("A").unwrap().allows_origin(None));
+        assert!(!tenant.allows_origin(None));
+        let puzzle = build_puzzle_with(
+            &MemoryStore::new(),
+            "127.0.0.1",
+            None,
+            1693469848,
+            0,
+            &tenant.keyring,
+            1800,
+            &tenant.options,
+        )
+        .unwrap();
+        let puzzle = puzzle.parse::<SignedPuzzle>().unwrap().puzzle;
+        assert_eq!((puzzle.solution_count, puzzle.difficulty), (1, 10));
+
+        assert!(matches!(
+            parse("A;SECRET-A;KEY-A;1;1"),
+            Err(TenantError::ConfigMalformed(_))
+        ));
+        assert!(matches!(
+            parse("A;SECRET-A;KEY-A;1;1;;0:1"),
+            Err(TenantError::ConfigMalformed(_))
+        ));
+        assert!(matches!(
+            parse("A;SECRET-A;KEY-A;one;1;"),
+            Err(TenantError::ConfigMalformed(_))
+        ));
+    }
+
+    #[test]
+    fn test_with_tenant_rejects_duplicates() {
+        let tenant = |entry| parse(entry).unwrap();
+        assert_eq!(
+            registry()
+                .with_tenant(tenant("A;SECRET-C;KEY-C;1;3;"))
+                .err(),
+            Some(TenantError::DuplicateSitekey("A".to_string()))
+        );
+        assert_eq!(
+            registry()
+                .with_tenant(tenant("C;SECRET-A;KEY-C;1;3;"))
+                .err(),
+            Some(TenantError::DuplicateSecret)
+        );
+        assert_eq!(
+            registry()
+                .with_tenant(tenant("C;SECRET-C;KEY-C;1;2;"))
+                .err(),
+            Some(TenantError::DuplicateOrigin(1, 2))
+        );
+    }
+
+    #[test]
+    fn test_resolve() {
+        let registry = registry();
+        let resolve = |sitekey, secret: &str| {
+            registry
+                .resolve(sitekey, secret.as_bytes())
+                .map(|tenant| tenant.sitekey.as_str())
+        };
+        assert_eq!(resolve(None, "SECRET-B"), Some("B"));
+        assert_eq!(resolve(Some("B"), "SECRET-B"), Some("B"));
+        assert_eq!(resolve(Some("A"), "SECRET-B"), None);
+        assert_eq!(resolve(None, "SECRET-C"), None);
+        // Prefixes and extensions of a secret do not match.
+        assert_eq!(resolve(None, "SECRET-"), None);
+        assert_eq!(resolve(Some("B"), "SECRET-BB"), None);
+        assert_eq!(resolve(None, ""), None);
+    }
+
+    #[test]
+    fn test_solution_rejected_for_other_tenant() -> Result<(), BuildPuzzleError> {
+        let registry = registry();
+        let (tenant_a, tenant_b) = (registry.get("A").unwrap(), registry.get("B").unwrap());
+        let store = MemoryStore::new();
+        let puzzle = build_puzzle_with(
+            &store,
+            "127.0.0.1",
+            None,
+            1693469848,
+            0,
+            &tenant_a.keyring,
+            1800,
+            &tenant_a.options,
+        )?;
+        // No solutions are required, so only the tenant checks can fail.
+        let mut signed_puzzle: SignedPuzzle = puzzle.parse().unwrap();
+        signed_puzzle.puzzle.solution_count = 0;
+        signed_puzzle.signature = tenant_a.keyring.sign(&mut signed_puzzle.puzzle)?;
+        let solution = format!("{}..AgAA", signed_puzzle);
+
+        let verdict = verify_puzzle_result_with(
+            &store,
+            &solution,
+            None,
+            1693469848,
+            3600,
+            &tenant_b.keyring,
+            &tenant_b.options,
+        );
+        assert_eq!(
+            verdict.into_result(),
+            Err(VerifyPuzzleResultError::AppMismatch)
+        );
+        let verdict = verify_puzzle_result_with(
+            &store,
+            &solution,
+            None,
+            1693469848,
+            3600,
+            &tenant_a.keyring,
+            &tenant_a.options,
+        );
+        assert!(verdict.is_ok());
+        Ok(())
+    }
+}
@@ -1,31 +1,25 @@
-use crate::config::get;
-use crate::util;
+use crate::build_puzzle::PuzzleOptions;
+use crate::engine::default_fcaptcha;
+use crate::keyring::{Keyring, KeyringError};
+use crate::puzzle::{
+    difficulty_threshold, Diagnostics, ParsePuzzleError, Puzzle, PuzzleSolution, PUZZLE_LEN_BYTE,
+    SOLUTION_LEN_BYTE,
+};
+use crate::store::{CaptchaStore, StoreError};
 use base64::DecodeError;
-use base64::{engine::general_purpose, Engine as _};
 use blake2::{digest::consts::U32, Blake2b, Digest};
 use digest::{InvalidLength, MacError};
 use displaydoc::Display;
 use hex::FromHexError;
-use hmac::{Hmac, Mac};
-use log::Level::Info;
-use sha2::Sha256;
-use std::collections::{HashMap, HashSet};
+use std::collections::HashSet;
 use std::str;
-use std::sync::{Mutex, PoisonError};
+use std::sync::PoisonError;
 use std::time::SystemTimeError;
 use thiserror::Error;
 
-const SOLUTION_PARTS_COUNT: usize = 4;
-const PUZZLE_BIN_LEN_BYTE: usize = 32;
-const PUZZLE_B64_LEN_BYTE: usize = 44;
-
-lazy_static! {
-    // TODO: Empty maps periodically!
-    static ref VERIFIED_PUZZLE_TO_TIMESTAMP_MAP: Mutex<HashMap<Vec<u8>, u64>> =
-        Mutex::new(HashMap::new());
-    static ref PUZZLE_TTL: u64 = get::<u64>("PUZZLE_TTL");
-    static ref SECRET_KEY: Vec<u8> = get::<Vec<u8>>("SECRET_KEY");
-}
+/// Time in seconds puzzles may be issued in the future, as seen by the verifying server, to
+/// tolerate clocks of servers sharing a store being slightly out of sync.
+pub const MAX_CLOCK_SKEW_SECS: u64 = 5;
 
 /// Describes an error that occurred during verifying a puzzle result.
 #[derive(Display, Error, Debug, PartialEq)]
@@ -34,16 +28,35 @@ pub enum VerifyPuzzleResultError {
     SignatureKeyInvalid(#[from] InvalidLength),
     /// Signatures do not match.
     SignatureMismatch(#[from] MacError),
+    /// Puzzle was signed with the unknown or retired key {0}.
+    UnknownKey(u32),
     /// Puzzle is reused.
     PuzzleReuse,
     /// Puzzle is expired.
     PuzzleExpired,
+    /// Puzzle was issued further in the future than the tolerated clock skew.
+    PuzzleFromFuture,
+    /// Puzzle was issued for another account.
+    AccountMismatch,
+    /// Puzzle was issued for another app.
+    AppMismatch,
+    /// Puzzle was bound to another context.
+    ContextMismatch,
     /// Duplicate Solution.
     DuplicateSolution,
     /// Solution below threshold.
     SolutionBelowThreshold,
+    /// Solutions are {actual} bytes long, expected at least {expected}.
+    SolutionTooShort {
+        /// Length required by the solution count of the puzzle.
+        expected: usize,
+        /// Length of the submitted solutions.
+        actual: usize,
+    },
     /// Data access failed.
     DataAccess,
+    /// Store access failed: {0}
+    Store(#[from] StoreError),
     /// Data conversion failed.
     Conversion,
     /// Decoding hex failed.
@@ -54,16 +67,180 @@ pub enum VerifyPuzzleResultError {
     TimeError,
     /// Input malformed
     InputMalformed,
+    /// Configuration invalid: {0}
+    Config(String),
     /// Unknown error.
     Unknown,
 }
 
+impl VerifyPuzzleResultError {
+    /// Returns the error code reported for this error by the FriendlyCaptcha `siteverify` API.
+    /// Errors caused by the server instead of the submitted solution are reported as
+    /// `internal_error`, which is not part of the FriendlyCaptcha API.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use fcaptcha::verify_puzzle_result::VerifyPuzzleResultError;
+    ///
+    /// assert_eq!(
+    ///     VerifyPuzzleResultError::PuzzleReuse.error_code(),
+    ///     "solution_timeout_or_duplicate"
+    /// );
+    /// ```
+    pub fn error_code(&self) -> &'static str {
+        match self {
+            Self::SignatureMismatch(_)
+            | Self::UnknownKey(_)
+            | Self::AccountMismatch
+            | Self::AppMismatch
+            | Self::ContextMismatch
+            | Self::DuplicateSolution
+            | Self::SolutionBelowThreshold
+            | Self::SolutionTooShort { .. }
+            | Self::Conversion
+            | Self::DecodeHex(_)
+            | Self::DecodeBas64(_)
+            | Self::InputMalformed => "solution_invalid",
+            Self::PuzzleReuse | Self::PuzzleExpired | Self::PuzzleFromFuture => {
+                "solution_timeout_or_duplicate"
+            }
+            Self::SignatureKeyInvalid(_)
+            | Self::DataAccess
+            | Self::Store(_)
+            | Self::TimeError
+            | Self::Config(_)
+            | Self::Unknown => "internal_error",
+        }
+    }
+
+    /// Returns `true` if the error is caused by the server instead of the submitted solution.
+    pub fn is_internal(&self) -> bool {
+        self.error_code() == "internal_error"
+    }
+}
+
+/// Names a check performed during verifying a puzzle result, in the order they are performed.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Check {
+    /// Loading the configuration of the free function [verify_puzzle_result].
+    Config,
+    /// Getting the current time.
+    Time,
+    /// Parsing the solution.
+    Parse,
+    /// Verifying the signature of the puzzle.
+    Signature,
+    /// Checking the puzzle was issued for the expected account and app.
+    Origin,
+    /// Checking the puzzle was bound to the given context, if any.
+    Context,
+    /// Checking the puzzle is not expired.
+    Expiry,
+    /// Verifying the solutions of the puzzle.
+    Solutions,
+    /// Checking the puzzle was not used before, marking it as used.
+    Reuse,
+}
+
+/// Describes a failed check and the error it failed with.
+#[derive(Debug, PartialEq)]
+pub struct Failure {
+    /// The check that failed.
+    pub check: Check,
+    /// The error the check failed with.
+    pub error: VerifyPuzzleResultError,
+}
+
+impl Failure {
+    pub(crate) fn at<E: Into<VerifyPuzzleResultError>>(check: Check) -> impl FnOnce(E) -> Failure {
+        move |err| Failure {
+            check,
+            error: err.into(),
+        }
+    }
+}
+
+/// The outcome of verifying a puzzle result together with everything decoded along the way.
+#[derive(Debug, PartialEq)]
+pub struct Verdict {
+    /// The decoded puzzle, `None` if the solution could not be parsed. Holds the timestamp,
+    /// difficulty, solution count, account and app id of the puzzle.
+    pub puzzle: Option<Puzzle>,
+    /// Age of the puzzle at the time of verification in seconds, `None` if the solution could
+    /// not be parsed or the puzzle is from the future. Puzzles issued in the future within
+    /// [MAX_CLOCK_SKEW_SECS] have the age 0.
+    pub age: Option<u64>,
+    /// Diagnostics reported by the solver, `None` if the solution could not be parsed or they are
+    /// malformed.
+    pub diagnostics: Option<Diagnostics>,
+    /// The first failed check, `None` if the puzzle result is valid.
+    pub failure: Option<Failure>,
+}
+
+impl Verdict {
+    pub(crate) fn failed(failure: Failure) -> Verdict {
+        Verdict {
+            puzzle: None,
+            age: None,
+            diagnostics: None,
+            failure: Some(failure),
+        }
+    }
+
+    /// Returns `true` if the puzzle result is valid.
+    pub fn is_ok(&self) -> bool {
+        self.failure.is_none()
+    }
+
+    /// Returns the first failed check, `None` if the puzzle result is valid.
+    pub fn failed_check(&self) -> Option<Check> {
+        self.failure.as_ref().map(|failure| failure.check)
+    }
+
+    /// Returns the error of the first failed check, `None` if the puzzle result is valid.
+    pub fn error(&self) -> Option<&VerifyPuzzleResultError> {
+        self.failure.as_ref().map(|failure| &failure.error)
+    }
+
+    /// Discards the decoded information and returns only whether the puzzle result is valid.
+    pub fn into_result(self) -> Result<(), VerifyPuzzleResultError> {
+        match self.failure {
+            Some(failure) => Err(failure.error),
+            None => Ok(()),
+        }
+    }
+}
+
 impl<T> From<PoisonError<T>> for VerifyPuzzleResultError {
     fn from(_err: PoisonError<T>) -> Self {
         Self::DataAccess
     }
 }
 
+impl From<ParsePuzzleError> for VerifyPuzzleResultError {
+    fn from(err: ParsePuzzleError) -> Self {
+        match err {
+            ParsePuzzleError::InputMalformed | ParsePuzzleError::InvalidLength => {
+                Self::InputMalformed
+            }
+            ParsePuzzleError::DecodeHex(err) => Self::DecodeHex(err),
+            ParsePuzzleError::DecodeBase64(err) => Self::DecodeBas64(err),
+        }
+    }
+}
+
+impl From<KeyringError> for VerifyPuzzleResultError {
+    fn from(err: KeyringError) -> Self {
+        match err {
+            KeyringError::UnknownKey(key_id) => Self::UnknownKey(key_id),
+            KeyringError::InvalidKey(err) => Self::SignatureKeyInvalid(err),
+            KeyringError::SignatureMismatch => Self::SignatureMismatch(MacError),
+            KeyringError::ConfigMalformed(_) => Self::Unknown,
+        }
+    }
+}
+
 impl From<SystemTimeError> for VerifyPuzzleResultError {
     fn from(_err: SystemTimeError) -> Self {
         Self::TimeError
@@ -71,7 +248,9 @@ impl From<SystemTimeError> for VerifyPuzzleResultError {
 }
 
 /// Verifies a puzzle result given by `solution`.
-/// Can be configured with the environment variables `PUZZLE_TTL` and `FCAPTCHA_SECRET_KEY`.
+/// Can be configured with the environment variable `FCAPTCHA_PUZZLE_TTL` and the ones read by
+/// [Keyring::from_settings] and [PuzzleOptions::from_settings]. Fails at [Check::Config] if the
+/// configuration is malformed or invalid.
 ///
 /// # Examples
 ///
@@ -85,21 +264,33 @@ impl From<SystemTimeError> for VerifyPuzzleResultError {
 /// AABIgQAAJAAAAJoPAQAlAAAAYlgAACYAAABIbAAAJwAAAGCwAAAoAAAAokkAACkAAADl6gAAKgAAAAo5AQArAAAA5igAAC\
 /// wAAADVfAAALQAAAHYfAAAuAAAALdYAAC8AAAC11gEAMAAAAN1dAAAxAAAAbyEAADIAAADjwAAA.\
 /// AgAA";
-/// let result = fcaptcha::verify_puzzle_result(solution);
-/// println!("Verification result: {:?}", result);
+/// let verdict = fcaptcha::verify_puzzle_result(solution);
+/// println!("Verification verdict: {:?}", verdict);
 /// ```
-pub fn verify_puzzle_result(solution: &str) -> Result<(), VerifyPuzzleResultError> {
-    let timestamp = util::get_timestamp()?;
-    verify_puzzle_result_with(solution, timestamp, *PUZZLE_TTL, &SECRET_KEY)
+pub fn verify_puzzle_result(solution: &str) -> Verdict {
+    match default_fcaptcha() {
+        Ok((fcaptcha, tenant)) => fcaptcha.verify(tenant, solution, None),
+        Err(err) => Verdict::failed(Failure::at(Check::Config)(VerifyPuzzleResultError::Config(
+            err,
+        ))),
+    }
 }
 
-/// Verifies a puzzle result. In contrast to [verify_puzzle_result] all input variables can be controlled
-/// directly instead deriving them from environment variables.
+/// Verifies a puzzle result. In contrast to [verify_puzzle_result] all input variables can be
+/// controlled directly instead deriving them from environment variables. Used puzzles are recorded
+/// in `store`. The puzzle must have been issued for the account and app given by `options` and
+/// signed with a key of `keyring`. Puzzles bound to a context are only accepted if the same
+/// `context` is given, unbound puzzles only without a context. The returned [Verdict] holds the
+/// decoded puzzle and diagnostics as far as the solution could be parsed.
 ///
 /// # Examples
 ///
 /// ```
-/// let secret_key = "NOT-A-SECRET-KEY".as_bytes();
+/// use fcaptcha::build_puzzle::PuzzleOptions;
+/// use fcaptcha::store::MemoryStore;
+/// use fcaptcha::Keyring;
+/// let store = MemoryStore::new();
+/// let keyring = Keyring::new(0, "NOT-A-SECRET-KEY".as_bytes());
 /// let solution = "3761fae80ef01b32dcf892d099ca07f31db7a97311cce59529a4bae93a801db4.\
 /// ZO+cGAAAAAEAAAABAQwzegAAAAAAAAAAWlXMkohinFU=.\
 /// AAAAAIgRAAABAAAAzHwAAAIAAAAuDQAAAwAAAPsUAAAEAAAACaMAAAUAAADEGgAABgAAAEcSAAAHAAAAvz0AAAgAAABhpQ\
@@ -111,124 +302,199 @@ pub fn verify_puzzle_result(solution: &str) -> Result<(), VerifyPuzzleResultErro
 /// AgAA";
 /// let puzzle_ttl_secs = 3600;
 /// let timestamp: u64 = 1693424664;
-/// let result =
-///      fcaptcha::verify_puzzle_result_with(solution, timestamp, puzzle_ttl_secs, secret_key);
-/// assert!(result.is_ok())
+/// let options = PuzzleOptions::default();
+/// let verdict = fcaptcha::verify_puzzle_result_with(
+///     &store,
+///     solution,
+///     None,
+///     timestamp,
+///     puzzle_ttl_secs,
+///     &keyring,
+///     &options,
+/// );
+/// assert!(verdict.is_ok());
+/// assert_eq!(verdict.puzzle.unwrap().difficulty, 122);
+/// assert_eq!(verdict.age, Some(0));
 /// ```
-pub fn verify_puzzle_result_with(
+pub fn verify_puzzle_result_with<S: CaptchaStore + ?Sized>(
+    store: &S,
     solution: &str,
+    context: Option<&[u8]>,
     timestamp: u64,
     puzzle_ttl_secs: u64,
-    secret_key: &[u8],
-) -> Result<(), VerifyPuzzleResultError> {
-    let solution_parts: Vec<&str> = solution.splitn(SOLUTION_PARTS_COUNT, '.').collect();
-
-    if solution_parts.len() != SOLUTION_PARTS_COUNT {
-        return Err(VerifyPuzzleResultError::InputMalformed);
+    keyring: &Keyring,
+    options: &PuzzleOptions,
+) -> Verdict {
+    info!("Trying to decode solution: {:?}", solution);
+    let solution: PuzzleSolution = match solution.parse::<PuzzleSolution>() {
+        Ok(solution) => solution,
+        Err(err) => return Verdict::failed(Failure::at(Check::Parse)(err)),
+    };
+    let failure = check_solution(
+        store,
+        &solution,
+        context,
+        timestamp,
+        puzzle_ttl_secs,
+        keyring,
+        options,
+    )
+    .err();
+    if failure.is_none() {
+        info!("Puzzle solutions verified successfully for: {}", solution);
     }
 
-    let signature = hex::decode(solution_parts[0])?;
-
-    info!("Trying to decode puzzle: {:?}", solution_parts[1]);
-    if solution_parts[1].len() != PUZZLE_B64_LEN_BYTE {
-        return Err(VerifyPuzzleResultError::InputMalformed);
+    Verdict {
+        age: puzzle_age(&solution.puzzle, timestamp),
+        diagnostics: process_diagnostics(&solution.diagnostics),
+        puzzle: Some(solution.puzzle),
+        failure,
     }
-    // 2 additional bytes needed: https://docs.rs/base64/latest/base64/fn.decoded_len_estimate.html
-    let mut puzzle_padded: [u8; PUZZLE_BIN_LEN_BYTE + 2] = [0; PUZZLE_BIN_LEN_BYTE + 2];
-    general_purpose::STANDARD.decode_slice_unchecked(solution_parts[1], &mut puzzle_padded)?;
-    let puzzle = &puzzle_padded[..PUZZLE_BIN_LEN_BYTE];
+}
 
-    verify_signature(secret_key, puzzle, &signature)?;
-    check_puzzle_reuse(puzzle, puzzle_ttl_secs, timestamp)?;
-    check_puzzle_expiry(puzzle, timestamp)?;
-    process_diagnostics(solution_parts[3])?;
-    verify_solutions(puzzle, solution_parts[2])?;
+fn check_solution<S: CaptchaStore + ?Sized>(
+    store: &S,
+    solution: &PuzzleSolution,
+    context: Option<&[u8]>,
+    timestamp: u64,
+    puzzle_ttl_secs: u64,
+    keyring: &Keyring,
+    options: &PuzzleOptions,
+) -> Result<(), Failure> {
+    let puzzle = &solution.puzzle;
+    let puzzle_bytes = puzzle.to_bytes();
 
-    info!("Puzzle solutions verified successfully for: {:?}", solution);
+    keyring
+        .verify(puzzle, &solution.signature)
+        .map_err(Failure::at(Check::Signature))?;
+    check_puzzle_origin(puzzle, options).map_err(Failure::at(Check::Origin))?;
+    check_puzzle_context(puzzle, context, keyring).map_err(Failure::at(Check::Context))?;
+    check_puzzle_expiry(puzzle, timestamp).map_err(Failure::at(Check::Expiry))?;
+    verify_solutions(puzzle, &solution.solutions).map_err(Failure::at(Check::Solutions))?;
+    // Only valid puzzles are marked as used, so invalid ones can't fill the store.
+    check_puzzle_reuse(store, &puzzle_bytes, puzzle_ttl_secs, timestamp)
+        .map_err(Failure::at(Check::Reuse))
+}
+
+fn check_puzzle_origin(
+    puzzle: &Puzzle,
+    options: &PuzzleOptions,
+) -> Result<(), VerifyPuzzleResultError> {
+    if puzzle.account_id != options.account_id {
+        info!(
+            "Puzzle for account: {:?}, expected: {:?}",
+            puzzle.account_id, options.account_id
+        );
+        return Err(VerifyPuzzleResultError::AccountMismatch);
+    }
+    if puzzle.app_id != options.app_id {
+        info!(
+            "Puzzle for app: {:?}, expected: {:?}",
+            puzzle.app_id, options.app_id
+        );
+        return Err(VerifyPuzzleResultError::AppMismatch);
+    }
     Ok(())
 }
 
-fn verify_signature(
-    secret_key: &[u8],
-    puzzle: &[u8],
-    signature: &[u8],
+fn check_puzzle_context(
+    puzzle: &Puzzle,
+    context: Option<&[u8]>,
+    keyring: &Keyring,
 ) -> Result<(), VerifyPuzzleResultError> {
-    type HmacSha256 = Hmac<Sha256>;
-    let mut macer = HmacSha256::new_from_slice(secret_key)?;
-    macer.update(puzzle);
-    macer.verify_slice(signature)?;
+    let expected_tag = match context {
+        Some(context) => keyring.context_tag(puzzle.key_id, context)?,
+        None => [0; 4],
+    };
+    if puzzle.context_tag != expected_tag {
+        info!(
+            "Puzzle for context tag: {:?}, expected: {:?}",
+            puzzle.context_tag, expected_tag
+        );
+        return Err(VerifyPuzzleResultError::ContextMismatch);
+    }
     Ok(())
 }
 
-fn check_puzzle_reuse(
+fn check_puzzle_reuse<S: CaptchaStore + ?Sized>(
+    store: &S,
     puzzle: &[u8],
     puzzle_ttl: u64,
     current_timestamp: u64,
 ) -> Result<(), VerifyPuzzleResultError> {
-    let mut map = VERIFIED_PUZZLE_TO_TIMESTAMP_MAP.lock()?;
-
-    let puzzle_option = map.get_mut(&puzzle.to_vec());
-
-    match puzzle_option {
-        Some(timestamp) => {
-            if current_timestamp - *timestamp < puzzle_ttl {
-                info!("Puzzle reuse with: {:?}", puzzle);
-                return Err(VerifyPuzzleResultError::PuzzleReuse);
-            } else {
-                info!("Expired puzzle reuse with: {:?}", puzzle);
-                *timestamp = current_timestamp;
-            }
-        }
-        None => {
-            info!("New puzzle with: {:?}", puzzle);
-            map.insert(puzzle.to_vec(), current_timestamp);
-        }
+    if !store.mark_puzzle_used(puzzle, current_timestamp, puzzle_ttl)? {
+        return Err(VerifyPuzzleResultError::PuzzleReuse);
     }
     Ok(())
 }
 
-fn check_puzzle_expiry(puzzle: &[u8], timestamp: u64) -> Result<(), VerifyPuzzleResultError> {
-    let timestamp_received = u32::from_be_bytes(
-        puzzle[0..4]
-            .try_into()
-            .map_err(|_| VerifyPuzzleResultError::Conversion)?,
-    );
-    let age: u64 = timestamp - u64::from(timestamp_received);
-    let expiry: u32 = u32::from(puzzle[13]) * 300;
+/// Returns the age of `puzzle` at `timestamp`, counting puzzles issued in the future within
+/// [MAX_CLOCK_SKEW_SECS] as new.
+fn puzzle_age(puzzle: &Puzzle, timestamp: u64) -> Option<u64> {
+    let puzzle_timestamp = u64::from(puzzle.timestamp);
+    (timestamp.saturating_add(MAX_CLOCK_SKEW_SECS) >= puzzle_timestamp)
+        .then(|| timestamp.saturating_sub(puzzle_timestamp))
+}
 
-    if (expiry != 0) && (age > u64::from(expiry)) {
-        info!("Expired puzzle, age: {:?}, expiry: {:?}", age, expiry);
-        return Err(VerifyPuzzleResultError::PuzzleExpired);
+fn check_puzzle_expiry(puzzle: &Puzzle, timestamp: u64) -> Result<(), VerifyPuzzleResultError> {
+    // Puzzles are only issued by servers sharing the key, so a timestamp in the future points to
+    // clocks out of sync between them, which is tolerated up to a few seconds.
+    let age = puzzle_age(puzzle, timestamp).ok_or_else(|| {
+        info!(
+            "Puzzle from the future, timestamp: {:?}, now: {:?}",
+            puzzle.timestamp, timestamp
+        );
+        VerifyPuzzleResultError::PuzzleFromFuture
+    })?;
+
+    if let Some(expiry) = puzzle.expiry_secs() {
+        if age > expiry {
+            info!("Expired puzzle, age: {:?}, expiry: {:?}", age, expiry);
+            return Err(VerifyPuzzleResultError::PuzzleExpired);
+        }
     }
     Ok(())
 }
 
-fn verify_solutions(puzzle: &[u8], solutions: &str) -> Result<(), VerifyPuzzleResultError> {
-    let difficulty = puzzle[15];
-    let solutions_count = puzzle[14];
-    // TODO: Why use floats?
-    let threshold: u32 = (2_f32.powf(255.999 - f32::from(difficulty)) / 8_f32).floor() as u32;
-    let mut seen_solutions = HashSet::<&[u8]>::new();
-    let solutions_decoded = general_purpose::STANDARD.decode(solutions)?;
+/// Verifies `solutions`, which holds `puzzle.solution_count` solutions of [SOLUTION_LEN_BYTE]
+/// bytes each. Trailing bytes are ignored.
+fn verify_solutions(puzzle: &Puzzle, solutions: &[u8]) -> Result<(), VerifyPuzzleResultError> {
+    let difficulty = puzzle.difficulty;
+    let solutions_count = usize::from(puzzle.solution_count);
+    let expected_len = solutions_count * SOLUTION_LEN_BYTE;
+    if solutions.len() < expected_len {
+        info!(
+            "Solutions too short: {:?} bytes, expected: {:?}",
+            solutions.len(),
+            expected_len
+        );
+        return Err(VerifyPuzzleResultError::SolutionTooShort {
+            expected: expected_len,
+            actual: solutions.len(),
+        });
+    }
 
-    for solution_idx in 0..solutions_count {
-        let current_start_idx = usize::from(solution_idx);
-        let current_solution = &solutions_decoded[current_start_idx..current_start_idx + 8];
+    let puzzle_bytes = puzzle.to_bytes();
+    let threshold = difficulty_threshold(difficulty);
+    let mut seen_solutions = HashSet::<&[u8]>::new();
 
-        if seen_solutions.contains(current_solution) {
+    for current_solution in solutions
+        .chunks_exact(SOLUTION_LEN_BYTE)
+        .take(solutions_count)
+    {
+        if !seen_solutions.insert(current_solution) {
             info!("Duplicate solution found: {:?}", current_solution);
             return Err(VerifyPuzzleResultError::DuplicateSolution);
         }
-        seen_solutions.insert(current_solution);
 
         let mut full_solution: [u8; 128] = [0; 128];
-        full_solution[0..32].copy_from_slice(puzzle);
-        full_solution[120..128].copy_from_slice(current_solution);
+        full_solution[0..PUZZLE_LEN_BYTE].copy_from_slice(&puzzle_bytes);
+        full_solution[128 - SOLUTION_LEN_BYTE..].copy_from_slice(current_solution);
         info!("Full solution: {:?}", full_solution);
 
         type Blake2b256 = Blake2b<U32>;
         let hash = Blake2b256::digest(full_solution);
-        info!("Solution hash: {:?}", full_solution);
+        info!("Solution hash: {:?}", hash);
 
         let solution_leading = u32::from_le_bytes(
             hash[0..4]
@@ -251,21 +517,45 @@ fn verify_solutions(puzzle: &[u8], solutions: &str) -> Result<(), VerifyPuzzleRe
     Ok(())
 }
 
-fn process_diagnostics(diagnostics: &str) -> Result<(), VerifyPuzzleResultError> {
-    if log_enabled!(Info) {
-        let diagnostics = general_purpose::STANDARD.decode(diagnostics)?;
-        info!("Got diagnostics: {:?}", diagnostics);
-    }
-    Ok(())
+fn process_diagnostics(diagnostics: &[u8]) -> Option<Diagnostics> {
+    let parsed = Diagnostics::from_bytes(diagnostics);
+    info!("Got diagnostics: {:?}, parsed: {:?}", diagnostics, parsed);
+    parsed
 }
 
 #[cfg(test)]
 mod test {
     use super::*;
+    use crate::difficulty::{Scaling, Tier, TierTable};
+    use crate::solve_puzzle::SolveOptions;
+    use crate::store::MemoryStore;
+    use base64::{engine::general_purpose, Engine as _};
+    use proptest::prelude::*;
+
+    fn build_solved_puzzle(
+        keyring: &Keyring,
+        options: &PuzzleOptions,
+        ip_address: &str,
+        timestamp: u64,
+        nonce: u64,
+    ) -> String {
+        let puzzle = crate::build_puzzle_with(
+            &MemoryStore::new(),
+            ip_address,
+            None,
+            timestamp,
+            nonce,
+            keyring,
+            1800,
+            options,
+        )
+        .unwrap();
+        crate::solve_puzzle_with(&puzzle, &SolveOptions::default()).unwrap()
+    }
 
     #[test]
     fn test_verify_puzzle_result_with_primitive_success() {
-        let secret_key = "NOT-A-SECRET-KEY".as_bytes();
+        let keyring = Keyring::from("NOT-A-SECRET-KEY".as_bytes());
         let solution = "3761fae80ef01b32dcf892d099ca07f31db7a97311cce59529a4bae93a801db4.\
         ZO+cGAAAAAEAAAABAQwzegAAAAAAAAAAWlXMkohinFU=.\
         AAAAAIgRAAABAAAAzHwAAAIAAAAuDQAAAwAAAPsUAAAEAAAACaMAAAUAAADEGgAABgAAAEcSAAAHAAAAvz0AAAgAAABhpQ\
@@ -276,14 +566,38 @@ mod test {
         wAAADVfAAALQAAAHYfAAAuAAAALdYAAC8AAAC11gEAMAAAAN1dAAAxAAAAbyEAADIAAADjwAAA.\
         AgAA";
         let timestamp: u64 = 1693424664;
+        let options = PuzzleOptions::default();
 
-        let result = verify_puzzle_result_with(solution, timestamp, 0, &secret_key);
-        assert!(result.is_ok())
+        let result = verify_puzzle_result_with(
+            &MemoryStore::new(),
+            solution,
+            None,
+            timestamp,
+            0,
+            &keyring,
+            &options,
+        );
+        assert!(result.is_ok());
+        assert_eq!(result.failed_check(), None);
+        let puzzle = result.puzzle.unwrap();
+        assert_eq!(puzzle.timestamp, 1693424664);
+        assert_eq!(puzzle.difficulty, 122);
+        assert_eq!(puzzle.solution_count, 51);
+        assert_eq!(puzzle.account_id, 1);
+        assert_eq!(puzzle.app_id, 1);
+        assert_eq!(result.age, Some(0));
+        assert_eq!(
+            result.diagnostics,
+            Some(Diagnostics {
+                solver_id: 2,
+                solve_time_secs: 0
+            })
+        );
     }
 
     #[test]
     fn test_verify_puzzle_result_with_primitive_signature_error() {
-        let secret_key = "THE-WRONG-SECRET-KEY".as_bytes();
+        let keyring = Keyring::from("THE-WRONG-SECRET-KEY".as_bytes());
         let solution = "3761fae80ef01b32dcf892d099ca07f31db7a97311cce59529a4bae93a801db4.\
         ZO+cGAAAAAEAAAABAQwzegAAAAAAAAAAWlXMkohinFU=.\
         AAAAAIgRAAABAAAAzHwAAAIAAAAuDQAAAwAAAPsUAAAEAAAACaMAAAUAAADEGgAABgAAAEcSAAAHAAAAvz0AAAgAAABhpQ\
@@ -294,11 +608,470 @@ mod test {
         wAAADVfAAALQAAAHYfAAAuAAAALdYAAC8AAAC11gEAMAAAAN1dAAAxAAAAbyEAADIAAADjwAAA.\
         AgAA";
         let timestamp: u64 = 1693424664;
+        let options = PuzzleOptions::default();
 
-        let result = verify_puzzle_result_with(solution, timestamp, 0, &secret_key);
+        let result = verify_puzzle_result_with(
+            &MemoryStore::new(),
+            solution,
+            None,
+            timestamp,
+            0,
+            &keyring,
+            &options,
+        );
+        assert_eq!(result.failed_check(), Some(Check::Signature));
         assert_eq!(
-            result,
+            result.into_result(),
             Err(VerifyPuzzleResultError::SignatureMismatch(MacError))
         )
     }
+
+    #[test]
+    fn test_verify_puzzle_result_with_primitive_reuse_error() {
+        let keyring = Keyring::from("NOT-A-SECRET-KEY".as_bytes());
+        let solution = "3761fae80ef01b32dcf892d099ca07f31db7a97311cce59529a4bae93a801db4.\
+        ZO+cGAAAAAEAAAABAQwzegAAAAAAAAAAWlXMkohinFU=.\
+        AAAAAIgRAAABAAAAzHwAAAIAAAAuDQAAAwAAAPsUAAAEAAAACaMAAAUAAADEGgAABgAAAEcSAAAHAAAAvz0AAAgAAABhpQ\
+        AACQAAAAstAAAKAAAA2CYAAAsAAADtNgEADAAAAC0CAAANAAAAFp8AAA4AAABdcgAADwAAAL6JAAAQAAAALYkAABEAAAD0\
+        vAEAEgAAAPxaAAATAAAAvFAAABQAAAAA7wEAFQAAAPoWAAAWAAAAGoEAABcAAACovwAAGAAAAGXcAAAZAAAAP2sBABoAAA\
+        D4BQAAGwAAAE9nAAAcAAAAFcQBAB0AAABQCgEAHgAAAB0FAAAfAAAAe9EAACAAAAClywAAIQAAAFYPAAAiAAAAtjcAACMA\
+        AABIgQAAJAAAAJoPAQAlAAAAYlgAACYAAABIbAAAJwAAAGCwAAAoAAAAokkAACkAAADl6gAAKgAAAAo5AQArAAAA5igAAC\
+        wAAADVfAAALQAAAHYfAAAuAAAALdYAAC8AAAC11gEAMAAAAN1dAAAxAAAAbyEAADIAAADjwAAA.\
+        AgAA";
+        let timestamp: u64 = 1693424664;
+        let options = PuzzleOptions::default();
+        let store = MemoryStore::new();
+
+        let result =
+            verify_puzzle_result_with(&store, solution, None, timestamp, 3600, &keyring, &options);
+        assert!(result.is_ok());
+        let result =
+            verify_puzzle_result_with(&store, solution, None, timestamp, 3600, &keyring, &options);
+        assert_eq!(
+            result.into_result(),
+            Err(VerifyPuzzleResultError::PuzzleReuse)
+        );
+    }
+
+    #[test]
+    fn test_verify_puzzle_result_with_reuse_after_flood() {
+        let keyring = Keyring::from("NOT-A-SECRET-KEY".as_bytes());
+        let options = PuzzleOptions::default().with_difficulty_policy(
+            TierTable::new(vec![Tier {
+                min_access_count: 0,
+                scaling: Scaling::new(4, 100),
+            }])
+            .unwrap(),
+        );
+        let timestamp: u64 = 1693424664;
+        let store = MemoryStore::with_max_entries(4);
+        let verify = |solution: &str| {
+            verify_puzzle_result_with(&store, solution, None, timestamp, 3600, &keyring, &options)
+        };
+        let solution = build_solved_puzzle(&keyring, &options, "192.0.2.1", timestamp, 0);
+        assert!(verify(&solution).is_ok());
+
+        // Invalid solutions are not marked as used.
+        let (puzzle, _) = solution
+            .rsplit_once('.')
+            .unwrap()
+            .0
+            .rsplit_once('.')
+            .unwrap();
+        for _ in 0..8 {
+            let verdict = verify(&format!("{}.AAAAAAAAAAA=.AgAA", puzzle));
+            assert_eq!(verdict.failed_check(), Some(Check::Solutions));
+        }
+        // Valid solutions fill the store up to its capacity and are rejected afterwards.
+        for nonce in 1..8 {
+            let verdict = verify(&build_solved_puzzle(
+                &keyring,
+                &options,
+                "192.0.2.1",
+                timestamp,
+                nonce,
+            ));
+            if nonce < 4 {
+                assert!(verdict.is_ok());
+            } else {
+                assert_eq!(verdict.failed_check(), Some(Check::Reuse));
+                assert_eq!(
+                    verdict.into_result(),
+                    Err(VerifyPuzzleResultError::Store(StoreError::Full))
+                );
+            }
+        }
+
+        assert_eq!(
+            verify(&solution).into_result(),
+            Err(VerifyPuzzleResultError::PuzzleReuse)
+        );
+    }
+
+    #[test]
+    fn test_verify_puzzle_result_with_primitive_app_mismatch_error() {
+        let keyring = Keyring::from("NOT-A-SECRET-KEY".as_bytes());
+        let solution = "3761fae80ef01b32dcf892d099ca07f31db7a97311cce59529a4bae93a801db4.\
+        ZO+cGAAAAAEAAAABAQwzegAAAAAAAAAAWlXMkohinFU=.\
+        AAAAAIgRAAABAAAAzHwAAAIAAAAuDQAAAwAAAPsUAAAEAAAACaMAAAUAAADEGgAABgAAAEcSAAAHAAAAvz0AAAgAAABhpQ\
+        AACQAAAAstAAAKAAAA2CYAAAsAAADtNgEADAAAAC0CAAANAAAAFp8AAA4AAABdcgAADwAAAL6JAAAQAAAALYkAABEAAAD0\
+        vAEAEgAAAPxaAAATAAAAvFAAABQAAAAA7wEAFQAAAPoWAAAWAAAAGoEAABcAAACovwAAGAAAAGXcAAAZAAAAP2sBABoAAA\
+        D4BQAAGwAAAE9nAAAcAAAAFcQBAB0AAABQCgEAHgAAAB0FAAAfAAAAe9EAACAAAAClywAAIQAAAFYPAAAiAAAAtjcAACMA\
+        AABIgQAAJAAAAJoPAQAlAAAAYlgAACYAAABIbAAAJwAAAGCwAAAoAAAAokkAACkAAADl6gAAKgAAAAo5AQArAAAA5igAAC\
+        wAAADVfAAALQAAAHYfAAAuAAAALdYAAC8AAAC11gEAMAAAAN1dAAAxAAAAbyEAADIAAADjwAAA.\
+        AgAA";
+        let timestamp: u64 = 1693424664;
+        let store = MemoryStore::new();
+
+        let options = PuzzleOptions::default().with_app_id(2);
+        let result =
+            verify_puzzle_result_with(&store, solution, None, timestamp, 3600, &keyring, &options);
+        assert_eq!(
+            result.into_result(),
+            Err(VerifyPuzzleResultError::AppMismatch)
+        );
+
+        let options = PuzzleOptions::default().with_account_id(2);
+        let result =
+            verify_puzzle_result_with(&store, solution, None, timestamp, 3600, &keyring, &options);
+        assert_eq!(
+            result.into_result(),
+            Err(VerifyPuzzleResultError::AccountMismatch)
+        );
+
+        // Rejected puzzles are not marked as used.
+        let options = PuzzleOptions::default();
+        let result =
+            verify_puzzle_result_with(&store, solution, None, timestamp, 3600, &keyring, &options);
+        assert!(result.is_ok());
+    }
+
+    #[test]
+    fn test_verify_puzzle_result_with_primitive_unknown_key_error() {
+        let keyring = Keyring::new(1, "NOT-A-SECRET-KEY".as_bytes());
+        let solution = "3761fae80ef01b32dcf892d099ca07f31db7a97311cce59529a4bae93a801db4.\
+        ZO+cGAAAAAEAAAABAQwzegAAAAAAAAAAWlXMkohinFU=.\
+        AAAAAIgRAAABAAAAzHwAAAIAAAAuDQAAAwAAAPsUAAAEAAAACaMAAAUAAADEGgAABgAAAEcSAAAHAAAAvz0AAAgAAABhpQ\
+        AACQAAAAstAAAKAAAA2CYAAAsAAADtNgEADAAAAC0CAAANAAAAFp8AAA4AAABdcgAADwAAAL6JAAAQAAAALYkAABEAAAD0\
+        vAEAEgAAAPxaAAATAAAAvFAAABQAAAAA7wEAFQAAAPoWAAAWAAAAGoEAABcAAACovwAAGAAAAGXcAAAZAAAAP2sBABoAAA\
+        D4BQAAGwAAAE9nAAAcAAAAFcQBAB0AAABQCgEAHgAAAB0FAAAfAAAAe9EAACAAAAClywAAIQAAAFYPAAAiAAAAtjcAACMA\
+        AABIgQAAJAAAAJoPAQAlAAAAYlgAACYAAABIbAAAJwAAAGCwAAAoAAAAokkAACkAAADl6gAAKgAAAAo5AQArAAAA5igAAC\
+        wAAADVfAAALQAAAHYfAAAuAAAALdYAAC8AAAC11gEAMAAAAN1dAAAxAAAAbyEAADIAAADjwAAA.\
+        AgAA";
+        let timestamp: u64 = 1693424664;
+        let options = PuzzleOptions::default();
+
+        let result = verify_puzzle_result_with(
+            &MemoryStore::new(),
+            solution,
+            None,
+            timestamp,
+            0,
+            &keyring,
+            &options,
+        );
+        assert_eq!(
+            result.into_result(),
+            Err(VerifyPuzzleResultError::UnknownKey(0))
+        );
+
+        let keyring = keyring.with_verification_key(0, "NOT-A-SECRET-KEY".as_bytes());
+        let result = verify_puzzle_result_with(
+            &MemoryStore::new(),
+            solution,
+            None,
+            timestamp,
+            0,
+            &keyring,
+            &options,
+        );
+        assert!(result.is_ok());
+    }
+
+    #[test]
+    fn test_verify_puzzle_result_with_primitive_context_mismatch_error() {
+        let keyring = Keyring::from("NOT-A-SECRET-KEY".as_bytes());
+        let options = PuzzleOptions::default();
+        let timestamp: u64 = 1693424664;
+        let verify = |solution: &str, context: Option<&str>| {
+            verify_puzzle_result_with(
+                &MemoryStore::new(),
+                solution,
+                context.map(str::as_bytes),
+                timestamp,
+                0,
+                &keyring,
+                &options,
+            )
+            .failed_check()
+        };
+
+        let puzzle = crate::build_puzzle_with(
+            &MemoryStore::new(),
+            "192.0.2.1",
+            Some("192.0.2.1".as_bytes()),
+            timestamp,
+            0,
+            &keyring,
+            1800,
+            &options,
+        )
+        .unwrap();
+        // Only the context is checked, the solutions are not solved.
+        let solution = format!("{}.{}==.AgAA", puzzle, "A".repeat(86));
+        assert_eq!(verify(&solution, Some("192.0.2.1")), Some(Check::Solutions));
+        assert_eq!(verify(&solution, Some("192.0.2.2")), Some(Check::Context));
+        assert_eq!(verify(&solution, None), Some(Check::Context));
+
+        let unbound_solution = "3761fae80ef01b32dcf892d099ca07f31db7a97311cce59529a4bae93a801db4.\
+        ZO+cGAAAAAEAAAABAQwzegAAAAAAAAAAWlXMkohinFU=.AAAAAIgRAAA=.AgAA";
+        let verdict = verify_puzzle_result_with(
+            &MemoryStore::new(),
+            unbound_solution,
+            Some("192.0.2.1".as_bytes()),
+            timestamp,
+            0,
+            &keyring,
+            &options,
+        );
+        assert_eq!(
+            verdict.error(),
+            Some(&VerifyPuzzleResultError::ContextMismatch)
+        );
+    }
+
+    #[test]
+    fn test_verify_puzzle_result_with_primitive_parse_error() {
+        let verdict = verify_puzzle_result_with(
+            &MemoryStore::new(),
+            "not-a-solution",
+            None,
+            1693424664,
+            0,
+            &Keyring::from("NOT-A-SECRET-KEY".as_bytes()),
+            &PuzzleOptions::default(),
+        );
+        assert_eq!(verdict.failed_check(), Some(Check::Parse));
+        assert_eq!(
+            verdict.error(),
+            Some(&VerifyPuzzleResultError::InputMalformed)
+        );
+        assert_eq!(verdict.puzzle, None);
+    }
+
+    #[test]
+    fn test_verify_puzzle_result_with_primitive_too_short_error() {
+        let keyring = Keyring::from("NOT-A-SECRET-KEY".as_bytes());
+        let options = PuzzleOptions::default().with_difficulty_policy(
+            TierTable::new(vec![Tier {
+                min_access_count: 0,
+                scaling: Scaling::new(4, 100),
+            }])
+            .unwrap(),
+        );
+        let timestamp: u64 = 1693424664;
+        let solution = build_solved_puzzle(&keyring, &options, "192.0.2.1", timestamp, 0);
+        let mut parts: Vec<String> = solution.split('.').map(str::to_string).collect();
+        // Drop the last solution and re-encode the rest.
+        let solutions = general_purpose::STANDARD.decode(&parts[2]).unwrap();
+        parts[2] =
+            general_purpose::STANDARD.encode(&solutions[..solutions.len() - SOLUTION_LEN_BYTE]);
+
+        let verdict = verify_puzzle_result_with(
+            &MemoryStore::new(),
+            &parts.join("."),
+            None,
+            timestamp,
+            0,
+            &keyring,
+            &options,
+        );
+        assert_eq!(verdict.failed_check(), Some(Check::Solutions));
+        assert_eq!(
+            verdict.into_result(),
+            Err(VerifyPuzzleResultError::SolutionTooShort {
+                expected: 32,
+                actual: 24
+            })
+        );
+    }
+
+    #[test]
+    fn test_verify_puzzle_result_with_primitive_threshold_error() {
+        let keyring = Keyring::from("NOT-A-SECRET-KEY".as_bytes());
+        let options = PuzzleOptions::default().with_difficulty_policy(
+            TierTable::new(vec![Tier {
+                min_access_count: 0,
+                scaling: Scaling::new(1, 255),
+            }])
+            .unwrap(),
+        );
+        let timestamp: u64 = 1693424664;
+        let puzzle = crate::build_puzzle_with(
+            &MemoryStore::new(),
+            "192.0.2.1",
+            None,
+            timestamp,
+            0,
+            &keyring,
+            1800,
+            &options,
+        )
+        .unwrap();
+
+        // Only a hash starting with four zero bytes is below the threshold of `1`.
+        let verdict = verify_puzzle_result_with(
+            &MemoryStore::new(),
+            &format!("{}.AAAAAAAAAAA=.AgAA", puzzle),
+            None,
+            timestamp,
+            0,
+            &keyring,
+            &options,
+        );
+        assert_eq!(
+            verdict.into_result(),
+            Err(VerifyPuzzleResultError::SolutionBelowThreshold)
+        );
+    }
+
+    #[test]
+    fn test_verify_puzzle_result_with_primitive_expiry_error() {
+        let keyring = Keyring::from("NOT-A-SECRET-KEY".as_bytes());
+        let options = PuzzleOptions::default();
+        let timestamp: u64 = 1693424664;
+        let verify = |timestamp: u64| {
+            verify_puzzle_result_with(
+                &MemoryStore::new(),
+                "3761fae80ef01b32dcf892d099ca07f31db7a97311cce59529a4bae93a801db4.\
+                ZO+cGAAAAAEAAAABAQwzegAAAAAAAAAAWlXMkohinFU=.AAAAAIgRAAA=.AgAA",
+                None,
+                timestamp,
+                0,
+                &keyring,
+                &options,
+            )
+        };
+
+        let verdict = verify(timestamp + 3601);
+        assert_eq!(verdict.failed_check(), Some(Check::Expiry));
+        assert_eq!(
+            verdict.error(),
+            Some(&VerifyPuzzleResultError::PuzzleExpired)
+        );
+        assert_eq!(verdict.age, Some(3601));
+
+        // Clock skew between servers is tolerated up to a few seconds.
+        let verdict = verify(timestamp - MAX_CLOCK_SKEW_SECS);
+        assert_eq!(verdict.failed_check(), Some(Check::Solutions));
+        assert_eq!(verdict.age, Some(0));
+
+        let verdict = verify(timestamp - MAX_CLOCK_SKEW_SECS - 1);
+        assert_eq!(verdict.failed_check(), Some(Check::Expiry));
+        assert_eq!(
+            verdict.error(),
+            Some(&VerifyPuzzleResultError::PuzzleFromFuture)
+        );
+        assert_eq!(verdict.age, None);
+
+        // Within the expiry the solutions are checked.
+        let verdict = verify(timestamp + 3600);
+        assert_eq!(verdict.failed_check(), Some(Check::Solutions));
+    }
+
+    proptest! {
+        #![proptest_config(ProptestConfig::with_cases(32))]
+
+        // Solving the full solution count of the upper tiers takes too long, so fewer solutions of
+        // the same difficulty are requested.
+        #[test]
+        fn test_build_solve_verify(
+            tier in proptest::sample::select(TierTable::default().tiers().to_vec()),
+            solution_count in 1_u8..=3,
+            ip_address in any::<std::net::IpAddr>(),
+            timestamp in 0_u64..=u64::from(u32::MAX) - 3600,
+            age in 0_u64..=3600,
+            nonce in any::<u64>(),
+        ) {
+            let keyring = Keyring::from("NOT-A-SECRET-KEY".as_bytes());
+            let scaling = Scaling::new(solution_count, tier.scaling.difficulty);
+            let options = PuzzleOptions::default().with_difficulty_policy(
+                TierTable::new(vec![Tier { min_access_count: 0, scaling }]).unwrap(),
+            );
+            let solution =
+                build_solved_puzzle(&keyring, &options, &ip_address.to_string(), timestamp, nonce);
+
+            let store = MemoryStore::new();
+            let verify = |solution: &str| {
+                verify_puzzle_result_with(
+                    &store,
+                    solution,
+                    None,
+                    timestamp + age,
+                    3600,
+                    &keyring,
+                    &options,
+                )
+            };
+            let verdict = verify(&solution);
+            prop_assert_eq!(verdict.failure, None);
+            prop_assert_eq!(verdict.age, Some(age));
+            let puzzle = verdict.puzzle.unwrap();
+            prop_assert_eq!(puzzle.solution_count, solution_count);
+            prop_assert_eq!(puzzle.difficulty, tier.scaling.difficulty);
+            prop_assert_eq!(
+                verify(&solution).into_result(),
+                Err(VerifyPuzzleResultError::PuzzleReuse)
+            );
+            let verdict =
+                verify_puzzle_result_with(&store, &solution, None, u64::MAX, 3600, &keyring, &options);
+            prop_assert_eq!(verdict.into_result(), Err(VerifyPuzzleResultError::PuzzleExpired));
+        }
+
+        #[test]
+        fn test_puzzle_age(
+            puzzle_timestamp in any::<u32>(),
+            timestamp in prop_oneof![
+                Just(u64::MAX),
+                u64::MAX - 2 * MAX_CLOCK_SKEW_SECS..=u64::MAX,
+                0_u64..=u64::from(u32::MAX) + MAX_CLOCK_SKEW_SECS,
+                any::<u64>(),
+            ],
+        ) {
+            let puzzle = Puzzle {
+                timestamp: puzzle_timestamp,
+                ..Puzzle::default()
+            };
+            // Computed without the risk of overflows.
+            let age = i128::from(timestamp) - i128::from(puzzle_timestamp);
+            let expected = (age >= -i128::from(MAX_CLOCK_SKEW_SECS))
+                .then(|| u64::try_from(age.max(0)).unwrap());
+            prop_assert_eq!(puzzle_age(&puzzle, timestamp), expected);
+        }
+
+        #[test]
+        fn test_verify_arbitrary_solutions(
+            solution_count in any::<u8>(),
+            solutions in proptest::collection::vec(any::<u8>(), 0..2048),
+        ) {
+            let puzzle = Puzzle {
+                solution_count,
+                ..Puzzle::default()
+            };
+            let result = verify_solutions(&puzzle, &solutions);
+            let expected = usize::from(solution_count) * SOLUTION_LEN_BYTE;
+            if solutions.len() < expected {
+                prop_assert_eq!(
+                    result,
+                    Err(VerifyPuzzleResultError::SolutionTooShort {
+                        expected,
+                        actual: solutions.len(),
+                    })
+                );
+            } else {
+                let too_short = matches!(result, Err(VerifyPuzzleResultError::SolutionTooShort { .. }));
+                prop_assert!(!too_short);
+            }
+        }
+    }
 }
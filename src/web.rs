@@ -1,13 +1,14 @@
 #![cfg(feature = "web")]
 
-use actix_web::http::StatusCode;
-use actix_web::{web, HttpRequest, Responder, Result};
+use actix_web::http::{header, StatusCode};
+use actix_web::{web, Either, HttpRequest, Responder, Result};
 use serde::{Deserialize, Serialize};
+use std::net::IpAddr;
 use std::str;
 
-use crate::build_puzzle::build_puzzle;
-use crate::config::get;
-use crate::verify_puzzle_result::verify_puzzle_result;
+use crate::build_puzzle::BuildPuzzleError;
+use crate::engine::ReloadableFcaptcha;
+use crate::ip_rules::TrustedProxies;
 
 /// An input to the puzzle builder web service.
 #[derive(Deserialize)]
@@ -33,43 +34,123 @@ impl BuildPuzzleServiceOutput {
     }
 }
 
-/// An input to the puzzle verification web service.
+/// An input to the puzzle verification web service, sent either as JSON or form-encoded.
 #[derive(Deserialize, Debug)]
 pub struct VerifyPuzzleResultServiceInput {
+    solution: Option<String>,
+    secret: Option<String>,
+    sitekey: Option<String>,
+    remoteip: Option<String>,
+}
+
+#[derive(Serialize)]
+struct VerifyPuzzleResultServiceOutput {
+    success: bool,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    errors: Vec<&'static str>,
+}
+
+impl VerifyPuzzleResultServiceOutput {
+    fn success() -> (web::Json<Self>, StatusCode) {
+        let output = VerifyPuzzleResultServiceOutput {
+            success: true,
+            errors: Vec::new(),
+        };
+        (web::Json(output), StatusCode::OK)
+    }
+
+    fn error(error_code: &'static str, status: StatusCode) -> (web::Json<Self>, StatusCode) {
+        let output = VerifyPuzzleResultServiceOutput {
+            success: false,
+            errors: vec![error_code],
+        };
+        (web::Json(output), status)
+    }
+}
+
+/// An input to the legacy puzzle verification web service.
+#[derive(Deserialize, Debug)]
+pub struct LegacyVerifyPuzzleResultServiceInput {
     solution: String,
     secret: String,
 }
 
 #[derive(Serialize)]
-struct VerifyPuzzleResultServiceOutput {
+struct LegacyVerifyPuzzleResultServiceOutput {
     success: bool,
-    errors: Option<String>,
+    errors: Option<&'static str>,
 }
 
-lazy_static! {
-    static ref API_KEY: Vec<u8> = get::<Vec<u8>>("API_KEY");
+/// Returns the address of the client that sent `req`. The addresses in the `Forwarded` or, without
+/// it, the `X-Forwarded-For` headers are only considered if the request was received from one of
+/// `trusted_proxies`, see [TrustedProxies::client_address].
+fn client_address(req: &HttpRequest, trusted_proxies: &TrustedProxies) -> Option<IpAddr> {
+    let peer = req.peer_addr()?.ip();
+    if !trusted_proxies.contains(peer) {
+        return Some(peer);
+    }
+    let values = |name| {
+        req.headers()
+            .get_all(name)
+            .filter_map(|value| value.to_str().ok())
+            .flat_map(|value| value.split(','))
+            .collect::<Vec<_>>()
+    };
+    let forwarded = values(header::FORWARDED);
+    let forwarded_for = if forwarded.is_empty() {
+        values(header::X_FORWARDED_FOR)
+    } else {
+        // Elements without a `for` parameter do not name the client and end the search there.
+        forwarded
+            .into_iter()
+            .map(|element| {
+                element
+                    .split(';')
+                    .filter_map(|pair| pair.trim().split_once('='))
+                    .find(|(name, _)| name.eq_ignore_ascii_case("for"))
+                    .map_or("unknown", |(_, value)| value.trim_matches('"'))
+            })
+            .collect()
+    };
+    Some(trusted_proxies.client_address(peer, forwarded_for))
 }
 
-/// A web service that serves puzzles to be solved.
+/// A web service that serves puzzles to be solved for the tenant given by the `sitekey`, if
+/// requested from one of its allowed origins and not from a denied network. The client address is
+/// only taken from forwarding headers of the trusted proxies of the engine. Requires the
+/// [ReloadableFcaptcha] engine as app data.
 pub async fn build_puzzle_service(
+    fcaptcha: web::Data<ReloadableFcaptcha>,
     req: HttpRequest,
     input: web::Query<BuildPuzzleServiceInput>,
 ) -> Result<impl Responder> {
-    let con_info = req.connection_info();
-    let remote_address = con_info.realip_remote_addr();
-    if (input.sitekey.as_bytes() != *API_KEY) || (remote_address.is_none()) {
+    let fcaptcha = fcaptcha.current();
+    let origin = req
+        .headers()
+        .get(header::ORIGIN)
+        .and_then(|origin| origin.to_str().ok());
+    let tenant = fcaptcha
+        .tenants()
+        .get(&input.sitekey)
+        .filter(|tenant| tenant.allows_origin(origin));
+    let remote_address = client_address(&req, &fcaptcha.config().trusted_proxies);
+    let (Some(tenant), Some(remote_address)) = (tenant, remote_address) else {
         return Ok((
             web::Json(BuildPuzzleServiceOutput::new("".to_string())),
             StatusCode::FORBIDDEN,
         ));
-    }
+    };
 
-    let puzzle_result = build_puzzle(con_info.realip_remote_addr().unwrap());
+    let puzzle_result = fcaptcha.build_puzzle(tenant, &remote_address.to_string());
     match puzzle_result {
         Ok(puzzle) => Ok((
             web::Json(BuildPuzzleServiceOutput::new(puzzle)),
             StatusCode::OK,
         )),
+        Err(BuildPuzzleError::Denied) => Ok((
+            web::Json(BuildPuzzleServiceOutput::new("".to_string())),
+            StatusCode::FORBIDDEN,
+        )),
         Err(_) => Ok((
             // TODO: Propagate error information
             web::Json(BuildPuzzleServiceOutput::new("".to_string())),
@@ -78,37 +159,343 @@ pub async fn build_puzzle_service(
     }
 }
 
-/// A web service that verifies solutions to a puzzle.
+/// A web service that verifies solutions to a puzzle, compatible with the `siteverify` API of
+/// FriendlyCaptcha. The tenant is resolved by the `secret` and the optional `sitekey`, only puzzles
+/// built for that tenant are accepted. If the tenant binds puzzles to the client IP address, it has
+/// to be given as `remoteip`. Failures are reported with the FriendlyCaptcha error codes, see
+/// [VerifyPuzzleResultError::error_code](crate::verify_puzzle_result::VerifyPuzzleResultError::error_code).
+/// Requires the [ReloadableFcaptcha] engine as app data.
 pub async fn verify_puzzle_result_service(
-    input: web::Json<VerifyPuzzleResultServiceInput>,
+    fcaptcha: web::Data<ReloadableFcaptcha>,
+    input: Option<
+        Either<
+            web::Json<VerifyPuzzleResultServiceInput>,
+            web::Form<VerifyPuzzleResultServiceInput>,
+        >,
+    >,
 ) -> Result<impl Responder> {
-    if input.secret.as_bytes() != *API_KEY {
+    let input = match input {
+        Some(Either::Left(web::Json(input)) | Either::Right(web::Form(input))) => input,
+        None => {
+            return Ok(VerifyPuzzleResultServiceOutput::error(
+                "bad_request",
+                StatusCode::BAD_REQUEST,
+            ))
+        }
+    };
+    let fcaptcha = fcaptcha.current();
+    let Some(secret) = input.secret else {
+        return Ok(VerifyPuzzleResultServiceOutput::error(
+            "secret_missing",
+            StatusCode::BAD_REQUEST,
+        ));
+    };
+    let Some(tenant) = fcaptcha
+        .tenants()
+        .resolve(input.sitekey.as_deref(), secret.as_bytes())
+    else {
+        return Ok(VerifyPuzzleResultServiceOutput::error(
+            "secret_invalid",
+            StatusCode::UNAUTHORIZED,
+        ));
+    };
+    let Some(solution) = input.solution else {
+        return Ok(VerifyPuzzleResultServiceOutput::error(
+            "solution_missing",
+            StatusCode::BAD_REQUEST,
+        ));
+    };
+
+    info!("Got puzzle result verify request with {:?}", solution);
+    match fcaptcha
+        .verify(tenant, &solution, input.remoteip.as_deref())
+        .into_result()
+    {
+        Ok(()) => Ok(VerifyPuzzleResultServiceOutput::success()),
+        Err(err) if err.is_internal() => {
+            error!("Verifying puzzle result failed: {}", err);
+            Ok(VerifyPuzzleResultServiceOutput::error(
+                err.error_code(),
+                StatusCode::INTERNAL_SERVER_ERROR,
+            ))
+        }
+        Err(err) => Ok(VerifyPuzzleResultServiceOutput::error(
+            err.error_code(),
+            StatusCode::OK,
+        )),
+    }
+}
+
+/// A web service that verifies solutions to a puzzle with the response format of
+/// `/verify-puzzle-result` before [verify_puzzle_result_service]: an invalid `secret` is answered
+/// with status 403 and `"errors": "secret_invalid"`, any other result with status 200 and
+/// `"errors": null`. The tenant is resolved by the `secret` alone and, as no client address is
+/// given, puzzles of tenants using IP binding are rejected. Requires the [ReloadableFcaptcha]
+/// engine as app data.
+pub async fn legacy_verify_puzzle_result_service(
+    fcaptcha: web::Data<ReloadableFcaptcha>,
+    input: web::Json<LegacyVerifyPuzzleResultServiceInput>,
+) -> Result<impl Responder> {
+    let fcaptcha = fcaptcha.current();
+    let Some(tenant) = fcaptcha.tenants().resolve(None, input.secret.as_bytes()) else {
         return Ok((
-            web::Json(VerifyPuzzleResultServiceOutput {
+            web::Json(LegacyVerifyPuzzleResultServiceOutput {
                 success: false,
-                errors: Some("secret_invalid".to_string()),
+                errors: Some("secret_invalid"),
             }),
             StatusCode::FORBIDDEN,
         ));
+    };
+
+    info!("Got puzzle result verify request with {:?}", input.solution);
+    let verdict = fcaptcha.verify(tenant, &input.solution, None);
+    if let Some(err) = verdict.error().filter(|err| err.is_internal()) {
+        error!("Verifying puzzle result failed: {}", err);
     }
+    Ok((
+        web::Json(LegacyVerifyPuzzleResultServiceOutput {
+            success: verdict.is_ok(),
+            errors: None,
+        }),
+        StatusCode::OK,
+    ))
+}
 
-    info!("Got puzzle result verify request with {:?}", input);
-    let puzzle_result = verify_puzzle_result(&input.solution);
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Settings;
+    use crate::engine::{Fcaptcha, FcaptchaConfig};
+    use crate::puzzle::SignedPuzzle;
+    use actix_web::{test, App};
+    use serde_json::{json, Value};
 
-    match puzzle_result {
-        Ok(_) => Ok((
-            web::Json(VerifyPuzzleResultServiceOutput {
-                success: true,
-                errors: None,
-            }),
-            StatusCode::OK,
-        )),
-        Err(_) => Ok((
-            web::Json(VerifyPuzzleResultServiceOutput {
-                success: false,
-                errors: None, // TODO: Add description
-            }),
-            StatusCode::OK,
-        )),
+    fn fcaptcha() -> web::Data<ReloadableFcaptcha> {
+        web::Data::new(ReloadableFcaptcha::new(Fcaptcha::new(
+            FcaptchaConfig::from_settings(&Settings::default()).unwrap(),
+        )))
+    }
+
+    const SOLUTION: &str = "3761fae80ef01b32dcf892d099ca07f31db7a97311cce59529a4bae93a801db4.\
+    ZO+cGAAAAAEAAAABAQwzegAAAAAAAAAAWlXMkohinFU=.AAAAAIgRAAA=.AgAA";
+
+    async fn siteverify(request: test::TestRequest) -> (StatusCode, Value) {
+        let app = test::init_service(App::new().app_data(fcaptcha()).route(
+            "/api/v1/siteverify",
+            web::post().to(verify_puzzle_result_service),
+        ))
+        .await;
+        let response =
+            test::call_service(&app, request.uri("/api/v1/siteverify").to_request()).await;
+        let status = response.status();
+        (status, test::read_body_json(response).await)
+    }
+
+    #[actix_web::test]
+    async fn test_siteverify_json() {
+        let request = test::TestRequest::post()
+            .set_json(json!({"solution": SOLUTION, "secret": "NOT-AN-API-KEY"}));
+        let (status, body) = siteverify(request).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(
+            body,
+            json!({"success": false, "errors": ["solution_timeout_or_duplicate"]})
+        );
+    }
+
+    #[actix_web::test]
+    async fn test_siteverify_form() {
+        let request = test::TestRequest::post()
+            .set_form([("solution", "invalid"), ("secret", "NOT-AN-API-KEY")]);
+        let (status, body) = siteverify(request).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(
+            body,
+            json!({"success": false, "errors": ["solution_invalid"]})
+        );
+    }
+
+    #[actix_web::test]
+    async fn test_siteverify_request_errors() {
+        let request = test::TestRequest::post()
+            .set_json(json!({"solution": SOLUTION, "secret": "WRONG-API-KEY"}));
+        assert_eq!(
+            siteverify(request).await,
+            (
+                StatusCode::UNAUTHORIZED,
+                json!({"success": false, "errors": ["secret_invalid"]})
+            )
+        );
+
+        let request = test::TestRequest::post().set_json(
+            json!({"solution": SOLUTION, "secret": "NOT-AN-API-KEY", "sitekey": "OTHER"}),
+        );
+        assert_eq!(
+            siteverify(request).await,
+            (
+                StatusCode::UNAUTHORIZED,
+                json!({"success": false, "errors": ["secret_invalid"]})
+            )
+        );
+
+        let request = test::TestRequest::post().set_json(json!({"solution": SOLUTION}));
+        assert_eq!(
+            siteverify(request).await,
+            (
+                StatusCode::BAD_REQUEST,
+                json!({"success": false, "errors": ["secret_missing"]})
+            )
+        );
+
+        let request = test::TestRequest::post().set_json(json!({"secret": "NOT-AN-API-KEY"}));
+        assert_eq!(
+            siteverify(request).await,
+            (
+                StatusCode::BAD_REQUEST,
+                json!({"success": false, "errors": ["solution_missing"]})
+            )
+        );
+
+        let request = test::TestRequest::post().set_payload("not json");
+        assert_eq!(
+            siteverify(request).await,
+            (
+                StatusCode::BAD_REQUEST,
+                json!({"success": false, "errors": ["bad_request"]})
+            )
+        );
+    }
+
+    #[actix_web::test]
+    async fn test_legacy_verify_puzzle_result() {
+        let app = test::init_service(App::new().app_data(fcaptcha()).route(
+            "/verify-puzzle-result",
+            web::post().to(legacy_verify_puzzle_result_service),
+        ))
+        .await;
+        let verify = |secret| {
+            test::TestRequest::post()
+                .uri("/verify-puzzle-result")
+                .set_json(json!({"solution": SOLUTION, "secret": secret}))
+                .to_request()
+        };
+
+        let response = test::call_service(&app, verify("NOT-AN-API-KEY")).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        let body: Value = test::read_body_json(response).await;
+        assert_eq!(body, json!({"success": false, "errors": null}));
+
+        let response = test::call_service(&app, verify("WRONG-API-KEY")).await;
+        assert_eq!(response.status(), StatusCode::FORBIDDEN);
+        let body: Value = test::read_body_json(response).await;
+        assert_eq!(body, json!({"success": false, "errors": "secret_invalid"}));
+    }
+
+    #[actix_web::test]
+    async fn test_build_puzzle_sitekey() {
+        let app = test::init_service(
+            App::new()
+                .app_data(fcaptcha())
+                .route("/build-puzzle", web::get().to(build_puzzle_service)),
+        )
+        .await;
+        let request = |sitekey| {
+            test::TestRequest::get()
+                .uri(&format!("/build-puzzle?sitekey={}", sitekey))
+                .peer_addr("127.0.0.1:1234".parse().unwrap())
+                .to_request()
+        };
+        let response = test::call_service(&app, request("NOT-AN-API-KEY")).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        let response = test::call_service(&app, request("OTHER")).await;
+        assert_eq!(response.status(), StatusCode::FORBIDDEN);
+    }
+
+    #[actix_web::test]
+    async fn test_build_puzzle_forwarded_for() {
+        let settings = Settings {
+            ip_rules: "bypass 192.0.2.0/24".to_string(),
+            trusted_proxies: "10.0.0.0/8".to_string(),
+            ..Settings::default()
+        };
+        let fcaptcha = Fcaptcha::new(FcaptchaConfig::from_settings(&settings).unwrap());
+        let app = test::init_service(
+            App::new()
+                .app_data(web::Data::new(ReloadableFcaptcha::new(fcaptcha)))
+                .route("/build-puzzle", web::get().to(build_puzzle_service)),
+        )
+        .await;
+        let difficulty = |peer: &str, header: (&str, &str)| {
+            let request = test::TestRequest::get()
+                .uri("/build-puzzle?sitekey=NOT-AN-API-KEY")
+                .peer_addr(peer.parse().unwrap())
+                .insert_header(header)
+                .to_request();
+            async {
+                let body: Value = test::call_and_read_body_json(&app, request).await;
+                let puzzle = body["data"]["puzzle"].as_str().unwrap();
+                puzzle.parse::<SignedPuzzle>().unwrap().puzzle.difficulty
+            }
+        };
+
+        // Clients can not claim to be in a bypassed network.
+        let spoofed = ("X-Forwarded-For", "192.0.2.1");
+        assert_eq!(difficulty("198.51.100.1:1234", spoofed).await, 122);
+        let spoofed = ("Forwarded", "for=192.0.2.1");
+        assert_eq!(difficulty("198.51.100.1:1234", spoofed).await, 122);
+        // Neither by sending the header through a trusted proxy.
+        let spoofed = ("X-Forwarded-For", "192.0.2.1, 198.51.100.1");
+        assert_eq!(difficulty("10.0.0.1:1234", spoofed).await, 122);
+        // Trusted proxies forward the address of the client.
+        let forwarded = ("X-Forwarded-For", "192.0.2.1");
+        assert_eq!(difficulty("10.0.0.1:1234", forwarded).await, 0);
+        let forwarded = ("Forwarded", "for=\"192.0.2.1:4711\";proto=https");
+        assert_eq!(difficulty("10.0.0.1:1234", forwarded).await, 0);
+    }
+
+    #[actix_web::test]
+    async fn test_ip_binding_forwarded_for() {
+        let settings = Settings {
+            ip_binding: true,
+            difficulty_tiers: "0:4:100".to_string(),
+            ..Settings::default()
+        };
+        let fcaptcha = Fcaptcha::new(FcaptchaConfig::from_settings(&settings).unwrap());
+        let app = test::init_service(
+            App::new()
+                .app_data(web::Data::new(ReloadableFcaptcha::new(fcaptcha)))
+                .route("/build-puzzle", web::get().to(build_puzzle_service))
+                .route(
+                    "/api/v1/siteverify",
+                    web::post().to(verify_puzzle_result_service),
+                ),
+        )
+        .await;
+        // The solver on 198.51.100.1 claims to request the puzzle for 203.0.113.2.
+        let request = test::TestRequest::get()
+            .uri("/build-puzzle?sitekey=NOT-AN-API-KEY")
+            .peer_addr("198.51.100.1:1234".parse().unwrap())
+            .insert_header(("X-Forwarded-For", "203.0.113.2"))
+            .to_request();
+        let body: Value = test::call_and_read_body_json(&app, request).await;
+        let solution = crate::solve_puzzle(body["data"]["puzzle"].as_str().unwrap()).unwrap();
+        let verify = |remoteip| {
+            let request = test::TestRequest::post()
+                .uri("/api/v1/siteverify")
+                .set_json(json!({
+                    "solution": solution,
+                    "secret": "NOT-AN-API-KEY",
+                    "remoteip": remoteip,
+                }))
+                .to_request();
+            test::call_and_read_body_json::<_, _, Value>(&app, request)
+        };
+
+        // The puzzle is bound to the solver, so it can not be redeemed from the claimed address.
+        assert_eq!(
+            verify("203.0.113.2").await,
+            json!({"success": false, "errors": ["solution_invalid"]})
+        );
+        assert_eq!(verify("198.51.100.1").await, json!({"success": true}));
     }
 }